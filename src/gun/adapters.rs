@@ -1 +1,128 @@
-pub mod websocket_server;
\ No newline at end of file
+pub mod filesystem;
+pub mod memory;
+pub mod multicast;
+pub mod redis;
+pub mod s3;
+pub mod tls;
+pub mod webrtc;
+pub mod websocket_client;
+pub mod websocket_server;
+
+use std::sync::Arc;
+use async_trait::async_trait;
+use crate::ham::{self, Mix, State};
+use crate::message::Dot;
+use crate::node::Node;
+use self::filesystem::FileStore;
+use self::memory::MemoryStore;
+use self::redis::RedisStore;
+use self::s3::{S3Options, S3Store};
+
+/// The storage backends to choose from.
+pub const BACKENDS: &[&str] = &["filesystem", "memory", "redis", "s3"];
+
+/// Where each storage backend keeps its data.
+pub struct StorageOptions {
+    /// The directory the filesystem backend writes to.
+    pub path: String,
+    /// The Redis server the redis backend talks to.
+    pub redis_url: String,
+    /// What the keys the redis backend writes start with.
+    pub redis_prefix: String,
+    /// The S3-compatible service the s3 backend talks to, or AWS if `None`.
+    /// Credentials come from AWS's default chain: `AWS_ACCESS_KEY_ID` and
+    /// `AWS_SECRET_ACCESS_KEY`, a `~/.aws` profile, or an instance role.
+    pub s3_endpoint: Option<String>,
+    pub s3_region: String,
+    pub s3_bucket: String,
+    /// What the names of the objects the s3 backend writes start with.
+    pub s3_prefix: String,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        StorageOptions {
+            path: "radata".to_string(),
+            redis_url: "redis://127.0.0.1/".to_string(),
+            redis_prefix: "gun:".to_string(),
+            s3_endpoint: None,
+            s3_region: S3Options::default().region,
+            s3_bucket: S3Options::default().bucket,
+            s3_prefix: S3Options::default().prefix,
+        }
+    }
+}
+
+/// Open the storage backend called `name`.
+pub fn open_storage(name: &str, opt: &StorageOptions) -> Result<Arc<dyn StorageAdapter>, String> {
+    match name {
+        "filesystem" => {
+            let store = FileStore::open(&opt.path).map_err(|e| format!("failed to open {}: {}", opt.path, e))?;
+            Ok(Arc::new(store))
+        }
+        "memory" => Ok(Arc::new(MemoryStore::new())),
+        "redis" => Ok(Arc::new(RedisStore::open(&opt.redis_url, &opt.redis_prefix)?)),
+        "s3" => {
+            let s3 = S3Options {
+                endpoint: opt.s3_endpoint.clone(),
+                region: opt.s3_region.clone(),
+                bucket: opt.s3_bucket.clone(),
+                prefix: opt.s3_prefix.clone(),
+                ..Default::default()
+            };
+            Ok(Arc::new(S3Store::open(s3)?))
+        }
+        _ => Err(format!("unknown storage backend '{}'", name)),
+    }
+}
+
+/// StorageAdapter is where the graph is kept. Gun only ever talks to
+/// its storage through this trait, so backends can be swapped per
+/// deployment without touching the rest.
+///
+/// Adapters store fields as they are given; deciding which writes win
+/// is HAM's job, done in `merge`.
+#[async_trait]
+pub trait StorageAdapter: Send + Sync {
+    /// Read a whole node, or `None` if nothing is known under its soul.
+    async fn get(&self, soul: &str) -> Result<Option<Node>, String>;
+
+    /// Read the keys of a node a `.` query asks for, or `None` if none match.
+    async fn get_range(&self, soul: &str, dot: &Dot) -> Result<Option<Node>, String> {
+        let node = self.get(soul).await?.map(|node| node.select(dot));
+        Ok(node.filter(|node| !node.is_empty()))
+    }
+
+    /// Write the fields of a node, replacing those already stored.
+    async fn put(&self, node: &Node) -> Result<(), String>;
+
+    /// Write several nodes at once.
+    async fn put_batch(&self, nodes: &[Node]) -> Result<(), String> {
+        for node in nodes.iter() {
+            self.put(node).await?;
+        }
+        Ok(())
+    }
+
+    /// Run a node through HAM against what's stored and write the fields
+    /// that win. Callers serialize merges unless the adapter overrides
+    /// this with an atomic one.
+    async fn merge(&self, node: &Node, machine: State) -> Result<Mix, String> {
+        let current = self.get(node.soul()).await?;
+        let mix = ham::union(current.as_ref(), node, machine)?;
+        if !mix.diff.is_empty() {
+            self.put(&mix.diff).await?;
+        }
+        Ok(mix)
+    }
+
+    /// Make sure everything written so far is stored for good.
+    async fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Whether `merge` is atomic, so callers don't have to serialize it.
+    fn atomic_merge(&self) -> bool {
+        false
+    }
+}
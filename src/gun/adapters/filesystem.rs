@@ -0,0 +1,550 @@
+//! Radisk-style storage on the local filesystem.
+//!
+//! Every field of every node is one record in a single sorted key space,
+//! keyed by `soul` + ESC + `key`. The key space is cut into chunk files,
+//! each named after the first key it holds, so a node (or a lexical range
+//! of its keys) is read from the one or few chunks covering it. A chunk
+//! that grows past the size limit is split in two. The first keys of
+//! chunks whose names had to be cut short are kept in an index file, so
+//! that opening the store reads no chunk.
+//!
+//! Writes are staged in memory and written out in batches, either once
+//! enough of them have piled up or shortly after the first one. Chunks
+//! are only read from disk when a `get` needs them, and a bounded number
+//! of them is kept in memory afterwards. Records that fail to be written
+//! are kept staged and tried again.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use crate::adapters::StorageAdapter;
+use crate::ham::State;
+use crate::message::Dot;
+use crate::node::Node;
+use crate::obj::Value;
+
+/// Separates the soul from the key in a record's key, as in radisk.
+pub(crate) const ESC: char = '\u{1b}';
+
+/// The name of the chunk holding the start of the key space.
+pub(crate) const FIRST_CHUNK: &str = "!";
+
+/// The file holding the first key of every chunk whose name is cut short.
+const INDEX: &str = "+index";
+
+/// Chunk file names longer than this are cut short and end in a hash of
+/// the whole name instead, to stay within what filesystems allow.
+const MAX_NAME: usize = 128;
+
+/// How long to wait before trying again to write what failed to be.
+const RETRY: Duration = Duration::from_secs(1);
+
+pub(crate) type Records = BTreeMap<String, (Value, State)>;
+
+/// Every chunk by the first key it holds, with its records once read.
+pub(crate) type Chunks = BTreeMap<String, Option<Records>>;
+
+pub struct FileStoreOptions {
+    /// Chunk files are split once they grow past this many bytes.
+    pub chunk: usize,
+    /// Staged records are written once there are this many of them...
+    pub batch: usize,
+    /// ...or this long after the first of them was staged.
+    pub wait: Duration,
+    /// How many chunks are kept in memory once read.
+    pub cache: usize,
+}
+
+impl Default for FileStoreOptions {
+    fn default() -> Self {
+        FileStoreOptions {
+            chunk: 1024 * 1024,
+            batch: 10_000,
+            wait: Duration::from_millis(100),
+            cache: 64,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct FileStore {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    opt: FileStoreOptions,
+    disk: Arc<Mutex<Disk>>,
+}
+
+#[derive(Default)]
+struct Disk {
+    chunks: Chunks,
+    /// The first keys of the chunks whose names are cut short, by name.
+    hashed: BTreeMap<String, String>,
+    /// Records waiting to be written.
+    pending: Records,
+    /// Whether a batch write is already on its way.
+    scheduled: bool,
+}
+
+impl FileStore {
+    /// Open the store in `dir`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        Self::with_options(dir, FileStoreOptions::default())
+    }
+
+    pub fn with_options<P: AsRef<Path>>(dir: P, opt: FileStoreOptions) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut index: BTreeMap<String, String> = match fs::read(dir.join(INDEX)) {
+            Ok(raw) => serde_json::from_slice(&raw).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut disk = Disk::default();
+        disk.chunks.insert(String::new(), None);
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name == FIRST_CHUNK {
+                continue;
+            }
+            if let Some(first) = decode(&name) {
+                disk.chunks.insert(first, None);
+            } else if is_hashed(&name) {
+                // A chunk written but not yet indexed starts with its first key.
+                let first = match index.remove(&name) {
+                    Some(first) => first,
+                    None => match read_chunk(&entry.path())?.into_keys().next() {
+                        Some(first) => first,
+                        None => continue,
+                    },
+                };
+                disk.chunks.insert(first.clone(), None);
+                disk.hashed.insert(name, first);
+            }
+        }
+
+        let disk = Arc::new(Mutex::new(disk));
+        Ok(FileStore { inner: Arc::new(Inner { dir, opt, disk }) })
+    }
+
+    /// Read a node, or `None` if nothing was ever written under its soul.
+    pub async fn get(&self, soul: &str) -> io::Result<Option<Node>> {
+        self.read(soul, "").await
+    }
+
+    /// Read the keys of a node from `from` on, touching only the chunks
+    /// that hold them.
+    pub async fn read(&self, soul: &str, from: &str) -> io::Result<Option<Node>> {
+        let (soul, from) = (soul.to_string(), from.to_string());
+        self.inner.clone().blocking(move |inner, disk| inner.read(disk, &soul, &from)).await
+    }
+
+    /// Stage the fields of a node to be written in the next batch.
+    pub async fn put(&self, node: &Node) -> io::Result<()> {
+        let mut disk = self.inner.disk.lock().await;
+        for (key, (val, state)) in node.iter() {
+            let record = format!("{}{}{}", node.soul(), ESC, key);
+            disk.pending.insert(record, (val.clone(), *state));
+        }
+
+        if disk.pending.len() >= self.inner.opt.batch {
+            drop(disk);
+            return self.flush().await;
+        }
+        if !disk.scheduled {
+            disk.scheduled = true;
+            self.inner.clone().schedule(self.inner.opt.wait);
+        }
+        Ok(())
+    }
+
+    /// Write every staged record now.
+    pub async fn flush(&self) -> io::Result<()> {
+        self.inner.clone().flush().await
+    }
+}
+
+#[async_trait]
+impl StorageAdapter for FileStore {
+    async fn get(&self, soul: &str) -> Result<Option<Node>, String> {
+        FileStore::get(self, soul).await.map_err(|e| e.to_string())
+    }
+
+    async fn get_range(&self, soul: &str, dot: &Dot) -> Result<Option<Node>, String> {
+        let node = self.read(soul, dot.lower().unwrap_or_default()).await
+            .map_err(|e| e.to_string())?;
+        Ok(node.map(|node| node.select(dot)).filter(|node| !node.is_empty()))
+    }
+
+    async fn put(&self, node: &Node) -> Result<(), String> {
+        FileStore::put(self, node).await.map_err(|e| e.to_string())
+    }
+
+    async fn flush(&self) -> Result<(), String> {
+        FileStore::flush(self).await.map_err(|e| e.to_string())
+    }
+}
+
+impl Disk {
+    /// Stage records again, unless newer ones were staged meanwhile.
+    fn restage(&mut self, records: Records) {
+        for (key, record) in records {
+            self.pending.entry(key).or_insert(record);
+        }
+    }
+}
+
+impl Inner {
+    // Work on the disk on a thread where blocking is fine, holding the lock.
+    async fn blocking<T, F>(self: Arc<Self>, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Inner, &mut Disk) -> io::Result<T> + Send + 'static,
+    {
+        let mut disk = self.disk.clone().lock_owned().await;
+        tokio::task::spawn_blocking(move || f(&self, &mut disk)).await
+            .map_err(io::Error::other)?
+    }
+
+    /// Write what's staged, or try again in a while if that fails.
+    async fn flush(self: Arc<Self>) -> io::Result<()> {
+        let result = self.clone().blocking(|inner, disk| inner.write(disk)).await;
+        if result.is_err() {
+            let mut disk = self.disk.lock().await;
+            if !disk.scheduled && !disk.pending.is_empty() {
+                disk.scheduled = true;
+                self.clone().schedule(RETRY);
+            }
+        }
+        result
+    }
+
+    fn schedule(self: Arc<Self>, wait: Duration) {
+        tokio::spawn(async move {
+            tokio::time::sleep(wait).await;
+            let dir = self.dir.clone();
+            if let Err(e) = self.flush().await {
+                eprintln!("failed to write to {}: {}", dir.display(), e);
+            }
+        });
+    }
+
+    fn read(&self, disk: &mut Disk, soul: &str, from: &str) -> io::Result<Option<Node>> {
+        let prefix = format!("{}{}", soul, ESC);
+        let start = format!("{}{}", prefix, from);
+        let names = covering(&disk.chunks, &start, soul);
+
+        let mut node = Node::new(soul);
+        for name in names.iter() {
+            let records = self.load(disk, name)?;
+            collect(&mut node, records, &start, &prefix);
+        }
+        collect(&mut node, &disk.pending, &start, &prefix);
+        evict(&mut disk.chunks, &names, self.opt.cache);
+
+        Ok(if node.is_empty() { None } else { Some(node) })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(chunk_name(name))
+    }
+
+    fn load<'a>(&self, disk: &'a mut Disk, name: &str) -> io::Result<&'a mut Records> {
+        let chunk = disk.chunks.entry(name.to_string()).or_insert(None);
+        if chunk.is_none() {
+            let records = match read_chunk(&self.path(name)) {
+                Ok(records) => records,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Records::new(),
+                Err(e) => return Err(e),
+            };
+            *chunk = Some(records);
+        }
+        Ok(chunk.as_mut().unwrap())
+    }
+
+    fn write(&self, disk: &mut Disk) -> io::Result<()> {
+        disk.scheduled = false;
+        let pending = std::mem::take(&mut disk.pending);
+
+        let mut by_chunk: BTreeMap<String, Records> = BTreeMap::new();
+        for (key, record) in pending.into_iter() {
+            by_chunk.entry(chunk_of(&disk.chunks, &key)).or_default().insert(key, record);
+        }
+
+        let mut touched = Vec::new();
+        let mut by_chunk = by_chunk.into_iter();
+        while let Some((name, records)) = by_chunk.next() {
+            let written = self.load(disk, &name)
+                .map(|chunk| chunk.clone())
+                .and_then(|mut chunk| {
+                    chunk.extend(records.clone());
+                    self.write_chunk(disk, name, chunk)
+                });
+            match written {
+                Ok(names) => touched.extend(names),
+                Err(e) => {
+                    // Whatever wasn't written stays staged for next time.
+                    disk.restage(records);
+                    by_chunk.for_each(|(_, records)| disk.restage(records));
+                    return Err(e);
+                }
+            }
+        }
+        evict(&mut disk.chunks, &touched, self.opt.cache);
+        Ok(())
+    }
+
+    /// Write a chunk out, split as many times as it takes to fit. Every
+    /// piece is written to a temporary file before any takes its place, the
+    /// chunk itself last, so a split that fails leaves the chunk whole. At
+    /// worst records moved out are then in both, and read from the new one.
+    fn write_chunk(&self, disk: &mut Disk, name: String, records: Records) -> io::Result<Vec<String>> {
+        let pieces = split(name, records, self.opt.chunk)?;
+        let mut temps = Vec::new();
+        for (name, _, raw) in pieces.iter() {
+            let path = self.path(name);
+            match write_temp(&path, raw) {
+                Ok(tmp) => temps.push((tmp, path)),
+                Err(e) => {
+                    temps.iter().for_each(|(tmp, _)| drop(fs::remove_file(tmp)));
+                    return Err(e);
+                }
+            }
+        }
+        for (tmp, path) in temps.iter() {
+            fs::rename(tmp, path)?;
+        }
+
+        let mut names = Vec::new();
+        let mut indexed = false;
+        for (name, records, _) in pieces {
+            let file = chunk_name(&name);
+            if is_hashed(&file) && !disk.hashed.contains_key(&file) {
+                disk.hashed.insert(file, name.clone());
+                indexed = true;
+            }
+            disk.chunks.insert(name.clone(), Some(records));
+            names.push(name);
+        }
+        if indexed {
+            let raw = serde_json::to_vec(&disk.hashed)?;
+            fs::rename(write_temp(&self.dir.join(INDEX), &raw)?, self.dir.join(INDEX))?;
+        }
+        Ok(names)
+    }
+}
+
+/// The chunks that may hold keys from `start` up to the end of the soul.
+pub(crate) fn covering(chunks: &Chunks, start: &str, soul: &str) -> Vec<String> {
+    let first = chunk_of(chunks, start);
+    let end = format!("{}{}", soul, char::from(ESC as u8 + 1));
+    chunks.range(first..end).map(|(name, _)| name.to_string()).collect()
+}
+
+/// The chunk a key belongs in.
+pub(crate) fn chunk_of(chunks: &Chunks, key: &str) -> String {
+    chunks.range(..=key.to_string())
+        .next_back()
+        .map(|(name, _)| name.to_string())
+        .unwrap_or_default()
+}
+
+/// Forget the records of chunks past `max` of them, keeping `keep`.
+pub(crate) fn evict(chunks: &mut Chunks, keep: &[String], max: usize) {
+    let loaded: Vec<String> = chunks.iter()
+        .filter(|(name, records)| records.is_some() && !keep.contains(name))
+        .map(|(name, _)| name.to_string())
+        .collect();
+    let over = (loaded.len() + keep.len()).saturating_sub(max);
+    for name in loaded.into_iter().take(over) {
+        chunks.insert(name, None);
+    }
+}
+
+/// Copy the records from `start` on that belong to the node under `prefix`.
+pub(crate) fn collect(node: &mut Node, records: &Records, start: &str, prefix: &str) {
+    for (key, (val, state)) in records.range(start.to_string()..) {
+        match key.strip_prefix(prefix) {
+            Some(field) => node.insert(field.to_string(), val.clone(), *state),
+            None => break,
+        }
+    }
+}
+
+/// Cut a chunk in halves for as long as it's bigger than `max` bytes,
+/// returning every piece with its name and serialized form, the upper
+/// ones first.
+pub(crate) fn split(name: String, mut records: Records, max: usize) -> serde_json::Result<Vec<(String, Records, Vec<u8>)>> {
+    let mut raw = serde_json::to_vec(&records)?;
+    if raw.len() > max && records.len() > 1 {
+        let middle = records.keys().nth(records.len() / 2).unwrap().to_string();
+        let upper = records.split_off(&middle);
+        let mut pieces = split(middle, upper, max)?;
+        pieces.extend(split(name, records, max)?);
+        return Ok(pieces);
+    }
+    if raw.is_empty() {
+        raw = b"{}".to_vec();
+    }
+    Ok(vec![(name, records, raw)])
+}
+
+/// The name a chunk is stored under: its first key, encoded, and cut
+/// short with a hash if that is too long.
+pub(crate) fn chunk_name(first: &str) -> String {
+    if first.is_empty() {
+        return FIRST_CHUNK.to_string();
+    }
+    let encoded = encode(first);
+    if encoded.len() <= MAX_NAME {
+        return encoded;
+    }
+    let hash: String = Sha256::digest(first.as_bytes()).iter().take(16).map(|b| format!("{:02x}", b)).collect();
+    format!("{}+{}", &encoded[..MAX_NAME], hash)
+}
+
+/// Chunk file names are their first key with anything but a few safe
+/// characters percent-encoded.
+pub(crate) fn encode(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// Whether a file name is that of a chunk whose name was too long.
+pub(crate) fn is_hashed(name: &str) -> bool {
+    match name.rsplit_once('+') {
+        Some((encoded, hash)) => encoded.len() == MAX_NAME && hash.len() == 32 && hash.bytes().all(|b| b.is_ascii_hexdigit()),
+        None => false,
+    }
+}
+
+// Write a file next to `path` to take its place, and return its path.
+fn write_temp(path: &Path, raw: &[u8]) -> io::Result<PathBuf> {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(raw)?;
+    file.sync_all()?;
+    Ok(PathBuf::from(tmp))
+}
+
+fn read_chunk(path: &Path) -> io::Result<Records> {
+    let raw = fs::read(path)?;
+    serde_json::from_slice(&raw).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub(crate) fn decode(name: &str) -> Option<String> {
+    let bytes = name.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = name.get(i + 1..i + 3)?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+            _ => return None,
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rod-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_encode() {
+        let key = format!("~@alice{}name.é", ESC);
+        assert_eq!(decode(&encode(&key)), Some(key));
+        assert_eq!(decode("a.tmp"), None);
+    }
+
+    #[tokio::test]
+    async fn test_survives_reopen_and_splits() -> io::Result<()> {
+        let dir = temp_dir("filesystem");
+        let opt = || FileStoreOptions { chunk: 512, batch: 8, ..Default::default() };
+        let store = FileStore::with_options(&dir, opt())?;
+        for i in 0..40 {
+            let mut node = Node::new(&format!("soul{:02}", i));
+            node.insert("name".to_string(), Value::Text(format!("node number {}", i)), i as State);
+            node.insert("n".to_string(), Value::Number(i as f64), i as State);
+            store.put(&node).await?;
+        }
+        // Staged records are read back before they are written.
+        assert_eq!(store.get("soul39").await?.unwrap().get("n"), Some(&Value::Number(39.0)));
+        store.flush().await?;
+        assert!(fs::read_dir(&dir)?.count() > 2);
+
+        let store = FileStore::with_options(&dir, opt())?;
+        for i in 0..40 {
+            let node = store.get(&format!("soul{:02}", i)).await?.unwrap();
+            assert_eq!(node.get("name"), Some(&Value::Text(format!("node number {}", i))));
+            assert_eq!(node.state("n"), Some(i as State));
+        }
+        assert!(store.get("soul4").await?.is_none());
+
+        let lex = serde_json::from_str(r#"{"*":"na"}"#)?;
+        let node = StorageAdapter::get_range(&store, "soul07", &lex).await.unwrap().unwrap();
+        assert_eq!(node.len(), 1);
+        assert_eq!(node.state("name"), Some(7.0));
+
+        fs::remove_dir_all(&dir)
+    }
+
+    #[tokio::test]
+    async fn test_long_names_and_failed_writes() -> io::Result<()> {
+        let dir = temp_dir("filesystem-long");
+        let opt = || FileStoreOptions { chunk: 512, wait: Duration::from_secs(60), ..Default::default() };
+        let store = FileStore::with_options(&dir, opt())?;
+        let soul = "s".repeat(300);
+        let mut node = Node::new(&soul);
+        for i in 0..10 {
+            node.insert(format!("{}{}", "k".repeat(300), i), Value::Number(i as f64), 1.0);
+        }
+        store.put(&node).await?;
+
+        // Nothing written is lost when the disk fails under us.
+        fs::remove_dir_all(&dir)?;
+        assert!(store.flush().await.is_err());
+        fs::create_dir_all(&dir)?;
+        store.flush().await?;
+        assert!(fs::read_dir(&dir)?.all(|entry| entry.unwrap().file_name().len() <= 255));
+
+        // Cut short names are opened from the index, without reading chunks.
+        assert!(dir.join(INDEX).exists());
+        let store = FileStore::with_options(&dir, opt())?;
+        assert!(store.inner.disk.lock().await.chunks.values().all(|records| records.is_none()));
+        let node = store.get(&soul).await?.unwrap();
+        assert_eq!(node.len(), 10);
+        assert_eq!(node.get(&format!("{}9", "k".repeat(300))), Some(&Value::Number(9.0)));
+        fs::remove_dir_all(&dir)
+    }
+}
@@ -0,0 +1,67 @@
+//! Storage that only lives as long as the process.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use async_trait::async_trait;
+use crate::adapters::StorageAdapter;
+use crate::ham::{self, Mix, State};
+use crate::message::Dot;
+use crate::node::Node;
+
+/// MemoryStore keeps every node we know in memory, keyed by soul.
+#[derive(Default)]
+pub struct MemoryStore {
+    nodes: RwLock<HashMap<String, Node>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore { nodes: RwLock::new(HashMap::new()) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl StorageAdapter for MemoryStore {
+    async fn get(&self, soul: &str) -> Result<Option<Node>, String> {
+        Ok(self.nodes.read().unwrap().get(soul).cloned())
+    }
+
+    async fn get_range(&self, soul: &str, dot: &Dot) -> Result<Option<Node>, String> {
+        let nodes = self.nodes.read().unwrap();
+        let node = nodes.get(soul).map(|node| node.select(dot));
+        Ok(node.filter(|node| !node.is_empty()))
+    }
+
+    async fn put(&self, node: &Node) -> Result<(), String> {
+        self.nodes.write().unwrap()
+            .entry(node.soul().to_string())
+            .or_insert_with(|| Node::new(node.soul()))
+            .merge(node.clone());
+        Ok(())
+    }
+
+    /// Comparing and writing happen under one lock, so that concurrent
+    /// writes to the same node can't overtake each other.
+    async fn merge(&self, node: &Node, machine: State) -> Result<Mix, String> {
+        let mut nodes = self.nodes.write().unwrap();
+        let mix = ham::union(nodes.get(node.soul()), node, machine)?;
+        if !mix.diff.is_empty() {
+            nodes.entry(node.soul().to_string())
+                .or_insert_with(|| Node::new(node.soul()))
+                .merge(mix.diff.clone());
+        }
+        Ok(mix)
+    }
+
+    fn atomic_merge(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,521 @@
+//! LAN peer discovery and sync over UDP multicast.
+//!
+//! Every so often we announce ourselves to a multicast group with a DAM
+//! hi. Whoever says hi, on the group or to us directly, becomes a peer
+//! known by its pid, up to so many of them, and from then on messages go
+//! to it directly over unicast UDP, to the address its hi came from.
+//! Anything else from an address that hasn't said hi is ignored, and
+//! peers we stop hearing from are forgotten.
+//!
+//! A message that fits in one datagram is sent as is, as gun.js does.
+//! Bigger ones are cut into fragments, each starting with a zero byte,
+//! which JSON never does, and put back together on arrival. Only so many
+//! messages are put back together at once, from each sender and in all.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use crate::dam::{Dam, Peer};
+use crate::message::{MessageError, Msg};
+
+/// Fragments start with this byte, then the message id, their index and
+/// the number of fragments.
+const FRAGMENT: u8 = 0;
+const HEADER: usize = 9;
+
+/// How long the rest of a fragmented message is waited for.
+const FRAGMENT_AGE: Duration = Duration::from_secs(5);
+
+/// How many fragmented messages are put back together at once, from one
+/// sender and from all of them.
+const PARTIALS_PER_PEER: usize = 8;
+const PARTIALS_MAX: usize = 64;
+
+/// The longest pid we take a peer by.
+const PID_MAX: usize = 64;
+
+/// The biggest datagram UDP can carry.
+const DATAGRAM_MAX: usize = 65_507;
+
+pub struct MulticastOptions {
+    /// The group peers announce themselves to.
+    pub group: Ipv4Addr,
+    pub port: u16,
+    /// The local interface to multicast on, or any.
+    pub interface: Ipv4Addr,
+    /// The biggest datagram we send; bigger messages are fragmented.
+    pub mtu: usize,
+    /// The biggest message we send or put back together.
+    pub max: usize,
+    /// How often we announce ourselves.
+    pub announce: Duration,
+    /// How long a peer we don't hear from is kept.
+    pub timeout: Duration,
+    /// How many peers we keep at most.
+    pub max_peers: usize,
+}
+
+impl Default for MulticastOptions {
+    fn default() -> Self {
+        // The group and port are the ones gun.js uses.
+        MulticastOptions {
+            group: Ipv4Addr::new(233, 255, 255, 255),
+            port: 8765,
+            interface: Ipv4Addr::UNSPECIFIED,
+            mtu: 1400,
+            max: 1024 * 1024,
+            announce: Duration::from_secs(5),
+            timeout: Duration::from_secs(20),
+            max_peers: 32,
+        }
+    }
+}
+
+/// Join the multicast group and sync with the peers found there, until
+/// the returned task is dropped or aborted.
+pub async fn join(dam: Arc<Dam>, opt: MulticastOptions) -> io::Result<JoinHandle<()>> {
+    if opt.mtu <= HEADER || opt.mtu > DATAGRAM_MAX {
+        let e = format!("multicast mtu must be between {} and {}", HEADER + 1, DATAGRAM_MAX);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+    }
+    if opt.max / (opt.mtu - HEADER) >= u16::MAX as usize {
+        let e = "multicast max is too big to be sent in fragments of this mtu";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+    }
+    let group = group_socket(&opt)?;
+    let unicast = Arc::new(unicast_socket(&opt)?);
+    let lan = Lan {
+        dam,
+        opt,
+        unicast,
+        ids: Arc::new(AtomicU32::new(rand::random())),
+        peers: HashMap::new(),
+        addrs: HashMap::new(),
+        partials: HashMap::new(),
+    };
+    Ok(tokio::spawn(lan.run(group)))
+}
+
+struct Lan {
+    dam: Arc<Dam>,
+    opt: MulticastOptions,
+    /// Announcements and everything we send to peers leave from here,
+    /// so this is where peers answer us.
+    unicast: Arc<UdpSocket>,
+    ids: Arc<AtomicU32>,
+    /// Our peers by pid.
+    peers: HashMap<String, LanPeer>,
+    /// The pid of the peer at each address.
+    addrs: HashMap<SocketAddr, String>,
+    partials: HashMap<(SocketAddr, u32), Partial>,
+}
+
+struct LanPeer {
+    addr: SocketAddr,
+    /// When we last heard from it.
+    at: Instant,
+}
+
+struct Partial {
+    parts: Vec<Option<Vec<u8>>>,
+    size: usize,
+    at: Instant,
+}
+
+impl Lan {
+    async fn run(mut self, group: UdpSocket) {
+        let unicast = self.unicast.clone();
+        let mut group_buf = vec![0; DATAGRAM_MAX];
+        let mut unicast_buf = vec![0; DATAGRAM_MAX];
+        let mut announce = tokio::time::interval(self.opt.announce);
+        let mut expire = tokio::time::interval(FRAGMENT_AGE / 5);
+        loop {
+            tokio::select! {
+                heard = group.recv_from(&mut group_buf) => match heard {
+                    Ok((len, from)) => self.receive(&group_buf[..len], from, true).await,
+                    Err(e) => eprintln!("multicast receive error: {}", e),
+                },
+                heard = unicast.recv_from(&mut unicast_buf) => match heard {
+                    Ok((len, from)) => self.receive(&unicast_buf[..len], from, false).await,
+                    Err(e) => eprintln!("multicast receive error: {}", e),
+                },
+                _ = announce.tick() => {
+                    self.announce().await;
+                    self.sweep().await;
+                }
+                _ = expire.tick() => self.expire(Instant::now()),
+            }
+        }
+    }
+
+    async fn announce(&self) {
+        let hi = self.dam.hi().to_string();
+        let group = SocketAddrV4::new(self.opt.group, self.opt.port);
+        if let Err(e) = self.unicast.send_to(hi.as_bytes(), group).await {
+            eprintln!("failed to announce on {}: {}", group, e);
+        }
+    }
+
+    /// Forget the peers we haven't heard from in a while.
+    async fn sweep(&mut self) {
+        let now = Instant::now();
+        let timeout = self.opt.timeout;
+        let gone: Vec<String> = self.peers.iter()
+            .filter(|(_, peer)| now.duration_since(peer.at) > timeout)
+            .map(|(pid, _)| pid.clone())
+            .collect();
+        for pid in gone {
+            self.forget(&pid).await;
+        }
+    }
+
+    async fn forget(&mut self, pid: &str) {
+        if let Some(peer) = self.peers.remove(pid) {
+            self.addrs.remove(&peer.addr);
+            self.dam.disconnect(&peer_id(pid)).await;
+        }
+    }
+
+    /// Give up on the messages whose fragments didn't all come in time.
+    fn expire(&mut self, now: Instant) {
+        self.partials.retain(|_, partial| now.duration_since(partial.at) < FRAGMENT_AGE);
+    }
+
+    async fn receive(&mut self, datagram: &[u8], from: SocketAddr, group: bool) {
+        // A hi fits in a datagram, so only peers send fragments.
+        let known = self.addrs.get(&from).cloned();
+        if known.is_none() && datagram.first() == Some(&FRAGMENT) {
+            return;
+        }
+        let raw = match self.reassemble(datagram, from) {
+            Some(raw) => raw,
+            None => return,
+        };
+        let raw = match (String::from_utf8(raw), &known) {
+            (Ok(raw), _) => raw,
+            (Err(_), Some(pid)) => return self.dam.reject(MessageError::Binary, &peer_id(pid)).await,
+            (Err(_), None) => return,
+        };
+
+        // Our own announcements loop back to us.
+        let hello = hi_pid(&raw);
+        if hello.as_deref() == Some(self.dam.pid()) {
+            return;
+        }
+        let pid = match (hello, known) {
+            (Some(pid), Some(known)) if pid == known => {
+                // Announcements from peers we know only tell us they're still around.
+                if group {
+                    self.touch(&pid);
+                    return;
+                }
+                pid
+            }
+            (Some(pid), _) => {
+                if !self.meet(&pid, from).await {
+                    return;
+                }
+                pid
+            }
+            (None, Some(known)) => known,
+            (None, None) => return,
+        };
+        self.touch(&pid);
+        self.dam.hear(&raw, &peer_id(&pid)).await;
+    }
+
+    fn touch(&mut self, pid: &str) {
+        if let Some(peer) = self.peers.get_mut(pid) {
+            peer.at = Instant::now();
+        }
+    }
+
+    /// Take a peer saying hi from `addr` on, unless we have all the peers
+    /// we keep. A peer we know that says hi from elsewhere has moved there.
+    async fn meet(&mut self, pid: &str, addr: SocketAddr) -> bool {
+        if let Some(old) = self.addrs.get(&addr).cloned() {
+            // Whoever was at this address is gone.
+            self.forget(&old).await;
+        }
+        match self.peers.get(pid) {
+            Some(peer) => {
+                self.addrs.remove(&peer.addr);
+            }
+            None if self.peers.len() >= self.opt.max_peers => return false,
+            None => {}
+        }
+        self.peers.insert(pid.to_string(), LanPeer { addr, at: Instant::now() });
+        self.addrs.insert(addr, pid.to_string());
+        self.connect(pid, addr).await;
+        true
+    }
+
+    /// Talk to a peer at `addr`, replacing whatever we had for its pid.
+    async fn connect(&self, pid: &str, addr: SocketAddr) {
+        let (tx, mut rx) = mpsc::channel::<String>(self.dam.queue());
+        let socket = self.unicast.clone();
+        let ids = self.ids.clone();
+        let (mtu, max) = (self.opt.mtu, self.opt.max);
+        tokio::spawn(async move {
+            while let Some(raw) = rx.recv().await {
+                let id = ids.fetch_add(1, Ordering::Relaxed);
+                if let Err(e) = send(&socket, addr, raw.as_bytes(), id, mtu, max).await {
+                    eprintln!("multicast send error to {}: {}", addr, e);
+                }
+            }
+        });
+        self.dam.connect(&peer_id(pid), Peer::relay(tx)).await;
+    }
+
+    /// Put a fragmented message back together once all of it is here.
+    fn reassemble(&mut self, datagram: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+        if datagram.first() != Some(&FRAGMENT) {
+            return Some(datagram.to_vec());
+        }
+        if datagram.len() <= HEADER {
+            return None;
+        }
+        let id = u32::from_be_bytes([datagram[1], datagram[2], datagram[3], datagram[4]]);
+        let index = u16::from_be_bytes([datagram[5], datagram[6]]) as usize;
+        let count = u16::from_be_bytes([datagram[7], datagram[8]]) as usize;
+        // No more fragments than a message we take can be cut into.
+        if index >= count || count > self.opt.max.div_ceil(self.opt.mtu - HEADER) {
+            return None;
+        }
+
+        let key = (from, id);
+        if !self.partials.contains_key(&key) {
+            let now = Instant::now();
+            if self.partials.len() >= PARTIALS_MAX {
+                self.expire(now);
+            }
+            let theirs = self.partials.keys().filter(|(addr, _)| *addr == from).count();
+            if theirs >= PARTIALS_PER_PEER || self.partials.len() >= PARTIALS_MAX {
+                return None;
+            }
+            self.partials.insert(key, Partial { parts: vec![None; count], size: 0, at: now });
+        }
+        let partial = self.partials.get_mut(&key)?;
+        if partial.parts.len() != count {
+            self.partials.remove(&key);
+            return None;
+        }
+        if partial.parts[index].is_none() {
+            partial.size += datagram.len() - HEADER;
+            partial.parts[index] = Some(datagram[HEADER..].to_vec());
+        }
+        if partial.size > self.opt.max {
+            log::debug!("dropping a message from {} bigger than {} bytes", from, self.opt.max);
+            self.partials.remove(&key);
+            return None;
+        }
+        if partial.parts.iter().any(|part| part.is_none()) {
+            return None;
+        }
+        let partial = self.partials.remove(&key)?;
+        Some(partial.parts.into_iter().flatten().flatten().collect())
+    }
+}
+
+/// Send a message to one peer, in fragments if it doesn't fit in one datagram.
+async fn send(socket: &UdpSocket, to: SocketAddr, raw: &[u8], id: u32, mtu: usize, max: usize) -> io::Result<()> {
+    if raw.len() > max {
+        let e = format!("a {} byte message is bigger than the {} allowed", raw.len(), max);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+    }
+    if raw.len() <= mtu && raw.first() != Some(&FRAGMENT) {
+        socket.send_to(raw, to).await?;
+        return Ok(());
+    }
+    let chunks: Vec<&[u8]> = raw.chunks(mtu - HEADER).collect();
+    let count = chunks.len() as u16;
+    for (index, chunk) in chunks.into_iter().enumerate() {
+        let mut datagram = Vec::with_capacity(HEADER + chunk.len());
+        datagram.push(FRAGMENT);
+        datagram.extend_from_slice(&id.to_be_bytes());
+        datagram.extend_from_slice(&(index as u16).to_be_bytes());
+        datagram.extend_from_slice(&count.to_be_bytes());
+        datagram.extend_from_slice(chunk);
+        socket.send_to(&datagram, to).await?;
+    }
+    Ok(())
+}
+
+/// The pid of a DAM hi, if that's what the frame is.
+fn hi_pid(raw: &str) -> Option<String> {
+    Msg::parse(raw).into_iter()
+        .filter_map(|msg| msg.ok())
+        .filter(|msg| msg.dam.as_deref() == Some("?"))
+        .find_map(|msg| msg.other.get("pid").and_then(|pid| pid.as_str()).map(String::from))
+        .filter(|pid| !pid.is_empty() && pid.len() <= PID_MAX)
+}
+
+fn peer_id(pid: &str) -> String {
+    format!("lan:{}", pid)
+}
+
+fn group_socket(opt: &MulticastOptions) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Several peers on one machine all listen on the group's port.
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, opt.port).into())?;
+    socket.join_multicast_v4(&opt.group, &opt.interface)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn unicast_socket(opt: &MulticastOptions) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_multicast_if_v4(&opt.interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.bind(&SocketAddrV4::new(opt.interface, 0).into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::gun::gun::Gun;
+    use crate::obj::Value;
+
+    fn options(port: u16) -> MulticastOptions {
+        MulticastOptions {
+            group: Ipv4Addr::new(239, 255, 76, 67),
+            port,
+            interface: Ipv4Addr::LOCALHOST,
+            mtu: 200,
+            announce: Duration::from_millis(50),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lan_sync_on_loopback() -> io::Result<()> {
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")?.local_addr()?.port();
+        let a = Arc::new(Dam::new(Gun::new()));
+        let b = Arc::new(Dam::new(Gun::new()));
+        let lan_a = join(a.clone(), options(port)).await?;
+        let lan_b = join(b.clone(), options(port)).await?;
+
+        for _ in 0..100 {
+            if b.peers.read().await.values().any(|p| p.pid.as_deref() == Some(a.pid())) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // Far more than one datagram, so it goes in fragments.
+        let (tx, _rx) = mpsc::channel(a.queue());
+        a.connect("1", Peer::new(tx)).await;
+        let bio = "x".repeat(1000);
+        let put = format!(r##"{{"#":"p1","put":{{"mark":{{"_":{{"#":"mark",">":{{"bio":1}}}},"bio":"{}"}}}}}}"##, bio);
+        a.hear(&put, "1").await;
+        for _ in 0..100 {
+            if b.gun.node("mark").await.unwrap().is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let node = b.gun.node("mark").await.unwrap().unwrap();
+        assert_eq!(node.get("bio"), Some(&Value::Text(bio)));
+
+        lan_a.abort();
+        lan_b.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_partials_are_bounded() -> io::Result<()> {
+        let opt = MulticastOptions { mtu: 109, max: 1000, ..options(0) };
+        let mut lan = Lan {
+            dam: Arc::new(Dam::new(Gun::new())),
+            opt,
+            unicast: Arc::new(UdpSocket::bind("127.0.0.1:0").await?),
+            ids: Arc::new(AtomicU32::new(0)),
+            peers: HashMap::new(),
+            addrs: HashMap::new(),
+            partials: HashMap::new(),
+        };
+        let fragment = |id: u32, count: u16| {
+            let mut datagram = vec![FRAGMENT];
+            datagram.extend_from_slice(&id.to_be_bytes());
+            datagram.extend_from_slice(&0u16.to_be_bytes());
+            datagram.extend_from_slice(&count.to_be_bytes());
+            datagram.push(b'x');
+            datagram
+        };
+        let (a, b): (SocketAddr, SocketAddr) = ("10.0.0.1:1".parse().unwrap(), "10.0.0.2:1".parse().unwrap());
+
+        // 1000 bytes take 10 fragments of 100 at most.
+        assert!(lan.reassemble(&fragment(0, u16::MAX), a).is_none());
+        assert!(lan.partials.is_empty());
+        for id in 0..PARTIALS_PER_PEER as u32 + 1 {
+            lan.reassemble(&fragment(id, 10), a);
+        }
+        assert_eq!(lan.partials.len(), PARTIALS_PER_PEER);
+        lan.reassemble(&fragment(0, 10), b);
+        assert_eq!(lan.partials.len(), PARTIALS_PER_PEER + 1);
+
+        lan.expire(Instant::now() + FRAGMENT_AGE);
+        assert!(lan.partials.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_peers_need_a_hi_and_are_capped() -> io::Result<()> {
+        let dam = Arc::new(Dam::new(Gun::new()));
+        let mut lan = Lan {
+            dam: dam.clone(),
+            opt: MulticastOptions { max_peers: 2, ..options(0) },
+            unicast: Arc::new(UdpSocket::bind("127.0.0.1:0").await?),
+            ids: Arc::new(AtomicU32::new(0)),
+            peers: HashMap::new(),
+            addrs: HashMap::new(),
+            partials: HashMap::new(),
+        };
+        let at = |port: u16| SocketAddr::from(([127, 0, 0, 1], port));
+        let hi = |pid: &str| format!(r##"{{"#":"hi{0}","dam":"?","pid":"{0}"}}"##, pid);
+
+        for port in 1..10 {
+            lan.receive(br##"{"#":"g1","get":{"#":"mark"}}"##, at(port), false).await;
+        }
+        assert!(lan.peers.is_empty() && dam.peers.read().await.is_empty());
+
+        for (pid, port) in [("p1", 1), ("p2", 2), ("p3", 3)] {
+            lan.receive(hi(pid).as_bytes(), at(port), true).await;
+        }
+        assert_eq!(lan.peers.len(), 2);
+        assert!(!lan.peers.contains_key("p3"));
+
+        // A peer saying hi from another port has moved there.
+        lan.receive(hi("p1").as_bytes(), at(4), true).await;
+        assert_eq!(lan.peers["p1"].addr, at(4));
+        assert_eq!(lan.addrs.len(), 2);
+        assert_eq!(dam.peers.read().await.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_refuses_oversized() -> io::Result<()> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let to = socket.local_addr()?;
+        assert!(send(&socket, to, &[b'x'; 300], 1, 100, 200).await.is_err());
+        send(&socket, to, &[b'x'; 150], 1, 100, 200).await?;
+        let mut buf = [0; 200];
+        let (len, _) = socket.recv_from(&mut buf).await?;
+        assert_eq!(len, 100);
+        assert_eq!(buf[0], FRAGMENT);
+        Ok(())
+    }
+}
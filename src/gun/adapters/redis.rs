@@ -0,0 +1,368 @@
+//! Storage in Redis.
+//!
+//! Every node is kept under three keys: a hash of its values, in their
+//! JSON form, a hash of their states alongside, and a sorted set of its
+//! keys, all scored the same so that lexical queries can be answered
+//! with ZRANGEBYLEX. HAM runs in a Lua script on the server, so a merge
+//! is atomic however many relays share the same Redis.
+
+use std::collections::HashMap;
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use redis::{Client, Script};
+use tokio::sync::OnceCell;
+use crate::adapters::StorageAdapter;
+use crate::ham::{self, Mix, State};
+use crate::message::Dot;
+use crate::node::Node;
+use crate::obj::Value;
+
+/// HAM as in ham.rs: the returned list holds, for every incoming field
+/// in order, 1 if it was written, 2 if it is from the future, 0 if not.
+const MERGE: &str = r#"
+-- Compare bytes, as Rust does, whatever the server's locale.
+local function before(a, b)
+    for i = 1, math.min(#a, #b) do
+        local x, y = string.byte(a, i), string.byte(b, i)
+        if x ~= y then
+            return x < y
+        end
+    end
+    return #a < #b
+end
+
+local machine = tonumber(ARGV[1])
+local out = {}
+for i = 2, #ARGV, 3 do
+    local key, state, value = ARGV[i], tonumber(ARGV[i + 1]), ARGV[i + 2]
+    local result = 0
+    if machine < state then
+        result = 2
+    else
+        local current = redis.call('HGET', KEYS[2], key)
+        current = current and tonumber(current)
+        local wins = not current or current < state
+        if current == state then
+            wins = before(redis.call('HGET', KEYS[1], key) or 'null', value)
+        end
+        if wins then
+            redis.call('HSET', KEYS[1], key, value)
+            redis.call('HSET', KEYS[2], key, ARGV[i + 1])
+            redis.call('ZADD', KEYS[3], 0, key)
+            result = 1
+        end
+    end
+    out[#out + 1] = result
+end
+return out
+"#;
+
+pub struct RedisStore {
+    client: Client,
+    prefix: String,
+    conn: OnceCell<ConnectionManager>,
+    merge: Script,
+}
+
+impl RedisStore {
+    /// Keep nodes in the Redis server at `url`, under keys starting with
+    /// `prefix`. The connection is made when it's first needed.
+    pub fn open(url: &str, prefix: &str) -> Result<Self, String> {
+        let client = Client::open(url).map_err(|e| e.to_string())?;
+        Ok(RedisStore {
+            client,
+            prefix: prefix.to_string(),
+            conn: OnceCell::new(),
+            merge: Script::new(MERGE),
+        })
+    }
+
+    async fn conn(&self) -> Result<ConnectionManager, String> {
+        let conn = self.conn
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(conn.clone())
+    }
+
+    /// The keys a node is kept under: its values, states and sorted keys.
+    fn keys(&self, soul: &str) -> (String, String, String) {
+        (
+            format!("{}node:{}", self.prefix, soul),
+            format!("{}state:{}", self.prefix, soul),
+            format!("{}keys:{}", self.prefix, soul),
+        )
+    }
+}
+
+#[async_trait]
+impl StorageAdapter for RedisStore {
+    async fn get(&self, soul: &str) -> Result<Option<Node>, String> {
+        let (values, states, _) = self.keys(soul);
+        let (values, mut states): (HashMap<String, String>, HashMap<String, String>) = redis::pipe()
+            .atomic()
+            .hgetall(values)
+            .hgetall(states)
+            .query_async(&mut self.conn().await?)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut node = Node::new(soul);
+        for (key, value) in values.into_iter() {
+            let state = states.remove(&key);
+            insert(&mut node, key, Some(value), state)?;
+        }
+        Ok(if node.is_empty() { None } else { Some(node) })
+    }
+
+    async fn get_range(&self, soul: &str, dot: &Dot) -> Result<Option<Node>, String> {
+        let (values, states, keys) = self.keys(soul);
+        let mut conn = self.conn().await?;
+        let (min, max) = bounds(dot);
+        let found: Vec<String> = redis::cmd("ZRANGEBYLEX")
+            .arg(keys)
+            .arg(min)
+            .arg(max)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| e.to_string())?;
+        let found: Vec<String> = found.into_iter().filter(|key| dot.matches(key)).collect();
+        if found.is_empty() {
+            return Ok(None);
+        }
+
+        let (found_values, found_states): (Vec<Option<String>>, Vec<Option<String>>) = redis::pipe()
+            .atomic()
+            .cmd("HMGET").arg(values).arg(&found)
+            .cmd("HMGET").arg(states).arg(&found)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut node = Node::new(soul);
+        for ((key, value), state) in found.into_iter().zip(found_values).zip(found_states) {
+            insert(&mut node, key, value, state)?;
+        }
+        let node = node.select(dot);
+        Ok(if node.is_empty() { None } else { Some(node) })
+    }
+
+    async fn put(&self, node: &Node) -> Result<(), String> {
+        if node.is_empty() {
+            return Ok(());
+        }
+        let (values, states, keys) = self.keys(node.soul());
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (key, (val, state)) in node.iter() {
+            pipe.hset(&values, key, ham::lexical(val)).ignore();
+            pipe.hset(&states, key, number(*state)).ignore();
+            pipe.zadd(&keys, key, 0).ignore();
+        }
+        pipe.query_async(&mut self.conn().await?).await.map_err(|e| e.to_string())
+    }
+
+    async fn merge(&self, node: &Node, machine: State) -> Result<Mix, String> {
+        let (values, states, keys) = self.keys(node.soul());
+        let mut invocation = self.merge.prepare_invoke();
+        invocation.key(values).key(states).key(keys).arg(number(machine));
+        for (key, (val, state)) in node.iter() {
+            invocation.arg(key).arg(number(*state)).arg(ham::lexical(val));
+        }
+        let results: Vec<i64> = invocation.invoke_async(&mut self.conn().await?)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut diff = Node::new(node.soul());
+        let mut defer = Node::new(node.soul());
+        for ((key, (val, state)), result) in node.iter().zip(results) {
+            match result {
+                1 => diff.insert(key.to_string(), val.clone(), *state),
+                2 => defer.insert(key.to_string(), val.clone(), *state),
+                _ => {}
+            }
+        }
+        Ok(Mix { diff, defer })
+    }
+
+    fn atomic_merge(&self) -> bool {
+        true
+    }
+}
+
+fn insert(node: &mut Node, key: String, value: Option<String>, state: Option<String>) -> Result<(), String> {
+    let (value, state) = match (value, state) {
+        (Some(value), Some(state)) => (value, state),
+        _ => return Ok(()),
+    };
+    let value: Value = serde_json::from_str(&value)
+        .map_err(|e| format!("bad value for '{}' in node '{}': {}", key, node.soul(), e))?;
+    let state: State = state.parse()
+        .map_err(|e| format!("bad state for '{}' in node '{}': {}", key, node.soul(), e))?;
+    node.insert(key, value, state);
+    Ok(())
+}
+
+/// A number as Lua's tonumber reads it back.
+fn number(n: f64) -> String {
+    match n {
+        n if n == f64::INFINITY => "1e999".to_string(),
+        n if n == f64::NEG_INFINITY => "-1e999".to_string(),
+        n => n.to_string(),
+    }
+}
+
+/// The ZRANGEBYLEX bounds around the keys a query may match.
+fn bounds(dot: &Dot) -> (Vec<u8>, Vec<u8>) {
+    let min = match dot.lower() {
+        Some(key) => [b"[", key.as_bytes()].concat(),
+        None => b"-".to_vec(),
+    };
+    let max = match dot {
+        Dot::Key(key) => [b"[", key.as_bytes()].concat(),
+        Dot::Lex(lex) => match (&lex.exact, &lex.end, &lex.prefix) {
+            (Some(exact), _, _) => [b"[", exact.as_bytes()].concat(),
+            (None, Some(end), _) => [b"[", end.as_bytes()].concat(),
+            // No key in UTF-8 has a 0xFF byte, so this is past every key
+            // starting with the prefix.
+            (None, None, Some(prefix)) => [b"(", prefix.as_bytes(), &[0xFF]].concat(),
+            (None, None, None) => b"+".to_vec(),
+        },
+    };
+    (min, max)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn url() -> String {
+        std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string())
+    }
+
+    #[test]
+    fn test_bounds() {
+        let lex = serde_json::from_str(r#"{"*":"2021-08"}"#).unwrap();
+        assert_eq!(bounds(&lex), (b"[2021-08".to_vec(), b"(2021-08\xff".to_vec()));
+        assert_eq!(bounds(&Dot::Key("name".to_string())), (b"[name".to_vec(), b"[name".to_vec()));
+    }
+
+    // MERGE run by an embedded Lua 5.1, as Redis has, with redis.call
+    // answering from tables instead of a server.
+    struct Sandbox(mlua::Lua);
+
+    impl Sandbox {
+        fn new() -> Self {
+            let lua = mlua::Lua::new();
+            lua.load(r#"
+                hashes, sets = {}, {}
+                redis = {}
+                function redis.call(cmd, key, field, value)
+                    if cmd == 'HGET' then
+                        return (hashes[key] or {})[field] or false
+                    elseif cmd == 'HSET' then
+                        hashes[key] = hashes[key] or {}
+                        hashes[key][field] = value
+                    elseif cmd == 'ZADD' then
+                        sets[key] = sets[key] or {}
+                        sets[key][value] = field
+                    end
+                end
+            "#).exec().unwrap();
+            Sandbox(lua)
+        }
+
+        fn merge(&self, node: &Node, machine: State) -> Vec<i64> {
+            let mut argv = vec![number(machine)];
+            for (key, (val, state)) in node.iter() {
+                argv.extend([key.to_string(), number(*state), ham::lexical(val)]);
+            }
+            let globals = self.0.globals();
+            globals.set("KEYS", vec!["node", "state", "keys"]).unwrap();
+            globals.set("ARGV", argv).unwrap();
+            self.0.load(MERGE).eval().unwrap()
+        }
+
+        fn get(&self, key: &str) -> Option<(String, String)> {
+            let value: Option<String> = self.0.load(format!("return (hashes.node or {{}})['{}']", key)).eval().unwrap();
+            let state: Option<String> = self.0.load(format!("return (hashes.state or {{}})['{}']", key)).eval().unwrap();
+            value.zip(state)
+        }
+    }
+
+    #[test]
+    fn test_merge_script() {
+        let script = Sandbox::new();
+        let mut node = Node::new("posts");
+        node.insert("a".to_string(), Value::Text("one".to_string()), 1.0);
+        node.insert("b".to_string(), Value::Number(2.0), 5.0);
+        node.insert("c".to_string(), Value::Text("b".to_string()), 1.0);
+        // A new field is written, one from the future is deferred.
+        assert_eq!(script.merge(&node, 2.0), vec![1, 2, 1]);
+        assert_eq!(script.get("a"), Some(("\"one\"".to_string(), "1".to_string())));
+        assert_eq!(script.get("b"), None);
+
+        let mut next = Node::new("posts");
+        next.insert("a".to_string(), Value::Text("older".to_string()), 0.5);
+        next.insert("c".to_string(), Value::Text("a".to_string()), 1.0);
+        next.insert("d".to_string(), Value::Text("x".to_string()), f64::NEG_INFINITY);
+        // An older state loses, as does the lexically smaller value at the same state.
+        assert_eq!(script.merge(&next, 2.0), vec![0, 0, 1]);
+        assert_eq!(script.get("a").unwrap().0, "\"one\"");
+        assert_eq!(script.get("c").unwrap().0, "\"b\"");
+
+        let mut newer = Node::new("posts");
+        newer.insert("a".to_string(), Value::Text("two".to_string()), 1.5);
+        newer.insert("c".to_string(), Value::Text("c".to_string()), 1.0);
+        newer.insert("e".to_string(), Value::Null, 2.0);
+        // A newer state wins, as does the lexically larger value, and a
+        // state equal to the machine's is not from the future.
+        assert_eq!(script.merge(&newer, 2.0), vec![1, 1, 1]);
+        assert_eq!(script.get("a"), Some(("\"two\"".to_string(), "1.5".to_string())));
+        assert_eq!(script.get("c").unwrap().0, "\"c\"");
+        assert_eq!(script.get("e").unwrap().0, "null");
+
+        // Bytes are compared, not the locale's collation.
+        let mut upper = Node::new("posts");
+        upper.insert("c".to_string(), Value::Text("C".to_string()), 1.0);
+        upper.insert("c2".to_string(), Value::Text("é".to_string()), 1.0);
+        assert_eq!(script.merge(&upper, 2.0), vec![0, 1]);
+        let mut lower = Node::new("posts");
+        lower.insert("c2".to_string(), Value::Text("z".to_string()), 1.0);
+        assert_eq!(script.merge(&lower, 2.0), vec![0]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis-server, at REDIS_URL or on localhost"]
+    async fn test_merge_and_range() -> Result<(), String> {
+        let prefix = format!("rod-test-{}:", std::process::id());
+        let store = RedisStore::open(&url(), &prefix)?;
+
+        let mut node = Node::new("posts");
+        node.insert("2021-07-31".to_string(), Value::Text("old".to_string()), 1.0);
+        node.insert("2021-08-01".to_string(), Value::Text("new".to_string()), 1.0);
+        node.insert("2021-08-02".to_string(), Value::Number(2.0), 5.0);
+        let mix = store.merge(&node, 2.0).await?;
+        assert_eq!(mix.diff.len(), 2);
+        assert_eq!(mix.defer.state("2021-08-02"), Some(5.0));
+
+        let mut older = Node::new("posts");
+        older.insert("2021-08-01".to_string(), Value::Text("older".to_string()), 0.5);
+        older.insert("2021-07-31".to_string(), Value::Text("zzz".to_string()), 1.0);
+        let mix = store.merge(&older, 2.0).await?;
+        assert_eq!(mix.diff.get("2021-07-31"), Some(&Value::Text("zzz".to_string())));
+        assert_eq!(mix.diff.len(), 1);
+
+        let lex = serde_json::from_str(r#"{"*":"2021-08"}"#).unwrap();
+        let range = store.get_range("posts", &lex).await?.unwrap();
+        assert_eq!(range.len(), 1);
+        assert_eq!(range.get("2021-08-01"), Some(&Value::Text("new".to_string())));
+        assert_eq!(store.get("posts").await?.unwrap().len(), 2);
+
+        let (values, states, keys) = store.keys("posts");
+        redis::cmd("DEL").arg(values).arg(states).arg(keys)
+            .query_async(&mut store.conn().await?)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
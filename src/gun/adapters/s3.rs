@@ -0,0 +1,687 @@
+//! Storage in an S3-compatible bucket.
+//!
+//! The graph is kept as the filesystem backend keeps it: one sorted key
+//! space of records, cut into chunk objects named after the first key
+//! they hold, under a prefix. Writes are staged and sent in batches, once
+//! enough records have changed or shortly after the first change, and a
+//! batch costs one request for every chunk it touches, plus one to read
+//! each of those not cached yet. Chunks that grow too big are split, the
+//! chunks read are kept in a bounded local cache, and records that fail
+//! to be written are kept staged and tried again.
+//!
+//! S3 has no way to merge atomically, so a prefix should only ever be
+//! written to by one relay at a time.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use aws_config::SdkConfig;
+use aws_sdk_s3::config::{Credentials, Region};
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client;
+use futures::{stream, StreamExt};
+use tokio::sync::{Mutex, OnceCell};
+use crate::adapters::StorageAdapter;
+use crate::adapters::filesystem::{self, Chunks, Records, ESC, FIRST_CHUNK};
+use crate::message::Dot;
+use crate::node::Node;
+
+/// How many chunks are read or written at once.
+const WORKERS: usize = 16;
+
+/// How long to wait before trying again to write what failed to be.
+const RETRY: Duration = Duration::from_secs(1);
+
+pub struct S3Options {
+    /// The endpoint of an S3-compatible service, or AWS if `None`.
+    pub endpoint: Option<String>,
+    pub region: String,
+    pub bucket: String,
+    /// What the names of the objects we write start with.
+    pub prefix: String,
+    /// Keys to sign with, as for a MinIO, or `None` for those AWS's default
+    /// chain finds: in the environment, a `~/.aws` profile, a web identity
+    /// token or an instance role.
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+    /// Chunk objects are split once they grow past this many bytes.
+    pub chunk: usize,
+    /// Changed records are written once there are this many of them...
+    pub batch: usize,
+    /// ...or this long after the first of them changed.
+    pub wait: Duration,
+    /// How many chunks are kept in memory once read.
+    pub cache: usize,
+}
+
+impl Default for S3Options {
+    fn default() -> Self {
+        S3Options {
+            endpoint: None,
+            region: "us-east-1".to_string(),
+            bucket: "gun".to_string(),
+            prefix: "radata/".to_string(),
+            access_key: None,
+            secret_key: None,
+            chunk: 1024 * 1024,
+            batch: 10_000,
+            wait: Duration::from_secs(1),
+            cache: 64,
+        }
+    }
+}
+
+/// Objects is as much of a bucket as the store needs.
+#[async_trait]
+trait Objects: Send + Sync {
+    /// Read an object, or `None` if there is none by that name.
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String>;
+
+    async fn put(&self, name: &str, raw: Vec<u8>) -> Result<(), String>;
+
+    /// The names of every object starting with `prefix`.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, String>;
+}
+
+struct Bucket {
+    name: String,
+    region: String,
+    endpoint: Option<String>,
+    credentials: Option<Credentials>,
+    // Made once it's first needed, as finding credentials may take requests.
+    client: OnceCell<Client>,
+}
+
+impl Bucket {
+    async fn client(&self) -> &Client {
+        self.client.get_or_init(|| async {
+            let sdk = aws_config::from_env().region(Region::new(self.region.clone())).load().await;
+            Client::from_conf(self.config(&sdk))
+        }).await
+    }
+
+    /// The config of the default chain, under whatever keys and endpoint
+    /// we were given.
+    fn config(&self, sdk: &SdkConfig) -> aws_sdk_s3::Config {
+        let mut config = aws_sdk_s3::config::Builder::from(sdk);
+        if let Some(credentials) = &self.credentials {
+            config = config.credentials_provider(credentials.clone());
+        }
+        if let Some(endpoint) = &self.endpoint {
+            // Services other than AWS rarely have a domain for every bucket.
+            config = config.endpoint_url(endpoint).force_path_style(true);
+        }
+        config.build()
+    }
+}
+
+#[async_trait]
+impl Objects for Bucket {
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        let got = self.client().await.get_object()
+            .bucket(&self.name)
+            .key(name)
+            .send()
+            .await;
+        let got = match got {
+            Ok(got) => got,
+            Err(e) => {
+                let e = e.into_service_error();
+                if e.is_no_such_key() {
+                    return Ok(None);
+                }
+                return Err(format!("failed to read {}: {}", name, DisplayErrorContext(e)));
+            }
+        };
+        let raw = got.body.collect().await
+            .map_err(|e| format!("failed to read {}: {}", name, e))?;
+        Ok(Some(raw.into_bytes().to_vec()))
+    }
+
+    async fn put(&self, name: &str, raw: Vec<u8>) -> Result<(), String> {
+        self.client().await.put_object()
+            .bucket(&self.name)
+            .key(name)
+            .content_type("application/json")
+            .body(ByteStream::from(raw))
+            .send()
+            .await
+            .map_err(|e| format!("failed to write {}: {}", name, DisplayErrorContext(e)))?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, String> {
+        let mut names = Vec::new();
+        let mut token = None;
+        loop {
+            let listed = self.client().await.list_objects_v2()
+                .bucket(&self.name)
+                .prefix(prefix)
+                .set_continuation_token(token)
+                .send()
+                .await
+                .map_err(|e| format!("failed to list {}: {}", self.name, DisplayErrorContext(e)))?;
+            let objects = listed.contents().unwrap_or_default();
+            names.extend(objects.iter().filter_map(|object| object.key()).map(str::to_string));
+            match listed.next_continuation_token() {
+                Some(next) if listed.is_truncated() => token = Some(next.to_string()),
+                _ => return Ok(names),
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct S3Store {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    objects: Arc<dyn Objects>,
+    opt: S3Options,
+    // Which chunks there are is learnt by listing the bucket, once.
+    listed: OnceCell<()>,
+    staged: Mutex<Staged>,
+    // Batches are written one at a time.
+    writing: Mutex<()>,
+}
+
+#[derive(Default)]
+struct Staged {
+    chunks: Chunks,
+    /// Records waiting to be written.
+    pending: Records,
+    /// Records being written right now.
+    writing: Records,
+    /// Whether a batch write is already on its way.
+    scheduled: bool,
+    /// How many batches were written, so chunks read meanwhile are known
+    /// to be stale.
+    batches: u64,
+}
+
+impl S3Store {
+    /// Keep nodes in the bucket `opt` describes. Nothing is asked of it
+    /// until it's first needed.
+    pub fn open(opt: S3Options) -> Result<Self, String> {
+        let bucket = Bucket {
+            name: opt.bucket.clone(),
+            region: opt.region.clone(),
+            endpoint: opt.endpoint.clone(),
+            credentials: credentials(&opt)?,
+            client: OnceCell::new(),
+        };
+        Ok(Self::with_objects(Arc::new(bucket), opt))
+    }
+
+    fn with_objects(objects: Arc<dyn Objects>, opt: S3Options) -> Self {
+        let mut staged = Staged::default();
+        staged.chunks.insert(String::new(), None);
+        let inner = Inner {
+            objects,
+            opt,
+            listed: OnceCell::new(),
+            staged: Mutex::new(staged),
+            writing: Mutex::new(()),
+        };
+        S3Store { inner: Arc::new(inner) }
+    }
+
+    /// Write every staged record now.
+    pub async fn flush(&self) -> Result<(), String> {
+        self.inner.clone().flush().await
+    }
+}
+
+#[async_trait]
+impl StorageAdapter for S3Store {
+    async fn get(&self, soul: &str) -> Result<Option<Node>, String> {
+        self.inner.read(soul, "").await
+    }
+
+    async fn get_range(&self, soul: &str, dot: &Dot) -> Result<Option<Node>, String> {
+        let node = self.inner.read(soul, dot.lower().unwrap_or_default()).await?;
+        Ok(node.map(|node| node.select(dot)).filter(|node| !node.is_empty()))
+    }
+
+    async fn put(&self, node: &Node) -> Result<(), String> {
+        let mut staged = self.inner.staged.lock().await;
+        for (key, (val, state)) in node.iter() {
+            let record = format!("{}{}{}", node.soul(), ESC, key);
+            staged.pending.insert(record, (val.clone(), *state));
+        }
+
+        if staged.pending.len() >= self.inner.opt.batch {
+            drop(staged);
+            return self.flush().await;
+        }
+        if !staged.scheduled && !staged.pending.is_empty() {
+            staged.scheduled = true;
+            self.inner.clone().schedule(self.inner.opt.wait);
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), String> {
+        S3Store::flush(self).await
+    }
+}
+
+/// The keys given to sign with, if any.
+fn credentials(opt: &S3Options) -> Result<Option<Credentials>, String> {
+    match (&opt.access_key, &opt.secret_key) {
+        (Some(access_key), Some(secret_key)) => Ok(Some(Credentials::new(access_key, secret_key, None, None, "rod"))),
+        (None, None) => Ok(None),
+        _ => Err("an S3 access key needs a secret key, and the other way around".to_string()),
+    }
+}
+
+// What became of writing one chunk: the pieces it was split into that
+// were written, and why the rest weren't.
+struct Written {
+    chunk: String,
+    pieces: Vec<(String, Records)>,
+    error: Option<String>,
+}
+
+impl Staged {
+    /// Stage records again, unless newer ones were staged meanwhile.
+    fn restage(&mut self, records: Records) {
+        for (key, record) in records {
+            self.pending.entry(key).or_insert(record);
+        }
+    }
+}
+
+impl Inner {
+    fn object(&self, chunk: &str) -> String {
+        format!("{}{}", self.opt.prefix, filesystem::chunk_name(chunk))
+    }
+
+    /// Learn which chunks the bucket has, the first time it's needed.
+    async fn list(&self) -> Result<(), String> {
+        self.listed.get_or_try_init(|| async {
+            let mut found = Chunks::new();
+            for object in self.objects.list(&self.opt.prefix).await? {
+                let name = &object[self.opt.prefix.len()..];
+                if name == FIRST_CHUNK {
+                    continue;
+                }
+                if let Some(first) = filesystem::decode(name) {
+                    found.insert(first, None);
+                } else if filesystem::is_hashed(name) {
+                    // The name is cut short, but the chunk starts with its first key.
+                    let records = self.fetch(&object).await?;
+                    if let Some(first) = records.keys().next().cloned() {
+                        found.insert(first, Some(records));
+                    }
+                }
+            }
+            self.staged.lock().await.chunks.extend(found);
+            Ok(())
+        }).await.map(|_| ())
+    }
+
+    /// Read the records of the object called `object`, if there is one.
+    async fn fetch(&self, object: &str) -> Result<Records, String> {
+        match self.objects.get(object).await? {
+            Some(raw) => serde_json::from_slice(&raw).map_err(|e| format!("failed to read {}: {}", object, e)),
+            None => Ok(Records::new()),
+        }
+    }
+
+    /// Read several chunks at once.
+    async fn fetch_all(&self, names: Vec<String>) -> Result<HashMap<String, Records>, String> {
+        stream::iter(names)
+            .map(|name| async move {
+                let records = self.fetch(&self.object(&name)).await?;
+                Ok((name, records))
+            })
+            .buffer_unordered(WORKERS)
+            .collect::<Vec<Result<_, String>>>()
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    /// Read the keys of a node from `from` on, touching only the chunks
+    /// that hold them.
+    async fn read(&self, soul: &str, from: &str) -> Result<Option<Node>, String> {
+        self.list().await?;
+        let prefix = format!("{}{}", soul, ESC);
+        let start = format!("{}{}", prefix, from);
+        let mut fetched = (0, HashMap::new());
+        loop {
+            let mut staged = self.staged.lock().await;
+            let names = filesystem::covering(&staged.chunks, &start, soul);
+            let (batches, records) = fetched;
+            if batches == staged.batches {
+                for (name, records) in records {
+                    if let Some(chunk @ None) = staged.chunks.get_mut(&name) {
+                        *chunk = Some(records);
+                    }
+                }
+            }
+            let missing: Vec<String> = names.iter()
+                .filter(|name| staged.chunks[*name].is_none())
+                .cloned()
+                .collect();
+            if !missing.is_empty() {
+                let batches = staged.batches;
+                drop(staged);
+                fetched = (batches, self.fetch_all(missing).await?);
+                continue;
+            }
+
+            let mut node = Node::new(soul);
+            for name in names.iter() {
+                if let Some(records) = &staged.chunks[name] {
+                    filesystem::collect(&mut node, records, &start, &prefix);
+                }
+            }
+            filesystem::collect(&mut node, &staged.writing, &start, &prefix);
+            filesystem::collect(&mut node, &staged.pending, &start, &prefix);
+            filesystem::evict(&mut staged.chunks, &names, self.opt.cache);
+            return Ok(if node.is_empty() { None } else { Some(node) });
+        }
+    }
+
+    /// Write what's staged, or try again in a while if that fails.
+    async fn flush(self: Arc<Self>) -> Result<(), String> {
+        let result = self.write().await;
+        if result.is_err() {
+            let mut staged = self.staged.lock().await;
+            if !staged.scheduled && !staged.pending.is_empty() {
+                staged.scheduled = true;
+                self.clone().schedule(RETRY);
+            }
+        }
+        result
+    }
+
+    fn schedule(self: Arc<Self>, wait: Duration) {
+        tokio::spawn(async move {
+            tokio::time::sleep(wait).await;
+            let bucket = self.opt.bucket.clone();
+            if let Err(e) = self.flush().await {
+                eprintln!("failed to write to bucket {}: {}", bucket, e);
+            }
+        });
+    }
+
+    /// Write every staged record into the chunk it belongs in.
+    async fn write(&self) -> Result<(), String> {
+        let _turn = self.writing.lock().await;
+        self.staged.lock().await.scheduled = false;
+        self.list().await?;
+
+        let (mut by_chunk, missing) = {
+            let mut staged = self.staged.lock().await;
+            staged.writing = std::mem::take(&mut staged.pending);
+            let mut by_chunk: BTreeMap<String, Records> = BTreeMap::new();
+            for (key, record) in staged.writing.iter() {
+                by_chunk.entry(filesystem::chunk_of(&staged.chunks, key))
+                    .or_default()
+                    .insert(key.clone(), record.clone());
+            }
+            let missing: Vec<String> = by_chunk.keys()
+                .filter(|name| staged.chunks[*name].is_none())
+                .cloned()
+                .collect();
+            (by_chunk, missing)
+        };
+        let mut fetched = match self.fetch_all(missing).await {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                let mut staged = self.staged.lock().await;
+                let writing = std::mem::take(&mut staged.writing);
+                staged.restage(writing);
+                return Err(e);
+            }
+        };
+
+        // Every chunk as it will be, split as many times as it takes to fit.
+        let mut chunks = Vec::new();
+        {
+            let mut staged = self.staged.lock().await;
+            for (name, records) in by_chunk.iter() {
+                let mut chunk = fetched.remove(name)
+                    .or_else(|| staged.chunks[name].clone())
+                    .unwrap_or_default();
+                // Keys split off to another chunk may linger where a write failed.
+                let next = staged.chunks.range(name.to_string()..).nth(1).map(|(next, _)| next.to_string());
+                if let Some(next) = next {
+                    chunk.split_off(&next);
+                }
+                chunk.extend(records.clone());
+                match filesystem::split(name.to_string(), chunk, self.opt.chunk) {
+                    Ok(pieces) => chunks.push((name.to_string(), pieces)),
+                    Err(e) => {
+                        let writing = std::mem::take(&mut staged.writing);
+                        staged.restage(writing);
+                        return Err(e.to_string());
+                    }
+                }
+            }
+        }
+
+        let results: Vec<Written> = stream::iter(chunks)
+            .map(|(name, pieces)| self.write_chunk(name, pieces))
+            .buffer_unordered(WORKERS)
+            .collect()
+            .await;
+
+        let mut staged = self.staged.lock().await;
+        staged.writing.clear();
+        staged.batches += 1;
+        let mut touched = Vec::new();
+        let mut failed = None;
+        for Written { chunk, pieces, error } in results {
+            for (piece, records) in pieces {
+                staged.chunks.insert(piece.clone(), Some(records));
+                touched.push(piece);
+            }
+            if let Some(e) = error {
+                // Whatever wasn't written stays staged for next time.
+                staged.chunks.insert(chunk.clone(), None);
+                staged.restage(by_chunk.remove(&chunk).unwrap_or_default());
+                failed = Some(e);
+            }
+        }
+        filesystem::evict(&mut staged.chunks, &touched, self.opt.cache);
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the pieces of a chunk, the one keeping its name last, so that
+    /// what was split off is never lost if the chunk itself fails to be
+    /// written.
+    async fn write_chunk(&self, chunk: String, pieces: Vec<(String, Records, Vec<u8>)>) -> Written {
+        let mut written = Written { chunk, pieces: Vec::new(), error: None };
+        for (piece, records, raw) in pieces {
+            if let Err(e) = self.objects.put(&self.object(&piece), raw).await {
+                written.error = Some(e);
+                break;
+            }
+            written.pieces.push((piece, records));
+        }
+        written
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use crate::obj::Value;
+
+    /// A bucket in memory, counting the requests made to it.
+    #[derive(Default)]
+    struct Memory {
+        objects: std::sync::Mutex<BTreeMap<String, Vec<u8>>>,
+        gets: AtomicUsize,
+        puts: AtomicUsize,
+        down: AtomicBool,
+    }
+
+    #[async_trait]
+    impl Objects for Memory {
+        async fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+            self.gets.fetch_add(1, Ordering::Relaxed);
+            Ok(self.objects.lock().unwrap().get(name).cloned())
+        }
+
+        async fn put(&self, name: &str, raw: Vec<u8>) -> Result<(), String> {
+            if self.down.load(Ordering::Relaxed) {
+                return Err("the bucket is down".to_string());
+            }
+            self.puts.fetch_add(1, Ordering::Relaxed);
+            self.objects.lock().unwrap().insert(name.to_string(), raw);
+            Ok(())
+        }
+
+        async fn list(&self, prefix: &str) -> Result<Vec<String>, String> {
+            let objects = self.objects.lock().unwrap();
+            Ok(objects.keys().filter(|name| name.starts_with(prefix)).cloned().collect())
+        }
+    }
+
+    fn options() -> S3Options {
+        S3Options { chunk: 512, wait: Duration::from_secs(60), ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn test_chunks_survive_reopen() -> Result<(), String> {
+        let memory = Arc::new(Memory::default());
+        let store = S3Store::with_objects(memory.clone(), options());
+        for i in 0..40 {
+            let mut node = Node::new(&format!("soul{:02}", i));
+            node.insert("name".to_string(), Value::Text(format!("node number {}", i)), i as f64);
+            node.insert("n".to_string(), Value::Number(i as f64), i as f64);
+            store.put(&node).await?;
+        }
+        // Staged records are read back before they are written.
+        assert_eq!(store.get("soul39").await?.unwrap().get("n"), Some(&Value::Number(39.0)));
+        store.flush().await?;
+
+        // Nodes are batched into a few chunks, each written once.
+        let objects = memory.objects.lock().unwrap().len();
+        assert!(objects > 2 && objects < 40);
+        assert_eq!(memory.puts.load(Ordering::Relaxed), objects);
+
+        // Another write only touches the chunk it lands in, which is cached.
+        let (gets, puts) = (memory.gets.load(Ordering::Relaxed), memory.puts.load(Ordering::Relaxed));
+        let mut node = Node::new("soul07");
+        node.insert("n".to_string(), Value::Number(70.0), 70.0);
+        store.put(&node).await?;
+        store.flush().await?;
+        assert_eq!(memory.gets.load(Ordering::Relaxed), gets);
+        assert_eq!(memory.puts.load(Ordering::Relaxed), puts + 1);
+
+        let store = S3Store::with_objects(memory.clone(), S3Options { cache: 2, ..options() });
+        for i in 0..40 {
+            let node = store.get(&format!("soul{:02}", i)).await?.unwrap();
+            assert_eq!(node.get("name"), Some(&Value::Text(format!("node number {}", i))));
+        }
+        assert!(store.get("soul4").await?.is_none());
+        let cached = store.inner.staged.lock().await.chunks.values().filter(|chunk| chunk.is_some()).count();
+        assert!(cached <= 2);
+        assert_eq!(store.get("soul07").await?.unwrap().state("n"), Some(70.0));
+
+        let lex = serde_json::from_str(r#"{"*":"na"}"#).unwrap();
+        let node = store.get_range("soul07", &lex).await?.unwrap();
+        assert_eq!(node.len(), 1);
+        assert_eq!(node.state("name"), Some(7.0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_writes_are_retried() -> Result<(), String> {
+        let memory = Arc::new(Memory::default());
+        let store = S3Store::with_objects(memory.clone(), options());
+        let soul = "s".repeat(300);
+        let mut node = Node::new(&soul);
+        for i in 0..10 {
+            node.insert(format!("{}{}", "k".repeat(300), i), Value::Number(i as f64), 1.0);
+        }
+        store.put(&node).await?;
+
+        memory.down.store(true, Ordering::Relaxed);
+        assert!(store.flush().await.is_err());
+        assert_eq!(store.get(&soul).await?.unwrap().len(), 10);
+
+        // The write is tried again on its own once the bucket is back.
+        memory.down.store(false, Ordering::Relaxed);
+        tokio::time::sleep(RETRY + Duration::from_millis(500)).await;
+        assert!(memory.objects.lock().unwrap().len() > 1);
+
+        let store = S3Store::with_objects(memory.clone(), options());
+        let node = store.get(&soul).await?.unwrap();
+        assert_eq!(node.len(), 10);
+        assert_eq!(node.get(&format!("{}9", "k".repeat(300))), Some(&Value::Number(9.0)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_without_keys() {
+        // Credentials are left to the default chain.
+        assert!(S3Store::open(S3Options::default()).is_ok());
+        let half = S3Options { access_key: Some("minioadmin".to_string()), ..Default::default() };
+        assert!(S3Store::open(half).is_err());
+
+        let opt = S3Options {
+            endpoint: Some("http://127.0.0.1:9000".to_string()),
+            region: "eu-north-1".to_string(),
+            access_key: Some("minioadmin".to_string()),
+            secret_key: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let keys = credentials(&opt).unwrap().unwrap();
+        assert_eq!((keys.access_key_id(), keys.secret_access_key()), ("minioadmin", "secret"));
+        let bucket = Bucket {
+            name: opt.bucket.clone(),
+            region: opt.region.clone(),
+            endpoint: opt.endpoint.clone(),
+            credentials: Some(keys),
+            client: OnceCell::new(),
+        };
+        let sdk = SdkConfig::builder().region(Region::new(opt.region.clone())).build();
+        assert_eq!(bucket.config(&sdk).region(), Some(&Region::new("eu-north-1")));
+    }
+
+    /// Run with a MinIO at S3_ENDPOINT, e.g. `minio server /tmp/minio`,
+    /// and a bucket named `rod-test`.
+    #[tokio::test]
+    #[ignore = "needs an S3-compatible service at S3_ENDPOINT"]
+    async fn test_survives_reopen() -> Result<(), String> {
+        let options = || S3Options {
+            endpoint: Some(std::env::var("S3_ENDPOINT").unwrap_or_else(|_| "http://127.0.0.1:9000".to_string())),
+            bucket: "rod-test".to_string(),
+            prefix: format!("{}/", std::process::id()),
+            access_key: Some(std::env::var("S3_ACCESS_KEY").unwrap_or_else(|_| "minioadmin".to_string())),
+            secret_key: Some(std::env::var("S3_SECRET_KEY").unwrap_or_else(|_| "minioadmin".to_string())),
+            batch: 2,
+            ..Default::default()
+        };
+        let store = S3Store::open(options())?;
+        let mut node = Node::new("mark");
+        node.insert("name".to_string(), Value::Text("Mark".to_string()), 1.0);
+        store.put(&node).await?;
+        let mut node = Node::new("mark");
+        node.insert("age".to_string(), Value::Number(30.0), 2.0);
+        store.put(&node).await?;
+        assert_eq!(store.get("mark").await?.unwrap().len(), 2);
+        store.flush().await?;
+
+        let store = S3Store::open(options())?;
+        let node = store.get("mark").await?.unwrap();
+        assert_eq!(node.get("name"), Some(&Value::Text("Mark".to_string())));
+        assert_eq!(node.state("age"), Some(2.0));
+        Ok(())
+    }
+}
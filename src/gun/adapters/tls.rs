@@ -0,0 +1,238 @@
+use std::convert::Infallible;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use hyper::server::conn::Http;
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls_pemfile::Item;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use warp::filters::path::FullPath;
+use warp::http::Uri;
+use warp::Filter;
+
+/// How long a client may take to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificates is the certificate chain and key we serve, read from PEM
+/// files that can be read again without a restart.
+pub struct Certificates {
+    cert: String,
+    key: String,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl Certificates {
+    pub fn load(cert: &str, key: &str) -> Result<Self, String> {
+        let current = RwLock::new(Arc::new(read(cert, key)?));
+        Ok(Certificates { cert: cert.to_string(), key: key.to_string(), current })
+    }
+
+    /// Read the files again. If they can't be read, what was being served
+    /// is kept.
+    pub fn reload(&self) -> Result<(), String> {
+        let certified = read(&self.cert, &self.key)?;
+        *self.current.write().unwrap() = Arc::new(certified);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn read(cert: &str, key: &str) -> Result<CertifiedKey, String> {
+    let open = |path: &str| File::open(path).map(BufReader::new).map_err(|e| format!("failed to read {}: {}", path, e));
+    let chain = rustls_pemfile::certs(&mut open(cert)?).map_err(|e| format!("{}: {}", cert, e))?;
+    if chain.is_empty() {
+        return Err(format!("{}: no certificate found", cert));
+    }
+    let items = rustls_pemfile::read_all(&mut open(key)?).map_err(|e| format!("{}: {}", key, e))?;
+    let der = items.into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => Some(der),
+            _ => None,
+        })
+        .ok_or_else(|| format!("{}: no private key found", key))?;
+    let signing = sign::any_supported_type(&rustls::PrivateKey(der)).map_err(|e| format!("{}: {}", key, e))?;
+    Ok(CertifiedKey::new(chain.into_iter().map(rustls::Certificate).collect(), signing))
+}
+
+/// The TLS server side, serving whatever `certs` holds at the time of
+/// each handshake.
+pub fn acceptor(certs: Arc<Certificates>) -> TlsAcceptor {
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(certs);
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// Read the certificates again whenever we get SIGHUP.
+#[cfg(unix)]
+pub fn reload_on_hangup(certs: Arc<Certificates>) -> io::Result<JoinHandle<()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup())?;
+    Ok(tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match certs.reload() {
+                Ok(()) => eprintln!("reloaded the tls certificate"),
+                Err(e) => eprintln!("failed to reload the tls certificate, keeping the old one: {}", e),
+            }
+        }
+    }))
+}
+
+/// Serve https to whoever connects to `listener`. Warp can't tell who
+/// is on the other end of a TLS stream, so `service` is made for each
+/// client knowing its address. New connections are taken until `stop`.
+pub async fn serve<S, M>(listener: TcpListener, acceptor: TlsAcceptor, stop: impl Future<Output = ()>, service: M)
+where
+    M: Fn(SocketAddr) -> S,
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    tokio::pin!(stop);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut stop => return,
+        };
+        let (tcp, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Most likely out of file descriptors; give it a moment.
+                eprintln!("failed to accept a connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let (acceptor, service) = (acceptor.clone(), service(addr));
+        tokio::spawn(async move {
+            let tls = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                Ok(Ok(tls)) => tls,
+                Ok(Err(e)) => return log::debug!("tls handshake with {} failed: {}", addr, e),
+                Err(_) => return,
+            };
+            if let Err(e) = Http::new().serve_connection(tls, service).with_upgrades().await {
+                log::debug!("https connection error with {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Any plain http request -> the same url over https on `port`.
+pub fn redirect_route(port: u16) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("host")
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and_then(move |host: Option<String>, path: FullPath, query: String| async move {
+            match host.and_then(|host| https_uri(&host, port, path.as_str(), &query)) {
+                Some(uri) => Ok(warp::redirect(uri)),
+                None => Err(warp::reject::not_found()),
+            }
+        })
+}
+
+fn https_uri(host: &str, port: u16, path: &str, query: &str) -> Option<Uri> {
+    // The host without its port, which may come after an IPv6 address.
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if !host.ends_with(']') && port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    };
+    let mut uri = match port {
+        443 => format!("https://{}{}", name, path),
+        _ => format!("https://{}:{}{}", name, port, path),
+    };
+    if !query.is_empty() {
+        uri = format!("{}?{}", uri, query);
+    }
+    uri.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::convert::TryFrom;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+
+    fn self_signed(dir: &std::path::Path, name: &str) -> (String, String, Vec<u8>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let (cert_path, key_path) = (dir.join(format!("{}.pem", name)), dir.join(format!("{}.key", name)));
+        let pem = cert.serialize_pem().unwrap();
+        std::fs::write(&cert_path, &pem).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        // Each serialization is signed anew, so the DER is read back from the PEM.
+        let der = rustls_pemfile::certs(&mut pem.as_bytes()).unwrap().remove(0);
+        let path = |p: std::path::PathBuf| p.to_str().unwrap().to_string();
+        (path(cert_path), path(key_path), der)
+    }
+
+    // Connect trusting only `der`, and say which certificate we were served.
+    async fn get(addr: SocketAddr, der: &[u8]) -> Result<(String, Vec<u8>), String> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&rustls::Certificate(der.to_vec())).map_err(|e| e.to_string())?;
+        let config = rustls::ClientConfig::builder().with_safe_defaults().with_root_certificates(roots).with_no_client_auth();
+        let tcp = TcpStream::connect(addr).await.map_err(|e| e.to_string())?;
+        let name = rustls::ServerName::try_from("localhost").unwrap();
+        let mut tls = TlsConnector::from(Arc::new(config)).connect(name, tcp).await.map_err(|e| e.to_string())?;
+        tls.write_all(b"GET /hello HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n").await.map_err(|e| e.to_string())?;
+        let mut response = String::new();
+        tls.read_to_string(&mut response).await.map_err(|e| e.to_string())?;
+        let served = tls.get_ref().1.peer_certificates().unwrap()[0].0.clone();
+        Ok((response, served))
+    }
+
+    #[tokio::test]
+    async fn test_serve_and_reload() {
+        let dir = std::env::temp_dir().join(format!("rod-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key, first) = self_signed(&dir, "first");
+        let certs = Arc::new(Certificates::load(&cert, &key).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let hello = warp::path("hello").map(|| "hello");
+        tokio::spawn(serve(listener, acceptor(certs.clone()), futures::future::pending(), move |_| warp::service(hello)));
+
+        let (response, served) = get(addr, &first).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("hello"));
+        assert_eq!(served, first);
+
+        // A broken key keeps the old certificate; a new one is served once read.
+        let (new_cert, new_key, second) = self_signed(&dir, "second");
+        std::fs::write(&key, "oops").unwrap();
+        assert!(certs.reload().is_err());
+        assert_eq!(get(addr, &first).await.unwrap().1, first);
+        std::fs::rename(&new_cert, &cert).unwrap();
+        std::fs::rename(&new_key, &key).unwrap();
+        certs.reload().unwrap();
+        assert_eq!(get(addr, &second).await.unwrap().1, second);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_https_uri() {
+        let uri = |host: &str, port: u16| https_uri(host, port, "/gun", "a=1").unwrap().to_string();
+        assert_eq!(uri("example.com", 443), "https://example.com/gun?a=1");
+        assert_eq!(uri("example.com:80", 8443), "https://example.com:8443/gun?a=1");
+        assert_eq!(uri("[::1]:80", 443), "https://[::1]/gun?a=1");
+        assert_eq!(uri("[::1]", 8443), "https://[::1]:8443/gun?a=1");
+        assert_eq!(https_uri("example.com", 443, "/", "").unwrap().to_string(), "https://example.com/");
+    }
+}
@@ -0,0 +1,456 @@
+//! WebRTC data channels as a peer transport.
+//!
+//! Peers find each other the way gun.js does it: they announce their pid
+//! in an `rtc` message, and offers, answers and ICE candidates follow in
+//! `rtc` messages addressed to one pid and sent through the mesh. Our
+//! announcements only go to our own peers, and we don't relay those of
+//! others, so that they don't flood the mesh every time. Once a data
+//! channel is open, the other side is a peer of the Dam like any other.
+//!
+//! When two peers hear each other's announcement, only the one with the
+//! lower pid makes an offer; the other asks it to.
+//!
+//! Pids in signaling aren't authenticated, so a connection that is up is
+//! never replaced by another offer, only so many connections are opened
+//! at once, and those that don't come up in time are closed.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use ::webrtc::api::{APIBuilder, API};
+use ::webrtc::data_channel::RTCDataChannel;
+use ::webrtc::data_channel::data_channel_message::DataChannelMessage;
+use ::webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use ::webrtc::ice_transport::ice_server::RTCIceServer;
+use ::webrtc::peer_connection::RTCPeerConnection;
+use ::webrtc::peer_connection::configuration::RTCConfiguration;
+use ::webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use ::webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use crate::dam::{Dam, Peer};
+use crate::message::{MessageError, Msg};
+
+pub struct RtcOptions {
+    /// The STUN and TURN servers ICE may use.
+    pub ice_servers: Vec<String>,
+    /// How often we announce ourselves to our peers.
+    pub announce: Duration,
+    /// How many connections we have at most, up or on their way.
+    pub max_connections: usize,
+    /// How long a connection may take to come up before it's closed.
+    pub connect_timeout: Duration,
+}
+
+impl Default for RtcOptions {
+    fn default() -> Self {
+        // The STUN server is the one gun.js uses.
+        RtcOptions {
+            ice_servers: vec!["stun:stun.l.google.com:19302".to_string()],
+            announce: Duration::from_secs(30),
+            max_connections: 64,
+            connect_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Signal is the body of an `rtc` message.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Signal {
+    /// The pid of the sender.
+    id: String,
+    /// The pid it is meant for, or `None` for an announcement to all.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    offer: Option<RTCSessionDescription>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    answer: Option<RTCSessionDescription>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    candidate: Option<RTCIceCandidateInit>,
+}
+
+/// Connect to whoever announces WebRTC in the mesh, and announce
+/// ourselves, until the returned task is dropped or aborted.
+pub fn start(dam: Arc<Dam>, opt: RtcOptions) -> JoinHandle<()> {
+    let heard = dam.tap("rtc");
+    dam.hush("rtc", |rtc| rtc.get("to").is_none_or(|to| to.is_null()));
+    let rtc = Arc::new(Rtc {
+        dam,
+        api: APIBuilder::new().build(),
+        ice_servers: opt.ice_servers,
+        max_connections: opt.max_connections,
+        connect_timeout: opt.connect_timeout,
+        connections: Mutex::new(HashMap::new()),
+    });
+    tokio::spawn(rtc.run(heard, opt.announce))
+}
+
+struct Rtc {
+    dam: Arc<Dam>,
+    api: API,
+    ice_servers: Vec<String>,
+    max_connections: usize,
+    connect_timeout: Duration,
+    /// Our connections, by the pid of the other side.
+    connections: Mutex<HashMap<String, Connection>>,
+}
+
+struct Connection {
+    pc: Arc<RTCPeerConnection>,
+    /// Candidates that came before the other side's description.
+    candidates: Option<Vec<RTCIceCandidateInit>>,
+}
+
+impl Rtc {
+    async fn run(self: Arc<Self>, mut heard: mpsc::Receiver<Msg>, every: Duration) {
+        let mut announce = tokio::time::interval(every);
+        loop {
+            tokio::select! {
+                msg = heard.recv() => match msg {
+                    Some(msg) => {
+                        if let Err(e) = self.clone().hear(&msg).await {
+                            eprintln!("webrtc signaling failed: {}", e);
+                        }
+                    }
+                    None => return,
+                },
+                _ = announce.tick() => self.announce().await,
+            }
+        }
+    }
+
+    async fn hear(self: Arc<Self>, msg: &Msg) -> Result<(), String> {
+        let signal: Signal = match msg.other.get("rtc").map(|rtc| serde_json::from_value(rtc.clone())) {
+            Some(Ok(signal)) => signal,
+            _ => return Ok(()),
+        };
+        let me = self.dam.pid();
+        if signal.id == me || signal.to.as_deref().is_some_and(|to| to != me) {
+            return Ok(());
+        }
+
+        if let Some(offer) = signal.offer {
+            return self.answer(signal.id, offer).await;
+        }
+        if let Some(answer) = signal.answer {
+            return self.accept(&signal.id, answer).await;
+        }
+        if let Some(candidate) = signal.candidate {
+            return self.candidate(&signal.id, candidate).await;
+        }
+        if self.connections.lock().await.contains_key(&signal.id) {
+            return Ok(());
+        }
+        if signal.to.is_some() || me < signal.id.as_str() {
+            self.offer(signal.id).await
+        } else {
+            self.signal(Signal { to: Some(signal.id), ..Signal::default() }).await;
+            Ok(())
+        }
+    }
+
+    /// Send signaling through the mesh.
+    async fn signal(&self, signal: Signal) {
+        self.dam.say(&self.rtc(signal)).await;
+    }
+
+    /// Tell our own peers we take WebRTC connections.
+    async fn announce(&self) {
+        let msg = self.rtc(Signal::default());
+        let peers: Vec<String> = self.dam.peers.read().await.keys().cloned().collect();
+        for peer in peers {
+            self.dam.say_to(&peer, &msg).await;
+        }
+    }
+
+    fn rtc(&self, signal: Signal) -> Msg {
+        let signal = Signal { id: self.dam.pid().to_string(), ..signal };
+        let mut msg = Msg::new();
+        msg.other.insert("rtc".to_string(), serde_json::to_value(&signal).unwrap_or_default());
+        msg
+    }
+
+    async fn offer(self: Arc<Self>, pid: String) -> Result<(), String> {
+        let pc = self.clone().connect(&pid).await?;
+        let channel = pc.create_data_channel("dc", None).await.map_err(|e| e.to_string())?;
+        self.wire(&pid, &pc, channel);
+        let offer = pc.create_offer(None).await.map_err(|e| e.to_string())?;
+        pc.set_local_description(offer.clone()).await.map_err(|e| e.to_string())?;
+        self.signal(Signal { to: Some(pid), offer: Some(offer), ..Signal::default() }).await;
+        Ok(())
+    }
+
+    async fn answer(self: Arc<Self>, pid: String, offer: RTCSessionDescription) -> Result<(), String> {
+        let pc = self.clone().connect(&pid).await?;
+        let (rtc, from, weak) = (self.clone(), pid.clone(), Arc::downgrade(&pc));
+        pc.on_data_channel(Box::new(move |channel: Arc<RTCDataChannel>| {
+            if let Some(pc) = weak.upgrade() {
+                rtc.wire(&from, &pc, channel);
+            }
+            Box::pin(async {})
+        }));
+        self.accept(&pid, offer).await?;
+        let answer = pc.create_answer(None).await.map_err(|e| e.to_string())?;
+        pc.set_local_description(answer.clone()).await.map_err(|e| e.to_string())?;
+        self.signal(Signal { to: Some(pid), answer: Some(answer), ..Signal::default() }).await;
+        Ok(())
+    }
+
+    /// Take the other side's description, and the candidates it sent
+    /// before it. Only the first description is taken.
+    async fn accept(&self, pid: &str, desc: RTCSessionDescription) -> Result<(), String> {
+        let (pc, candidates) = match self.connections.lock().await.get_mut(pid) {
+            Some(conn) if conn.candidates.is_some() => (conn.pc.clone(), conn.candidates.take().unwrap_or_default()),
+            Some(_) => return Err(format!("already have a description from {}", pid)),
+            None => return Err(format!("no connection to {} to take a description for", pid)),
+        };
+        pc.set_remote_description(desc).await.map_err(|e| e.to_string())?;
+        for candidate in candidates {
+            pc.add_ice_candidate(candidate).await.map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    async fn candidate(&self, pid: &str, candidate: RTCIceCandidateInit) -> Result<(), String> {
+        let pc = match self.connections.lock().await.get_mut(pid) {
+            Some(Connection { candidates: Some(early), .. }) => {
+                early.push(candidate);
+                return Ok(());
+            }
+            Some(conn) => conn.pc.clone(),
+            None => return Ok(()),
+        };
+        pc.add_ice_candidate(candidate).await.map_err(|e| e.to_string())
+    }
+
+    /// Whether we may open a new connection to a pid: not if one is up
+    /// already, nor if we have as many as we take.
+    fn room(&self, connections: &HashMap<String, Connection>, pid: &str) -> Result<(), String> {
+        match connections.get(pid) {
+            Some(conn) if conn.pc.connection_state() == RTCPeerConnectionState::Connected => {
+                Err(format!("already connected to {}", pid))
+            }
+            None if connections.len() >= self.max_connections => Err("too many webrtc connections".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Open a new connection to a pid, closing any we had on its way.
+    async fn connect(self: Arc<Self>, pid: &str) -> Result<Arc<RTCPeerConnection>, String> {
+        self.room(&*self.connections.lock().await, pid)?;
+        let config = RTCConfiguration {
+            ice_servers: vec![RTCIceServer { urls: self.ice_servers.clone(), ..Default::default() }],
+            ..Default::default()
+        };
+        let pc = Arc::new(self.api.new_peer_connection(config).await.map_err(|e| e.to_string())?);
+
+        let rtc = self.clone();
+        let to = pid.to_string();
+        pc.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
+            let (rtc, to) = (rtc.clone(), to.clone());
+            Box::pin(async move {
+                if let Some(Ok(candidate)) = candidate.map(|c| c.to_json()) {
+                    rtc.signal(Signal { to: Some(to), candidate: Some(candidate), ..Signal::default() }).await;
+                }
+            })
+        }));
+        let (rtc, to, weak) = (self.clone(), pid.to_string(), Arc::downgrade(&pc));
+        pc.on_peer_connection_state_change(Box::new(move |state: RTCPeerConnectionState| {
+            let (rtc, to, weak) = (rtc.clone(), to.clone(), weak.clone());
+            Box::pin(async move {
+                if state == RTCPeerConnectionState::Failed || state == RTCPeerConnectionState::Closed {
+                    rtc.forget(&to, &weak).await;
+                }
+            })
+        }));
+
+        let old = {
+            let mut connections = self.connections.lock().await;
+            match self.room(&connections, pid) {
+                Ok(()) => connections.insert(pid.to_string(), Connection { pc: pc.clone(), candidates: Some(Vec::new()) }),
+                Err(e) => {
+                    drop(connections);
+                    let _ = pc.close().await;
+                    return Err(e);
+                }
+            }
+        };
+        if let Some(old) = old {
+            let _ = old.pc.close().await;
+        }
+
+        let (rtc, to, weak) = (self.clone(), pid.to_string(), Arc::downgrade(&pc));
+        tokio::spawn(async move {
+            tokio::time::sleep(rtc.connect_timeout).await;
+            match weak.upgrade() {
+                Some(pc) if pc.connection_state() != RTCPeerConnectionState::Connected => {
+                    rtc.forget(&to, &weak).await;
+                    let _ = pc.close().await;
+                }
+                _ => {}
+            }
+        });
+        Ok(pc)
+    }
+
+    /// Forget a connection that is down, unless another has taken its place.
+    async fn forget(&self, pid: &str, pc: &Weak<RTCPeerConnection>) {
+        let mut connections = self.connections.lock().await;
+        if connections.get(pid).is_some_and(|conn| Weak::ptr_eq(&Arc::downgrade(&conn.pc), pc)) {
+            connections.remove(pid);
+            drop(connections);
+            self.dam.disconnect(&peer_id(pid)).await;
+        }
+    }
+
+    /// Join the data channel of a connection to the mesh once it opens.
+    fn wire(self: &Arc<Self>, pid: &str, pc: &Arc<RTCPeerConnection>, channel: Arc<RTCDataChannel>) {
+        let id = peer_id(pid);
+
+        let (dam, peer) = (self.dam.clone(), id.clone());
+        channel.on_message(Box::new(move |msg: DataChannelMessage| {
+            let (dam, peer) = (dam.clone(), peer.clone());
+            Box::pin(async move {
+                match String::from_utf8(msg.data.to_vec()) {
+                    Ok(raw) if msg.is_string => dam.hear(&raw, &peer).await,
+                    _ => dam.reject(MessageError::Binary, &peer).await,
+                }
+            })
+        }));
+
+        // Once a newer connection has taken the pid, its peer isn't ours
+        // to hang up.
+        let (rtc, to, weak) = (self.clone(), pid.to_string(), Arc::downgrade(pc));
+        channel.on_close(Box::new(move || {
+            let (rtc, to, weak) = (rtc.clone(), to.clone(), weak.clone());
+            Box::pin(async move { rtc.forget(&to, &weak).await })
+        }));
+
+        let (dam, sender) = (self.dam.clone(), channel.clone());
+        channel.on_open(Box::new(move || {
+            Box::pin(async move {
+                let (tx, mut rx) = mpsc::channel::<String>(dam.queue());
+                tokio::spawn(async move {
+                    while let Some(raw) = rx.recv().await {
+                        if let Err(e) = sender.send_text(raw).await {
+                            eprintln!("webrtc send error: {}", e);
+                            break;
+                        }
+                    }
+                });
+                dam.connect(&id, Peer::new(tx)).await;
+            })
+        }));
+    }
+}
+
+fn peer_id(pid: &str) -> String {
+    format!("rtc:{}", pid)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::gun::gun::Gun;
+
+    /// Link two Dams as if over a websocket, for signaling.
+    async fn link(a: &Arc<Dam>, b: &Arc<Dam>) {
+        for (from, to, name) in [(a, b, "a"), (b, a, "b")] {
+            let (tx, mut rx) = mpsc::channel::<String>(from.queue());
+            let to = to.clone();
+            tokio::spawn(async move {
+                while let Some(raw) = rx.recv().await {
+                    to.hear(&raw, name).await;
+                }
+            });
+            from.connect(if name == "a" { "b" } else { "a" }, Peer::new(tx)).await;
+        }
+    }
+
+    async fn connected(dam: &Dam, other: &Dam) -> bool {
+        dam.peers.read().await.contains_key(&peer_id(other.pid()))
+    }
+
+    #[tokio::test]
+    async fn test_data_channel_on_loopback() {
+        let a = Arc::new(Dam::new(Gun::new()));
+        let b = Arc::new(Dam::new(Gun::new()));
+        link(&a, &b).await;
+        let opt = || RtcOptions { ice_servers: Vec::new(), announce: Duration::from_millis(200), ..RtcOptions::default() };
+        let rtc_a = start(a.clone(), opt());
+        let rtc_b = start(b.clone(), opt());
+
+        for _ in 0..200 {
+            if connected(&a, &b).await && connected(&b, &a).await {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(connected(&a, &b).await && connected(&b, &a).await);
+
+        // Drop the signaling link; from here on only the data channel is left.
+        a.disconnect("b").await;
+        b.disconnect("a").await;
+        let (tx, _rx) = mpsc::channel(a.queue());
+        a.connect("u", Peer::new(tx.clone())).await;
+        b.connect("v", Peer::new(tx)).await;
+        b.hear(r##"{"#":"g1","get":{"#":"mark"}}"##, "v").await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        a.hear(r##"{"#":"p1","put":{"mark":{"_":{"#":"mark",">":{"name":1}},"name":"Mark"}}}"##, "u").await;
+        for _ in 0..100 {
+            if b.gun.node("mark").await.unwrap().is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(b.gun.node("mark").await.unwrap().is_some());
+
+        rtc_a.abort();
+        rtc_b.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connections_are_capped_and_time_out() {
+        let rtc = Arc::new(Rtc {
+            dam: Arc::new(Dam::new(Gun::new())),
+            api: APIBuilder::new().build(),
+            ice_servers: Vec::new(),
+            max_connections: 2,
+            connect_timeout: Duration::from_millis(100),
+            connections: Mutex::new(HashMap::new()),
+        });
+        rtc.clone().connect("a").await.unwrap();
+        rtc.clone().connect("b").await.unwrap();
+        assert_eq!(rtc.clone().connect("c").await.err(), Some("too many webrtc connections".to_string()));
+        // One on its way may be replaced.
+        rtc.clone().connect("b").await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(rtc.connections.lock().await.is_empty());
+        rtc.clone().connect("c").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_old_connection_leaves_newer_peer() {
+        let rtc = Arc::new(Rtc {
+            dam: Arc::new(Dam::new(Gun::new())),
+            api: APIBuilder::new().build(),
+            ice_servers: Vec::new(),
+            max_connections: 2,
+            connect_timeout: Duration::from_secs(30),
+            connections: Mutex::new(HashMap::new()),
+        });
+        let old = Arc::downgrade(&rtc.clone().connect("a").await.unwrap());
+        let new = Arc::downgrade(&rtc.clone().connect("a").await.unwrap());
+        let (tx, _rx) = mpsc::channel(rtc.dam.queue());
+        rtc.dam.connect(&peer_id("a"), Peer::new(tx)).await;
+
+        rtc.forget("a", &old).await;
+        assert!(rtc.dam.peers.read().await.contains_key(&peer_id("a")));
+        rtc.forget("a", &new).await;
+        assert!(!rtc.dam.peers.read().await.contains_key(&peer_id("a")));
+    }
+}
@@ -0,0 +1,155 @@
+//! Outgoing websocket connections to other GUN relays.
+//!
+//! A relay we dial is a peer like any user connected to us: its frames go
+//! through the same Dam, and it gets relayed what our users say. Dropped
+//! connections are dialed again, waiting longer after every failed try.
+
+use std::sync::Arc;
+use std::time::Duration;
+use futures::{SinkExt, StreamExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+use crate::dam::{Dam, Peer};
+use crate::message::MessageError;
+
+/// How long to wait before dialing again after the first failure.
+pub const BACKOFF_MIN: Duration = Duration::from_secs(1);
+
+/// The longest we ever wait before dialing again.
+pub const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Backoff doubles the wait between tries, up to a limit.
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Backoff { min, max, next: min }
+    }
+
+    /// How long to wait before the next try.
+    pub fn wait(&mut self) -> Duration {
+        let wait = self.next;
+        self.next = std::cmp::min(self.next * 2, self.max);
+        wait
+    }
+
+    /// Start over from the shortest wait, once a try went through.
+    pub fn reset(&mut self) {
+        self.next = self.min;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(BACKOFF_MIN, BACKOFF_MAX)
+    }
+}
+
+/// Keep a connection to the relay at `url` for as long as we run.
+pub fn dial(dam: Arc<Dam>, url: String) -> JoinHandle<()> {
+    dial_with_backoff(dam, url, Backoff::default())
+}
+
+pub fn dial_with_backoff(dam: Arc<Dam>, url: String, mut backoff: Backoff) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match connect(&dam, &url, &mut backoff).await {
+                Ok(()) => eprintln!("lost relay {}", url),
+                Err(e) => eprintln!("failed to reach relay {}: {}", url, e),
+            }
+            tokio::time::sleep(backoff.wait()).await;
+        }
+    })
+}
+
+/// Talk to the relay until the connection drops.
+async fn connect(dam: &Dam, url: &str, backoff: &mut Backoff) -> Result<(), String> {
+    let (ws, _) = connect_async(url).await.map_err(|e| e.to_string())?;
+    backoff.reset();
+    eprintln!("connected to relay {}", url);
+
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let (tx, mut rx) = mpsc::channel::<String>(dam.queue());
+    let writer = tokio::spawn(async move {
+        while let Some(raw) = rx.recv().await {
+            if let Err(e) = ws_tx.send(Message::text(raw)).await {
+                eprintln!("websocket send error: {}", e);
+                break;
+            }
+        }
+        let _ = ws_tx.close().await;
+    });
+
+    // Relays are known by their url, which can't clash with the numbers
+    // given to the users connected to us.
+    dam.connect(url, Peer::relay(tx)).await;
+    let result = loop {
+        match ws_rx.next().await {
+            Some(Ok(Message::Text(raw))) => dam.hear(&raw, url).await,
+            Some(Ok(Message::Binary(_))) => dam.reject(MessageError::Binary, url).await,
+            Some(Ok(Message::Close(_))) | None => break Ok(()),
+            Some(Ok(_)) => {}
+            Some(Err(e)) => break Err(e.to_string()),
+        }
+    };
+    dam.disconnect(url).await;
+    writer.abort();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::adapters::websocket_server::{client, gun_route, Sockets};
+    use crate::limit::{LimitOptions, Limiter};
+    use crate::gun::gun::Gun;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let waits: Vec<u64> = (0..5).map(|_| backoff.wait().as_secs()).collect();
+        assert_eq!(waits, vec![1, 2, 4, 5, 5]);
+        backoff.reset();
+        assert_eq!(backoff.wait(), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_relays_mesh() {
+        // Pick a free port, then dial it before anything listens there.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let there = Arc::new(Dam::new(Gun::new()));
+        let here = Arc::new(Dam::new(Gun::new()));
+        let backoff = Backoff::new(Duration::from_millis(20), Duration::from_millis(100));
+        let dialer = dial_with_backoff(here.clone(), format!("ws://{}/gun", addr), backoff);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        tokio::spawn(warp::serve(gun_route("gun".to_string(), there.clone(), Arc::new(Limiter::new(LimitOptions::unlimited())), Sockets::default(), client(false, warp::addr::remote()))).bind(addr));
+
+        // A put from one of our users reaches the relay over the new connection.
+        let (tx, _rx) = mpsc::channel(here.queue());
+        here.connect("1", Peer::new(tx)).await;
+        let put = r##"{"#":"p1","put":{"mark":{"_":{"#":"mark",">":{"name":1}},"name":"Mark"}}}"##;
+        for _ in 0..100 {
+            if here.peers.read().await.values().any(|p| p.relay && p.pid.is_some()) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        here.hear(put, "1").await;
+        for _ in 0..100 {
+            if there.gun.node("mark").await.unwrap().is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(there.gun.node("mark").await.unwrap().is_some());
+        dialer.abort();
+    }
+}
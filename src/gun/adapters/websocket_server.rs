@@ -1,202 +1,391 @@
 // #![deny(warnings)]
-use std::collections::{HashMap, HashSet};
 use std::sync::{
     atomic::{AtomicUsize, Ordering},
     Arc,
 };
-use std::env;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
 
 use futures::{SinkExt, StreamExt, TryFutureExt};
-use tokio::sync::{mpsc, RwLock};
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tokio_stream::wrappers::ReceiverStream;
 use warp::ws::{Message, WebSocket};
 use warp::Filter;
 
-use serde_json::Value;
+use crate::adapters::{multicast, tls, webrtc, websocket_client, StorageAdapter};
+use crate::config::{Config, TlsConfig};
+use crate::dam::{Dam, Peer};
+use crate::gun::gun::Gun;
+use crate::limit::{Connection, Limiter, Verdict};
+use crate::message::{MessageError, Msg};
+use crate::metrics::METRICS;
 
 /// Our global unique user id counter.
 static NEXT_USER_ID: AtomicUsize = AtomicUsize::new(1);
 
-struct User {
-    sender: mpsc::UnboundedSender<Message>,
-    subscriptions: HashSet<String>
+#[tokio::main]
+pub async fn serve(config: Config, storage: Arc<dyn StorageAdapter>) {
+    init_logger(config.server.debug);
+
+    // Keep track of all connected users through the mesh, which
+    // hands their messages to Gun and relays them to the others.
+    let dam = Arc::new(Dam::with_queue(Gun::with_storage(storage), config.server.queue));
+    // Dial the other relays we mesh with; they join it like any user.
+    for url in config.mesh.peers.iter() {
+        websocket_client::dial(dam.clone(), url.clone());
+    }
+    if let Some(opt) = config.mesh.multicast() {
+        let group = opt.group;
+        if let Err(e) = multicast::join(dam.clone(), opt).await {
+            eprintln!("failed to join multicast group {}: {}", group, e);
+        }
+    }
+    if let Some(opt) = config.mesh.webrtc() {
+        webrtc::start(dam.clone(), opt);
+    }
+
+    let limiter = Arc::new(Limiter::new(config.limits.options()));
+    let sockets = Sockets::default();
+    let stopping = CancellationToken::new();
+
+    let server = match &config.tls {
+        Some(tls) => match serve_tls(&config, tls, dam.clone(), limiter, sockets.clone(), stopping.clone()).await {
+            Ok(server) => server,
+            Err(e) => return eprintln!("{}", e),
+        },
+        None => {
+            let client = client(config.server.trust_forwarded, warp::addr::remote());
+            let routes = routes(&config, dam.clone(), limiter, sockets.clone(), client);
+            let (addr, server) = warp::serve(routes).bind_with_graceful_shutdown(config.bind(), stopping.clone().cancelled_owned());
+            eprintln!("Starting server at http://{}", addr);
+            tokio::spawn(server)
+        }
+    };
+
+    stop_signal().await;
+    eprintln!("shutting down");
+    stopping.cancel();
+    let deadline = config.shutdown_timeout();
+    let stopped = tokio::time::timeout(deadline, async {
+        // Nothing more is heard once the websockets stop being read, so
+        // storage is flushed while they are sent what is left for them.
+        sockets.stop().await;
+        let (flushed, _, _) = tokio::join!(dam.gun.flush(), sockets.closed(), server);
+        flushed
+    }).await;
+    match stopped {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => eprintln!("failed to flush storage: {}", e),
+        Err(_) => eprintln!("gave up on closing connections after {:?}", deadline),
+    }
+}
+
+/// Listen for https, and for http to redirect there, until `stopping`.
+async fn serve_tls(config: &Config, tls: &TlsConfig, dam: Arc<Dam>, limiter: Arc<Limiter>, sockets: Sockets, stopping: CancellationToken) -> Result<JoinHandle<()>, String> {
+    let certs = Arc::new(tls::Certificates::load(&tls.cert, &tls.key)?);
+    if let Err(e) = tls::reload_on_hangup(certs.clone()) {
+        eprintln!("failed to listen for SIGHUP, the tls certificate won't be reloaded: {}", e);
+    }
+    if let Some(redirect) = &tls.redirect {
+        let redirect: SocketAddr = redirect.parse().unwrap();
+        let redirects = warp::serve(tls::redirect_route(config.bind().port()));
+        let (addr, server) = redirects.bind_with_graceful_shutdown(redirect, stopping.clone().cancelled_owned());
+        eprintln!("Redirecting http://{} to https", addr);
+        tokio::spawn(server);
+    }
+    let listener = TcpListener::bind(config.bind()).await
+        .map_err(|e| format!("failed to listen on {}: {}", config.bind(), e))?;
+    eprintln!("Starting server at https://{}", config.bind());
+    let config = config.clone();
+    Ok(tokio::spawn(tls::serve(listener, tls::acceptor(certs), stopping.cancelled_owned(), move |addr| {
+        let client = client(config.server.trust_forwarded, warp::any().map(move || Some(addr)));
+        warp::service(routes(&config, dam.clone(), limiter.clone(), sockets.clone(), client))
+    })))
 }
-impl User {
-    fn new(sender: mpsc::UnboundedSender<Message>) -> User {
-        User { sender, subscriptions: HashSet::new() }
+
+/// Wait until we are told to stop, by SIGTERM or Ctrl-C.
+async fn stop_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => tokio::select! {
+                _ = term.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            },
+            Err(e) => {
+                eprintln!("failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
     }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
 
-/// Our state of currently connected users.
-///
-/// - Key is their id
-/// - Value is a sender of `warp::ws::Message`
-type Users = Arc<RwLock<HashMap<usize, User>>>;
+/// Sockets are the websockets we serve, kept track of so that they can be
+/// closed properly when we stop.
+#[derive(Clone, Default)]
+pub struct Sockets {
+    closing: CancellationToken,
+    readers: TaskTracker,
+    writers: TaskTracker,
+}
 
-#[tokio::main]
-pub async fn serve() {
-    pretty_env_logger::init();
+impl Sockets {
+    /// Stop reading from every websocket, and wait until none is.
+    pub async fn stop(&self) {
+        self.closing.cancel();
+        self.readers.close();
+        self.readers.wait().await;
+    }
 
-    // Keep track of all connected users, key is usize, value
-    // is a websocket sender.
-    let users = Users::default();
+    /// Wait for every websocket to be sent what was queued for it and closed.
+    pub async fn closed(&self) {
+        self.writers.close();
+        self.writers.wait().await;
+    }
+}
+
+/// The address of whoever is asking: where `remote` says the connection
+/// comes from or, if `trust_forwarded`, the last address the proxy in
+/// front of us added to `X-Forwarded-For`.
+pub fn client<R>(trust_forwarded: bool, remote: R) -> impl Filter<Extract = (Option<IpAddr>,), Error = Infallible> + Clone
+where
+    R: Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone + Send + Sync + 'static,
+{
+    remote.and(warp::header::headers_cloned()).map(move |addr: Option<SocketAddr>, headers: warp::http::HeaderMap| {
+        let forwarded = headers.get_all("x-forwarded-for").iter().next_back()
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        match forwarded {
+            Some(ip) if trust_forwarded => Some(ip),
+            _ => addr.map(|addr| addr.ip()),
+        }
+    })
+}
+
+/// Everything we serve, with `client` telling who is asking.
+fn routes<R>(config: &Config, dam: Arc<Dam>, limiter: Arc<Limiter>, sockets: Sockets, client: R) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+where
+    R: Filter<Extract = (Option<IpAddr>,), Error = Infallible> + Clone + Send + Sync + 'static,
+{
+    // The static files, unless there is no directory to serve them from.
+    let serve_static = !config.server.static_dir.is_empty();
+    let iris = warp::any()
+        .and_then(move || async move { if serve_static { Ok(()) } else { Err(warp::reject::not_found()) } })
+        .untuple_one()
+        .and(warp::fs::dir(config.server.static_dir.clone()));
+
+    iris
+        .or(metrics_route(config.server.metrics_path.clone(), dam.clone(), limiter.clone()))
+        .or(gun_route(config.server.gun_path.clone(), dam, limiter, sockets, client))
+}
+
+/// GET /<path> -> websocket upgrade, with every user joining the mesh and
+/// held to the limits of `limiter` for where `client` says they are.
+pub fn gun_route<R>(path: String, dam: Arc<Dam>, limiter: Arc<Limiter>, sockets: Sockets, client: R) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+where
+    R: Filter<Extract = (Option<IpAddr>,), Error = Infallible> + Clone + Send + Sync + 'static,
+{
     // Turn our "state" into a new Filter...
-    let users = warp::any().map(move || users.clone());
+    let dam = warp::any().map(move || dam.clone());
+    let limiter = warp::any().map(move || limiter.clone());
+    let sockets = warp::any().map(move || sockets.clone());
 
-    // GET /gun -> websocket upgrade
-    let chat = warp::path("gun")
+    warp::path(path)
         // The `ws()` filter will prepare Websocket handshake...
         .and(warp::ws())
-        .and(users)
-        .map(|ws: warp::ws::Ws, users| {
+        .and(client)
+        .and(dam)
+        .and(limiter)
+        .and(sockets)
+        .map(|ws: warp::ws::Ws, ip: Option<IpAddr>, dam, limiter: Arc<Limiter>, sockets: Sockets| {
+            let limit = limiter.open(ip);
             // This will call our function if the handshake succeeds.
-            ws.on_upgrade(move |socket| user_connected(socket, users))
-        });
-
-    let iris = warp::fs::dir("assets/iris");
+            let readers = sockets.readers.clone();
+            ws.on_upgrade(move |socket| readers.track_future(user_connected(socket, dam, limit, sockets)))
+        })
+}
 
-    let routes = iris.or(chat);
+/// GET /<path> -> what the relay has been doing, for Prometheus to scrape.
+pub fn metrics_route(path: String, dam: Arc<Dam>, limiter: Arc<Limiter>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let state = warp::any().map(move || (dam.clone(), limiter.clone()));
 
-    let port: u16 = match env::var("PORT") {
-        Ok(p) => p.parse::<u16>().unwrap(),
-        _ => 5000
-    };
+    warp::path(path)
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(state)
+        .and_then(|(dam, limiter): (Arc<Dam>, Arc<Limiter>)| async move {
+            let text = METRICS.render(&dam, &limiter).await;
+            Ok::<_, Infallible>(warp::reply::with_header(text, "content-type", "text/plain; version=0.0.4"))
+        })
+}
 
-    eprintln!("Starting server at http://localhost:{}", port);
-    warp::serve(routes).run(([0, 0, 0, 0], port)).await;
+/// Log warnings, and our own debug output too with `debug`. RUST_LOG, if
+/// set, has the last word.
+fn init_logger(debug: bool) {
+    let mut builder = pretty_env_logger::formatted_builder();
+    builder.filter_level(log::LevelFilter::Warn);
+    if debug {
+        builder.filter_module("rod", log::LevelFilter::Debug);
+    }
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    let _ = builder.try_init();
 }
 
-async fn user_connected(ws: WebSocket, users: Users) {
+async fn user_connected(ws: WebSocket, dam: Arc<Dam>, mut limit: Connection, sockets: Sockets) {
     // Use a counter to assign a new unique ID for this user.
-    let my_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
+    let my_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed).to_string();
 
-    eprintln!("new chat user: {}", my_id);
+    log::debug!("new chat user: {}", my_id);
 
     // Split the socket into a sender and receive of messages.
     let (mut user_ws_tx, mut user_ws_rx) = ws.split();
 
-    // Use a channel to handle buffering and flushing of messages
-    // to the websocket...
-    let (tx, rx) = mpsc::unbounded_channel();
-    let mut rx = UnboundedReceiverStream::new(rx);
+    // Use a bounded channel to handle buffering and flushing of messages
+    // to the websocket. A user who can't keep up is dropped by the mesh,
+    // which closes the channel and so the socket.
+    let (tx, rx) = mpsc::channel::<String>(dam.queue());
+    let mut rx = ReceiverStream::new(rx);
 
-    tokio::task::spawn(async move {
-        while let Some(message) = rx.next().await {
+    let (writer_dam, writer_id) = (dam.clone(), my_id.clone());
+    sockets.writers.spawn(async move {
+        while let Some(raw) = rx.next().await {
             let mut errored = false;
             user_ws_tx
-                .send(message)
+                .send(Message::text(raw))
                 .unwrap_or_else(|e| {
-                    eprintln!("websocket send error: {}", e);
+                    log::debug!("websocket send error: {}", e);
                     errored = true;
                 })
                 .await;
             if errored {
-                let _ = user_ws_tx.close().await;
-                // TODO: in production, the app runs out of memory quickly.
-                // Maybe due to users disconnecting but unbounded tx channel still being written to?
-                // how to user_disconnect here?
+                writer_dam.disconnect(&writer_id).await;
+                break;
             }
         }
+        let _ = user_ws_tx.close().await;
     });
 
-    // Save the sender in our list of connected users.
-    let user = User::new(tx);
-    users.write().await.insert(my_id, user);
+    // Save the sender in our list of connected peers.
+    dam.connect(&my_id, Peer::new(tx)).await;
 
     // Return a `Future` that is basically a state machine managing
     // this specific user's connection.
 
-    // Every time the user sends a message, broadcast it to
-    // all other users...
-    while let Some(result) = user_ws_rx.next().await {
+    // Every time the user sends a message, hand it to the mesh, until
+    // they leave or we stop...
+    loop {
+        let result = tokio::select! {
+            result = user_ws_rx.next() => result,
+            _ = sockets.closing.cancelled() => break,
+        };
         let msg = match result {
-            Ok(msg) => msg,
-            Err(e) => {
-                eprintln!("websocket error(uid={}): {}", my_id, e);
+            Some(Ok(msg)) => msg,
+            None => break,
+            Some(Err(e)) => {
+                log::debug!("websocket error(uid={}): {}", my_id, e);
                 break;
             }
         };
-        user_message(my_id, msg, &users).await;
+        if user_message(&my_id, msg, &dam, &mut limit).await == Verdict::Disconnect {
+            eprintln!("user {} kept going over its limits", my_id);
+            break;
+        }
     }
 
     // user_ws_rx stream will keep processing as long as the user stays
     // connected. Once they disconnect, then...
-    user_disconnected(my_id, &users).await;
+    user_disconnected(&my_id, &dam).await;
 }
 
-async fn user_message(my_id: usize, msg: Message, users: &Users) {
-    let msg_str = if let Ok(s) = msg.to_str() {
-        s
-    } else {
-        return;
-    };
-
-    let json: Value = match serde_json::from_str(msg_str) {
-        Ok(json) => json,
-        Err(_) => { return; }
-    };
-
-    if json.is_array() {
-        for sth in json.as_array().iter() {
-            for obj in sth.iter() {
-                user_message_item(my_id, users, obj, msg_str).await;
-            }
+async fn user_message(my_id: &str, msg: Message, dam: &Dam, limit: &mut Connection) -> Verdict {
+    // Frames over the byte limit aren't even parsed.
+    let bytes = msg.as_bytes().len();
+    let mut verdict = limit.check_bytes(bytes);
+    if verdict == Verdict::Allow {
+        let items = match msg.to_str() {
+            Ok(s) => Msg::parse(s),
+            Err(_) if msg.is_binary() => vec![Err(MessageError::Binary)],
+            Err(_) => return Verdict::Allow,
+        };
+        verdict = limit.check_messages(items.len());
+        if verdict == Verdict::Allow {
+            dam.hear_parsed(items, bytes, my_id).await;
+            return verdict;
         }
-    } else {
-        user_message_item(my_id, users, &json, msg_str).await;
     }
+    dam.reject(MessageError::Limited, my_id).await;
+    verdict
+}
+
+async fn user_disconnected(my_id: &str, dam: &Dam) {
+    log::debug!("good bye user: {}", my_id);
+
+    // Stream closed up, so remove from the peer list
+    dam.disconnect(my_id).await;
 }
 
-async fn user_message_item(my_id: usize, users: &Users, json: &Value, msg_str: &str) {
-    // eprintln!("user {} sent request with id {}, get {} and put {}", my_id, json["#"], json["get"], json["put"]);
-    if json["#"] == Value::Null || (json["get"] == Value::Null && json["put"] == Value::Null) {
-        // eprintln!("user {} sent funny request {}", my_id, msg_str);
-        return;
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::time::Duration;
+    use tokio_tungstenite::{connect_async, tungstenite};
+    use crate::limit::LimitOptions;
+
+    #[tokio::test]
+    async fn test_client_behind_proxy() {
+        let remote = warp::any().map(|| Some(SocketAddr::from(([10, 0, 0, 1], 1234))));
+        let proxied = || warp::test::request().header("x-forwarded-for", "6.6.6.6, 1.2.3.4");
+        let proxy = Some("10.0.0.1".parse().unwrap());
+        assert_eq!(proxied().filter(&client(true, remote)).await.unwrap(), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(proxied().filter(&client(false, remote)).await.unwrap(), proxy);
+        assert_eq!(warp::test::request().filter(&client(true, remote)).await.unwrap(), proxy);
     }
 
-    if json["get"] != Value::Null {
-        match users.write().await.get_mut(&my_id) {
-            Some(user) => {
-                match json["get"]["#"].as_str() {
-                    Some(path) => { user.subscriptions.insert(path.to_string()); },
-                    _ => {}
-                }
-            },
-            _ => { return; }
+    #[tokio::test]
+    async fn test_sockets_close_after_sending_what_is_queued() {
+        let dam = Arc::new(Dam::new(Gun::new()));
+        let sockets = Sockets::default();
+        let limiter = Arc::new(Limiter::new(LimitOptions::unlimited()));
+        let route = gun_route("gun".to_string(), dam.clone(), limiter, sockets.clone(), client(false, warp::addr::remote()));
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let (mut ws, _) = connect_async(format!("ws://{}/gun", addr)).await.unwrap();
+        let mut users = Vec::new();
+        for _ in 0..100 {
+            users = dam.peers.read().await.keys().cloned().collect();
+            if !users.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
-    }
+        let bye = Msg { id: Some("bye".to_string()), ..Msg::new() };
+        dam.say_to(&users[0], &bye).await;
+
+        sockets.stop().await;
+        assert!(dam.peers.read().await.is_empty());
+        tokio::time::timeout(Duration::from_secs(5), sockets.closed()).await.unwrap();
 
-    // New message from this user, relay it to everyone else (except same uid)...
-    for (&uid, user) in users.read().await.iter() {
-        if my_id != uid {
-            match &json["put"] {
-                Value::Object(put) => {
-                    let mut has = false;
-                    for (put_path, _v) in put.into_iter() {
-                        for s in user.subscriptions.iter() {
-                            if s.find(put_path) != None || put_path.find(s) != None {
-                                has = true;
-                                break;
-                            }
-                        }
-                        if has {
-                            break;
-                        }
-                    }
-                    if !has {
-                        continue;
-                    }
-                },
+        let (mut heard, mut closed) = (Vec::new(), false);
+        while let Some(Ok(frame)) = ws.next().await {
+            match frame {
+                tungstenite::Message::Text(raw) => heard.extend(Msg::parse(&raw).into_iter().filter_map(Result::ok)),
+                tungstenite::Message::Close(_) => closed = true,
                 _ => {}
             }
-            let _ = user.sender.send(Message::text(json.to_string()));
         }
+        assert_eq!(heard.last().and_then(|msg| msg.id.clone()), Some("bye".to_string()));
+        assert!(closed);
     }
 }
-
-async fn user_disconnected(my_id: usize, users: &Users) {
-    eprintln!("good bye user: {}", my_id);
-
-    // Stream closed up, so remove from the user list
-    users.write().await.remove(&my_id);
-}
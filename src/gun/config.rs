@@ -0,0 +1,398 @@
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use serde::Deserialize;
+use crate::adapters::{StorageOptions, BACKENDS};
+use crate::adapters::multicast::MulticastOptions;
+use crate::adapters::webrtc::RtcOptions;
+use crate::dam::QUEUE;
+use crate::limit::{LimitOptions, Rate};
+
+/// Config is everything a relay is set up with. It is read from a TOML
+/// file, and any of it can be overridden by a `ROD_<SECTION>_<KEY>`
+/// environment variable, like `ROD_SERVER_BIND` or `ROD_MESH_PEERS`.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub mesh: MeshConfig,
+    pub limits: LimitsConfig,
+    /// Serve https and wss from a certificate, if set.
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// The address to listen on. `PORT` overrides its port, as Heroku sets it.
+    pub bind: String,
+    /// Where websockets connect to.
+    pub gun_path: String,
+    /// Where Prometheus scrapes.
+    pub metrics_path: String,
+    /// The directory of static files to serve, or empty for none.
+    pub static_dir: String,
+    /// How many frames may wait to be sent to a peer before it is dropped.
+    pub queue: usize,
+    /// How many seconds we may take to close the connections and flush
+    /// storage once told to stop.
+    pub shutdown_timeout: u64,
+    /// Log comings and goings of peers and what's wrong with what they
+    /// send, which is too much to log otherwise.
+    pub debug: bool,
+    /// Take clients to be at the address a proxy in front of us, like
+    /// Heroku's router, appended to `X-Forwarded-For`, rather than at the
+    /// proxy's own. Only set this behind such a proxy, or clients can
+    /// claim to be anyone.
+    pub trust_forwarded: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: "0.0.0.0:5000".to_string(),
+            gun_path: "gun".to_string(),
+            metrics_path: "metrics".to_string(),
+            static_dir: "assets/iris".to_string(),
+            queue: QUEUE,
+            shutdown_timeout: 10,
+            debug: false,
+            trust_forwarded: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// One of `BACKENDS`.
+    pub backend: String,
+    pub path: String,
+    pub redis_url: String,
+    pub redis_prefix: String,
+    pub s3_endpoint: Option<String>,
+    pub s3_region: String,
+    pub s3_bucket: String,
+    pub s3_prefix: String,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        let opt = StorageOptions::default();
+        StorageConfig {
+            backend: "filesystem".to_string(),
+            path: opt.path,
+            redis_url: opt.redis_url,
+            redis_prefix: opt.redis_prefix,
+            s3_endpoint: opt.s3_endpoint,
+            s3_region: opt.s3_region,
+            s3_bucket: opt.s3_bucket,
+            s3_prefix: opt.s3_prefix,
+        }
+    }
+}
+
+impl StorageConfig {
+    pub fn options(&self) -> StorageOptions {
+        StorageOptions {
+            path: self.path.clone(),
+            redis_url: self.redis_url.clone(),
+            redis_prefix: self.redis_prefix.clone(),
+            s3_endpoint: self.s3_endpoint.clone(),
+            s3_region: self.s3_region.clone(),
+            s3_bucket: self.s3_bucket.clone(),
+            s3_prefix: self.s3_prefix.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MeshConfig {
+    /// Other relays to mesh with, like `wss://host/gun`.
+    pub peers: Vec<String>,
+    /// Find and sync with peers on the local network.
+    pub multicast: bool,
+    /// Connect to the peers of the mesh over WebRTC data channels.
+    pub webrtc: bool,
+}
+
+impl MeshConfig {
+    pub fn multicast(&self) -> Option<MulticastOptions> {
+        if self.multicast { Some(MulticastOptions::default()) } else { None }
+    }
+
+    pub fn webrtc(&self) -> Option<RtcOptions> {
+        if self.webrtc { Some(RtcOptions::default()) } else { None }
+    }
+}
+
+/// What clients may send per second, or 0 for no limit.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Messages per connection.
+    pub messages: u32,
+    /// Bytes per connection.
+    pub bytes: u64,
+    /// Messages from all connections of one address.
+    pub ip_messages: u32,
+    /// Bytes from all connections of one address.
+    pub ip_bytes: u64,
+    /// After how many frames over the limits a connection is dropped,
+    /// or 0 for never.
+    pub strikes: u32,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        let opt = LimitOptions::default();
+        LimitsConfig {
+            messages: opt.connection.messages,
+            bytes: opt.connection.bytes,
+            ip_messages: opt.ip.messages,
+            ip_bytes: opt.ip.bytes,
+            strikes: opt.strikes,
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn options(&self) -> LimitOptions {
+        LimitOptions {
+            connection: Rate { messages: self.messages, bytes: self.bytes },
+            ip: Rate { messages: self.ip_messages, bytes: self.ip_bytes },
+            strikes: self.strikes,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// The PEM certificate chain.
+    pub cert: String,
+    /// The PEM private key.
+    pub key: String,
+    /// An address to redirect plain http from to https, if any.
+    pub redirect: Option<String>,
+}
+
+impl Config {
+    /// Read the config file at `path`, or start from the defaults if
+    /// there is none, then apply the environment over it.
+    pub fn load(path: Option<&str>) -> Result<Config, String> {
+        let mut config = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+                Config::parse(&text).map_err(|e| format!("{}: {}", path, e))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        Ok(config)
+    }
+
+    /// Read a config from TOML.
+    pub fn parse(text: &str) -> Result<Config, String> {
+        toml::from_str(text).map_err(|e| e.to_string())
+    }
+
+    /// Override the config with whatever of it `var` has, by the names of
+    /// the environment variables.
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        let var = &var;
+        set(&mut self.server.bind, "ROD_SERVER_BIND", var)?;
+        if let Some(port) = var("PORT") {
+            let port: u16 = port.parse().map_err(|_| format!("PORT: '{}' is not a port", port))?;
+            let bind = bind_addr("server.bind", &self.server.bind)?;
+            self.server.bind = SocketAddr::new(bind.ip(), port).to_string();
+        }
+        set(&mut self.server.gun_path, "ROD_SERVER_GUN_PATH", var)?;
+        set(&mut self.server.metrics_path, "ROD_SERVER_METRICS_PATH", var)?;
+        set(&mut self.server.static_dir, "ROD_SERVER_STATIC_DIR", var)?;
+        set(&mut self.server.queue, "ROD_SERVER_QUEUE", var)?;
+        set(&mut self.server.shutdown_timeout, "ROD_SERVER_SHUTDOWN_TIMEOUT", var)?;
+        set(&mut self.server.debug, "ROD_SERVER_DEBUG", var)?;
+        set(&mut self.server.trust_forwarded, "ROD_SERVER_TRUST_FORWARDED", var)?;
+
+        set(&mut self.storage.backend, "ROD_STORAGE_BACKEND", var)?;
+        set(&mut self.storage.path, "ROD_STORAGE_PATH", var)?;
+        set(&mut self.storage.redis_url, "ROD_STORAGE_REDIS_URL", var)?;
+        set(&mut self.storage.redis_prefix, "ROD_STORAGE_REDIS_PREFIX", var)?;
+        if let Some(endpoint) = var("ROD_STORAGE_S3_ENDPOINT") {
+            self.storage.s3_endpoint = Some(endpoint).filter(|endpoint| !endpoint.is_empty());
+        }
+        set(&mut self.storage.s3_region, "ROD_STORAGE_S3_REGION", var)?;
+        set(&mut self.storage.s3_bucket, "ROD_STORAGE_S3_BUCKET", var)?;
+        set(&mut self.storage.s3_prefix, "ROD_STORAGE_S3_PREFIX", var)?;
+
+        if let Some(peers) = var("ROD_MESH_PEERS") {
+            self.mesh.peers = peers.split(',').map(str::trim).filter(|url| !url.is_empty()).map(String::from).collect();
+        }
+        set(&mut self.mesh.multicast, "ROD_MESH_MULTICAST", var)?;
+        set(&mut self.mesh.webrtc, "ROD_MESH_WEBRTC", var)?;
+
+        set(&mut self.limits.messages, "ROD_LIMITS_MESSAGES", var)?;
+        set(&mut self.limits.bytes, "ROD_LIMITS_BYTES", var)?;
+        set(&mut self.limits.ip_messages, "ROD_LIMITS_IP_MESSAGES", var)?;
+        set(&mut self.limits.ip_bytes, "ROD_LIMITS_IP_BYTES", var)?;
+        set(&mut self.limits.strikes, "ROD_LIMITS_STRIKES", var)?;
+
+        if ["ROD_TLS_CERT", "ROD_TLS_KEY", "ROD_TLS_REDIRECT"].iter().any(|name| var(name).is_some()) {
+            let tls = self.tls.get_or_insert_with(TlsConfig::default);
+            set(&mut tls.cert, "ROD_TLS_CERT", var)?;
+            set(&mut tls.key, "ROD_TLS_KEY", var)?;
+            if let Some(redirect) = var("ROD_TLS_REDIRECT") {
+                tls.redirect = Some(redirect).filter(|redirect| !redirect.is_empty());
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the config makes sense, before anything is started with it.
+    pub fn validate(&self) -> Result<(), String> {
+        let bind = bind_addr("server.bind", &self.server.bind)?;
+        segment("server.gun_path", &self.server.gun_path)?;
+        segment("server.metrics_path", &self.server.metrics_path)?;
+        if self.server.gun_path == self.server.metrics_path {
+            return Err("server.gun_path and server.metrics_path must differ".to_string());
+        }
+        if self.server.queue == 0 {
+            return Err("server.queue must be at least 1".to_string());
+        }
+        if !BACKENDS.contains(&self.storage.backend.as_str()) {
+            return Err(format!("storage.backend must be one of {}, not '{}'", BACKENDS.join(", "), self.storage.backend));
+        }
+        for url in self.mesh.peers.iter() {
+            if !url.starts_with("ws://") && !url.starts_with("wss://") {
+                return Err(format!("mesh.peers: '{}' is not a ws:// or wss:// url", url));
+            }
+        }
+        if let Some(tls) = &self.tls {
+            for (name, path) in [("tls.cert", &tls.cert), ("tls.key", &tls.key)] {
+                if path.is_empty() {
+                    return Err(format!("{} is required to serve tls", name));
+                }
+                if !Path::new(path).is_file() {
+                    return Err(format!("{}: '{}' is not a file", name, path));
+                }
+            }
+            if let Some(redirect) = &tls.redirect {
+                if bind_addr("tls.redirect", redirect)? == bind {
+                    return Err("tls.redirect must be another address than server.bind".to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The address to listen on.
+    pub fn bind(&self) -> SocketAddr {
+        self.server.bind.parse().unwrap()
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.server.shutdown_timeout)
+    }
+}
+
+fn set<T: FromStr>(field: &mut T, name: &str, var: &dyn Fn(&str) -> Option<String>) -> Result<(), String>
+where
+    T::Err: Display,
+{
+    if let Some(val) = var(name) {
+        *field = val.parse().map_err(|e| format!("{}: invalid value '{}': {}", name, val, e))?;
+    }
+    Ok(())
+}
+
+fn bind_addr(name: &str, addr: &str) -> Result<SocketAddr, String> {
+    addr.parse().map_err(|_| format!("{}: '{}' is not an address like 0.0.0.0:5000", name, addr))
+}
+
+fn segment(name: &str, path: &str) -> Result<(), String> {
+    if path.is_empty() || path.contains('/') {
+        return Err(format!("{}: '{}' must be a single path segment, like 'gun'", name, path));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(r#"
+            [server]
+            bind = "127.0.0.1:8765"
+            static_dir = ""
+
+            [storage]
+            backend = "redis"
+            redis_prefix = "rod:"
+
+            [mesh]
+            peers = ["wss://relay.example.com/gun"]
+            multicast = true
+
+            [limits]
+            messages = 10
+        "#).unwrap();
+        assert_eq!(config.bind(), "127.0.0.1:8765".parse().unwrap());
+        assert_eq!(config.server.gun_path, "gun");
+        assert_eq!(config.storage.options().redis_prefix, "rod:");
+        assert_eq!(config.storage.redis_url, StorageConfig::default().redis_url);
+        assert!(config.mesh.multicast().is_some() && config.mesh.webrtc().is_none());
+        assert_eq!(config.limits.options().connection, Rate { messages: 10, bytes: 1024 * 1024 });
+        assert_eq!(config.tls, None);
+        config.validate().unwrap();
+
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert_eq!(Config::parse(include_str!("../../rod.example.toml")).unwrap(), Config::default());
+        let e = Config::parse("[server]\nport = 80").unwrap_err();
+        assert!(e.contains("unknown field `port`"), "{}", e);
+        assert!(Config::parse("[limits]\nmessages = \"many\"").is_err());
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let env: HashMap<&str, &str> = [
+            ("PORT", "8080"),
+            ("ROD_STORAGE_BACKEND", "memory"),
+            ("ROD_MESH_PEERS", "ws://a/gun, ws://b/gun"),
+            ("ROD_MESH_WEBRTC", "true"),
+            ("ROD_LIMITS_STRIKES", "3"),
+            ("ROD_SERVER_DEBUG", "true"),
+            ("ROD_SERVER_TRUST_FORWARDED", "true"),
+        ].iter().copied().collect();
+        let mut config = Config::default();
+        config.apply_env(|name| env.get(name).map(|val| val.to_string())).unwrap();
+        assert_eq!(config.server.bind, "0.0.0.0:8080");
+        assert_eq!(config.storage.backend, "memory");
+        assert_eq!(config.mesh.peers, vec!["ws://a/gun".to_string(), "ws://b/gun".to_string()]);
+        assert!(config.mesh.webrtc);
+        assert_eq!(config.limits.strikes, 3);
+        assert!(config.server.debug && config.server.trust_forwarded);
+
+        let e = config.apply_env(|name| Some("lots").filter(|_| name == "ROD_SERVER_QUEUE").map(String::from)).unwrap_err();
+        assert!(e.starts_with("ROD_SERVER_QUEUE: invalid value 'lots'"), "{}", e);
+    }
+
+    #[test]
+    fn test_validate() {
+        let invalid = |toml: &str| Config::parse(toml).unwrap().validate().unwrap_err();
+        assert!(invalid("[server]\nbind = \"localhost\"").starts_with("server.bind"));
+        assert!(invalid("[server]\ngun_path = \"a/gun\"").starts_with("server.gun_path"));
+        assert!(invalid("[server]\nqueue = 0").starts_with("server.queue"));
+        assert!(invalid("[storage]\nbackend = \"floppy\"").starts_with("storage.backend"));
+        assert!(invalid("[mesh]\npeers = [\"http://a/gun\"]").starts_with("mesh.peers"));
+        assert!(invalid("[tls]\ncert = \"nowhere.pem\"\nkey = \"nowhere.key\"").starts_with("tls.cert"));
+    }
+}
@@ -1,76 +1,625 @@
-/// Currently not in use!
-
-use std::convert::TryInto;
-use crate::dup::Dup;
-use crate::obj::{Object, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, RwLock};
+use tokio::sync::mpsc::error::TrySendError;
+use crate::dup::LOCAL;
 use crate::gun::gun::Gun;
-use crate::message::{Message, Key};
+use crate::message::{Get, Msg, MessageError, Put};
+use crate::metrics::METRICS;
+use crate::node::Node;
+use crate::obj::gen_random;
+use crate::subscriptions::Subscriptions;
+
+/// How many frames may wait to be written to a peer before we give up
+/// on it as too slow, unless the Dam is told otherwise.
+pub const QUEUE: usize = 1024;
+
+/// Peer is anyone we exchange messages with, whatever the transport.
+/// Its transport drains the channel and writes each raw frame out.
+pub struct Peer {
+    sender: mpsc::Sender<String>,
+    /// The peer's mesh id, learned from its DAM handshake.
+    pub pid: Option<String>,
+    /// Whether the peer is another relay or a LAN peer, which gets sent
+    /// every put as it keeps the graph or serves peers of its own.
+    pub relay: bool,
+}
+
+impl Peer {
+    pub fn new(sender: mpsc::Sender<String>) -> Self {
+        Peer { sender, pid: None, relay: false }
+    }
+
+    /// A peer that is another relay, or a peer found on the LAN.
+    pub fn relay(sender: mpsc::Sender<String>) -> Self {
+        Peer { relay: true, ..Peer::new(sender) }
+    }
+
+    /// Queue a raw frame for the peer. Fails if its transport is gone, or
+    /// if it hasn't kept up and its queue is full.
+    pub fn send(&self, raw: String) -> Result<(), String> {
+        self.sender.try_send(raw).map_err(|e| {
+            let (reason, e) = match e {
+                TrySendError::Full(_) => ("full", "peer is too slow"),
+                TrySendError::Closed(_) => ("closed", "peer is gone"),
+            };
+            METRICS.dropped_sends.with_label_values(&[reason]).inc();
+            e.to_string()
+        })
+    }
+
+    /// How many frames are waiting to be written to the peer.
+    pub fn queued(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+}
+
+/// Our state of currently connected peers, keyed by a transport-given id.
+pub type Peers = Arc<RwLock<HashMap<String, Peer>>>;
+
+/// Picks, by its value under a key, a message not to relay.
+pub type Hush = fn(&serde_json::Value) -> bool;
 
 // Daisy-chain Ad-hoc Mesh-networking
-struct Dam;
+//
+// Every message we hear is handed to Gun, then relayed to every other
+// peer once. Dup drops the echoes, and `><` tells the next hop which
+// peers were already sent the message so that relays can be chained
+// without loops.
+pub struct Dam {
+    pub gun: Gun,
+    pub peers: Peers,
+    pub subs: Subscriptions,
+    pid: String,
+    queue: usize,
+    taps: std::sync::Mutex<Vec<(String, mpsc::Sender<Msg>)>>,
+    // Which messages carrying a key are heard but not relayed.
+    hushed: std::sync::Mutex<Vec<(String, Hush)>>,
+    // Our own gets still waiting for an answer, by message id.
+    asks: std::sync::Mutex<HashMap<String, oneshot::Sender<Msg>>>,
+}
+
 impl Dam {
-    fn hear(msg: Object, _peer: &mut Object, gun: &Gun) -> Result<(), String> {
-        if let Some(id_val) = msg.get(Key::MessageId.to_string()) {
-            if let Ok(id_string) = id_val.try_into() {
-                if Dup::check(&gun.dups, id_string) {
-                    return Ok(());
-                }
+    pub fn new(gun: Gun) -> Self {
+        Dam {
+            gun,
+            peers: Peers::default(),
+            subs: Subscriptions::new(),
+            pid: gen_random(9),
+            queue: QUEUE,
+            taps: Default::default(),
+            hushed: Default::default(),
+            asks: Default::default(),
+        }
+    }
+
+    /// A Dam whose peers may have up to `queue` frames waiting to be
+    /// written before they are dropped.
+    pub fn with_queue(gun: Gun, queue: usize) -> Self {
+        Dam { queue, ..Dam::new(gun) }
+    }
+
+    /// How many frames a transport should let wait for each peer.
+    pub fn queue(&self) -> usize {
+        self.queue
+    }
+
+    /// Hear every new message that carries `key`, for extensions to the
+    /// protocol like WebRTC signaling. They are relayed all the same unless
+    /// hushed. A tap that falls a queue behind misses messages until it
+    /// catches up.
+    pub fn tap(&self, key: &str) -> mpsc::Receiver<Msg> {
+        let (tx, rx) = mpsc::channel(self.queue);
+        self.taps.lock().unwrap().push((key.to_string(), tx));
+        rx
+    }
+
+    /// Stop relaying the messages whose `key` is one `which` picks. They
+    /// still reach the taps on `key`.
+    pub fn hush(&self, key: &str, which: Hush) {
+        self.hushed.lock().unwrap().push((key.to_string(), which));
+    }
+
+    /// Write to the graph ourselves, and send the write to our peers.
+    pub async fn put(&self, put: Put) -> Result<(), String> {
+        let msg = Msg { put: Some(put), ..Msg::new() };
+        self.gun.inbound(&msg).await?;
+        self.say(&msg).await;
+        Ok(())
+    }
+
+    /// Ask our peers for a node, and wait up to `wait` for the first of
+    /// them to answer. What they know is merged into what we had.
+    pub async fn fetch(&self, soul: &str, wait: Duration) -> Result<Option<Node>, String> {
+        if !self.peers.read().await.is_empty() {
+            let msg = Msg { get: Some(Get { soul: soul.to_string(), key: None }), ..Msg::new() };
+            let id = msg.id.clone().unwrap_or_default();
+            let (tx, rx) = oneshot::channel();
+            self.asks.lock().unwrap().insert(id.clone(), tx);
+            self.say(&msg).await;
+            let _ = tokio::time::timeout(wait, rx).await;
+            self.asks.lock().unwrap().remove(&id);
+        }
+        self.gun.node(soul).await
+    }
+
+    /// Our own mesh id.
+    pub fn pid(&self) -> &str {
+        &self.pid
+    }
+
+    /// Start talking to a peer, introducing ourselves.
+    pub async fn connect(&self, id: &str, peer: Peer) {
+        {
+            let mut peers = self.peers.write().await;
+            // A peer taking over an id doesn't get what the last one asked for.
+            self.subs.forget(id);
+            if peer.relay {
+                self.subs.relay(id);
             }
+            peers.insert(id.to_string(), peer);
         }
+        self.say_to(id, &self.hi()).await;
+    }
 
-        let ack_id = Key::AckId.to_string();
-        let message_id = Key::MessageId.to_string();
-        let content_id = Key::ContentHash.to_string();
+    pub async fn disconnect(&self, id: &str) {
+        let mut peers = self.peers.write().await;
+        peers.remove(id);
+        self.subs.forget(id);
+    }
 
-        // FIXME: Review this logic!
-        if let (Some(ack), Some(hash)) = 
-            (msg.get(ack_id.clone()), msg.get(content_id.clone()))
-            {
-                match (ack, hash) {
-                (Value::Link(obj), Value::Text(hash_str)) => {
-                    if Dup::check(&obj, hash_str.to_string()) {
-                        return Ok(());
-                    }
-                }
-                _ => {}
+    /// Hear a raw frame from a peer. Frames that aren't GUN messages are
+    /// answered with an `err`.
+    pub async fn hear(&self, raw: &str, peer: &str) {
+        self.hear_parsed(Msg::parse(raw), raw.len(), peer).await;
+    }
+
+    /// Hear the messages of a frame of `bytes` already parsed by the transport.
+    pub async fn hear_parsed(&self, items: Vec<Result<Msg, MessageError>>, bytes: usize, peer: &str) {
+        METRICS.bytes_in.inc_by(bytes as u64);
+        for item in items {
+            let result = match item {
+                Ok(msg) => self.hear_one(msg, peer).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                self.reject(e, peer).await;
             }
         }
-        
-        let v = msg.get(message_id.clone()).unwrap();
-        match v {
-            Value::Text(s) => {
-                Dup::track(&gun.dups, s.clone());
+    }
+
+    /// Answer a peer's bad frame with an `err`.
+    pub async fn reject(&self, e: MessageError, peer: &str) {
+        // One line per bad frame is only for debugging; a peer may send many.
+        log::debug!("peer {} sent a bad message: {}", peer, e);
+        self.say_to(peer, &e.reply()).await;
+    }
+
+    pub async fn hear_one(&self, mut msg: Msg, peer: &str) -> Result<(), MessageError> {
+        METRICS.heard(&msg);
+        let id = msg.id.clone().unwrap_or_default();
+        if msg.dam.is_some() {
+            self.hear_dam(&msg, peer).await;
+            return Ok(());
+        }
+
+        if self.gun.dups.check(&id) {
+            METRICS.dedup_hits.inc();
+            return Ok(());
+        }
+
+        // An ack carries the hash of what it answers, so the same answer
+        // coming back through another peer is heard only once.
+        if let (Some(ack), Some(hash)) = (&msg.ack, &msg.hash) {
+            let ash = format!("{}{}", ack, hash);
+            if self.gun.dups.check(&ash) {
+                METRICS.dedup_hits.inc();
                 return Ok(());
             }
-            _ => {}
+            self.gun.dups.track(&ash);
         }
 
-        // peers already set to, we will not want to relay again to them.
-        // We're not using this?
-        let _near = msg.get(Key::Peers.to_string());
+        self.gun.dups.track_via(&id, Some(peer));
+        msg.via = Some(peer.to_string());
 
+        if let Err(reason) = self.gun.inbound(&msg).await {
+            return Err(MessageError::Invalid { id: msg.id.clone(), reason });
+        }
 
-        gun.inbound(&msg);
+        let ours = msg.ack.as_ref().and_then(|ack| self.asks.lock().unwrap().remove(ack));
+        if let Some(tx) = ours {
+            let _ = tx.send(msg);
+            return Ok(());
+        }
 
+        if let Some(get) = &msg.get {
+            // Under the peers lock, so that a peer leaving meanwhile is
+            // not subscribed after it's forgotten.
+            let peers = self.peers.read().await;
+            let subscribed = if peers.contains_key(peer) { self.subs.subscribe(peer, get) } else { Ok(()) };
+            drop(peers);
+            if let Err(reason) = subscribed {
+                return Err(MessageError::Invalid { id: msg.id.clone(), reason });
+            }
+            match self.gun.get(&msg).await {
+                Ok(Some(reply)) => self.say(&reply).await,
+                Ok(None) => {}
+                Err(reason) => return Err(MessageError::Invalid { id: msg.id.clone(), reason }),
+            }
+        }
+        if msg.put.is_some() && msg.ack.is_none() {
+            let ok = Msg { ok: Some(1.into()), ..msg.reply() };
+            self.say(&ok).await;
+        }
+        self.taps.lock().unwrap().retain(|(key, tx)| {
+            if !msg.other.contains_key(key) {
+                return true;
+            }
+            match tx.try_send(msg.clone()) {
+                Err(TrySendError::Full(_)) => {
+                    METRICS.dropped_sends.with_label_values(&["tap"]).inc();
+                    true
+                }
+                sent => sent.is_ok(),
+            }
+        });
+
+        let hushed = self.hushed.lock().unwrap().iter()
+            .any(|(key, which)| msg.other.get(key).is_some_and(which));
+        if !hushed {
+            self.say(&msg).await;
+        }
+        Ok(())
+    }
 
-        if let Some(id_val) = msg.get(message_id) {
-            if let Ok(id_str) = id_val.try_into() {
-                Dup::track(&gun.dups, id_str);
+    /// Relay a message through the mesh. Answers go back to whoever asked,
+    /// or nowhere if that was us or is forgotten; everything else goes to
+    /// every peer except the one it came from and those the sender says
+    /// already have it. Puts only go to the peers subscribed to them.
+    pub async fn say(&self, msg: &Msg) {
+        if let Some(id) = &msg.id {
+            if !self.gun.dups.check(id) {
+                self.gun.dups.track_via(id, Some(LOCAL));
+            }
+        }
 
-                // Where did `ash` come from?
-                // Dup::track(&gun.dups, ash);
+        if let Some(ack) = &msg.ack {
+            match self.gun.dups.via(ack) {
+                Some(to) if to != LOCAL && msg.via.as_ref() != Some(&to) => self.say_to(&to, msg).await,
+                _ => {}
             }
+            return;
         }
 
-        Ok(())
+        let near: HashSet<&str> = msg.peers.as_deref()
+            .map(|near| near.split(',').filter(|pid| !pid.is_empty()).collect())
+            .unwrap_or_default();
+        let peers = self.peers.read().await;
+        let interested = match &msg.put {
+            Some(put) if msg.ack.is_none() => Some(self.subs.interested(put)),
+            _ => None,
+        };
+        let to: Vec<(&String, &Peer)> = match &interested {
+            Some(ids) => ids.iter().filter_map(|id| peers.get_key_value(id)).collect(),
+            None => peers.iter().collect(),
+        };
+        let to: Vec<(&String, &Peer)> = to.into_iter()
+            .filter(|(id, _)| msg.via.as_ref() != Some(*id))
+            .filter(|(_, p)| p.pid.as_deref().is_none_or(|pid| !near.contains(pid)))
+            .collect();
+        if msg.put.is_some() && msg.ack.is_none() {
+            METRICS.fanout.observe(to.len() as f64);
+        }
+        if to.is_empty() {
+            return;
+        }
+
+        let mut yo: Vec<&str> = near.iter().copied().collect();
+        yo.push(&self.pid);
+        yo.extend(to.iter().filter_map(|(_, p)| p.pid.as_deref()));
+        let raw = Msg { peers: Some(yo.join(",")), ..msg.clone() }.to_string();
+        METRICS.sent(msg, raw.len(), to.len());
+        let failed: Vec<(String, String)> = to.into_iter()
+            .filter_map(|(id, p)| p.send(raw.clone()).err().map(|e| (id.clone(), e)))
+            .collect();
+        drop(peers);
+        self.drop_peers(failed).await;
     }
 
-    // One can only borrow as mutable once, so not sure if this is legit or necessary.
-    fn hear_one(_msg: Box<dyn Message>, _peer: &mut Object) {
-        unimplemented!("Dam::hear");
+    /// Send a message to one peer only.
+    pub async fn say_to(&self, peer: &str, msg: &Msg) {
+        let failed = match self.peers.read().await.get(peer) {
+            Some(p) => {
+                let raw = msg.to_string();
+                METRICS.sent(msg, raw.len(), 1);
+                p.send(raw).err()
+            }
+            None => None,
+        };
+        if let Some(e) = failed {
+            self.drop_peers(vec![(peer.to_string(), e)]).await;
+        }
     }
 
-    fn say(_msg: &mut Object, _peer: &mut Object) {
-        unimplemented!("Dam::say");
+    // Peers we can't send to are dropped at once rather than buffered for.
+    // Their transports see the channel close and hang up.
+    async fn drop_peers(&self, failed: Vec<(String, String)>) {
+        if failed.is_empty() {
+            return;
+        }
+        let mut peers = self.peers.write().await;
+        for (id, e) in failed {
+            eprintln!("dropping peer {}: {}", id, e);
+            peers.remove(&id);
+            self.subs.forget(&id);
+        }
+    }
+
+    /// The DAM handshake introducing us to a peer.
+    pub fn hi(&self) -> Msg {
+        let mut hi = Msg { dam: Some("?".to_string()), ..Msg::new() };
+        hi.other.insert("pid".to_string(), self.pid.clone().into());
+        hi
+    }
+
+    // Peers introduce themselves with `{"dam": "?", "pid": ...}`; we learn
+    // their pid and answer with ours unless they are already answering us.
+    async fn hear_dam(&self, msg: &Msg, peer: &str) {
+        if msg.dam.as_deref() != Some("?") {
+            return;
+        }
+        let pid = msg.other.get("pid").and_then(|pid| pid.as_str());
+        if let (Some(pid), Some(p)) = (pid, self.peers.write().await.get_mut(peer)) {
+            p.pid = Some(pid.to_string());
+        }
+        if msg.ack.is_none() {
+            let hi = Msg { ack: msg.id.clone(), ..self.hi() };
+            self.say_to(peer, &hi).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use tokio::sync::mpsc::Receiver;
+    use crate::obj::Value;
+
+    async fn peer(dam: &Dam, id: &str, pid: &str) -> Receiver<String> {
+        let (tx, mut rx) = mpsc::channel(dam.queue());
+        dam.connect(id, Peer::new(tx)).await;
+        let hi = format!(r##"{{"#":"hi{}","dam":"?","pid":"{}","@":"x"}}"##, id, pid);
+        dam.hear(&hi, id).await;
+        rx.recv().await.unwrap();
+        rx
+    }
+
+    fn next(rx: &mut Receiver<String>) -> Option<Msg> {
+        rx.try_recv().ok().map(|raw| Msg::parse(&raw).pop().unwrap().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_relay_skips_origin_and_near() {
+        let dam = Dam::new(Gun::new());
+        let mut a = peer(&dam, "a", "pa").await;
+        let mut b = peer(&dam, "b", "pb").await;
+        let mut c = peer(&dam, "c", "pc").await;
+
+        dam.hear(r##"{"#":"g1","get":{"#":"mark"},"><":"pc"}"##, "a").await;
+        assert!(next(&mut a).is_none());
+        assert!(next(&mut c).is_none());
+        let relayed = next(&mut b).unwrap();
+        assert_eq!(relayed.id, Some("g1".to_string()));
+        let near: HashSet<&str> = relayed.peers.as_deref().unwrap().split(',').collect();
+        assert!(near.contains("pb") && near.contains("pc") && near.contains(dam.pid()));
+
+        // The same message coming back through another peer is dropped.
+        dam.hear(r##"{"#":"g1","get":{"#":"mark"}}"##, "c").await;
+        assert!(next(&mut a).is_none());
+        assert!(next(&mut b).is_none());
+
+        // The answer goes only to the peer who asked.
+        dam.hear(r##"{"#":"r1","@":"g1","ok":1}"##, "b").await;
+        assert_eq!(next(&mut a).unwrap().ack, Some("g1".to_string()));
+        assert!(next(&mut c).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_acks_to_us_are_not_relayed() {
+        let dam = Dam::new(Gun::new());
+        let mut a = peer(&dam, "a", "pa").await;
+        let mut b = peer(&dam, "b", "pb").await;
+        dam.hear(r##"{"#":"g1","get":{"#":"mark"}}"##, "a").await;
+        dam.hear(r##"{"#":"g2","get":{"#":"mark"}}"##, "b").await;
+        for rx in [&mut a, &mut b].iter_mut() {
+            while next(rx).is_some() {}
+        }
+
+        let put = Msg::parse(r##"{"#":"x","put":{"mark":{"_":{"#":"mark",">":{"name":1}},"name":"Mark"}}}"##).pop().unwrap().unwrap();
+        dam.put(put.put.unwrap()).await.unwrap();
+        let id = next(&mut a).unwrap().id.unwrap();
+        next(&mut b).unwrap();
+        dam.hear(&format!(r##"{{"#":"o1","@":"{}","ok":1}}"##, id), "a").await;
+        assert!(next(&mut b).is_none());
+
+        // Nor are answers to something long forgotten.
+        dam.hear(r##"{"#":"o2","@":"gone","ok":1}"##, "a").await;
+        assert!(next(&mut b).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_is_answered_from_store() {
+        let dam = Dam::new(Gun::new());
+        let mut a = peer(&dam, "a", "pa").await;
+        dam.hear(r##"{"#":"p1","put":{"mark":{"_":{"#":"mark",">":{"name":1,"age":1}},"name":"Mark","age":30}}}"##, "a").await;
+        let ok = next(&mut a).unwrap();
+        assert_eq!((ok.ack, ok.ok), (Some("p1".to_string()), Some(1.into())));
+
+        let mut b = peer(&dam, "b", "pb").await;
+        dam.hear(r##"{"#":"g1","get":{"#":"mark",".":"name"}}"##, "b").await;
+        let reply = next(&mut b).unwrap();
+        assert_eq!(reply.ack, Some("g1".to_string()));
+        assert!(reply.hash.is_some());
+        let node = &reply.put.unwrap()["mark"];
+        assert_eq!(node.get("name"), Some(&Value::Text("Mark".to_string())));
+        assert_eq!(node.get("age"), None);
+
+        dam.hear(r##"{"#":"g2","get":{"#":"amber"}}"##, "b").await;
+        assert!(next(&mut b).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lex_subscription() {
+        let dam = Dam::new(Gun::new());
+        let mut a = peer(&dam, "a", "pa").await;
+        let mut b = peer(&dam, "b", "pb").await;
+        dam.hear(r##"{"#":"g1","get":{"#":"posts",".":{"*":"2021-08"}}}"##, "b").await;
+        next(&mut a).unwrap();
+
+        dam.hear(r##"{"#":"p1","put":{"posts":{"_":{"#":"posts",">":{"2021-07-31":1}},"2021-07-31":"old"}}}"##, "a").await;
+        next(&mut a).unwrap();
+        assert!(next(&mut b).is_none());
+
+        dam.hear(r##"{"#":"p2","put":{"posts":{"_":{"#":"posts",">":{"2021-08-01":1}},"2021-08-01":"new"}}}"##, "a").await;
+        assert_eq!(next(&mut b).unwrap().id, Some("p2".to_string()));
+
+        dam.hear(r##"{"#":"g2","get":{"#":"posts",".":{"*":"2021","-":1,"%":1}}}"##, "b").await;
+        let reply = next(&mut b).unwrap();
+        let keys: Vec<String> = reply.put.unwrap()["posts"].iter().map(|(key, _)| key.to_string()).collect();
+        assert_eq!(keys, vec!["2021-08-01".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_match_souls_exactly() {
+        let dam = Dam::new(Gun::new());
+        let mut a = peer(&dam, "a", "pa").await;
+        let mut b = peer(&dam, "b", "pb").await;
+        let mut c = peer(&dam, "c", "pc").await;
+        dam.hear(r##"{"#":"g1","get":{"#":"a"}}"##, "b").await;
+        dam.hear(r##"{"#":"g2","get":{"#":"ba"}}"##, "c").await;
+        for rx in [&mut a, &mut b, &mut c].iter_mut() {
+            while next(rx).is_some() {}
+        }
+
+        dam.hear(r##"{"#":"p1","put":{"a":{"_":{"#":"a",">":{"x":1}},"x":1}}}"##, "a").await;
+        assert_eq!(next(&mut b).unwrap().id, Some("p1".to_string()));
+        assert!(next(&mut c).is_none());
+
+        dam.disconnect("b").await;
+        assert_eq!(dam.subs.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_slow_peer_is_dropped() {
+        let dam = Dam::with_queue(Gun::new(), 2);
+        let mut a = peer(&dam, "a", "pa").await;
+        let _b = peer(&dam, "b", "pb").await;
+        for i in 0..3 {
+            dam.hear(&format!(r##"{{"#":"g{}","get":{{"#":"mark"}}}}"##, i), "a").await;
+        }
+        assert!(next(&mut a).is_none());
+        let peers = dam.peers.read().await;
+        assert!(peers.contains_key("a"));
+        assert!(!peers.contains_key("b"));
+    }
+
+    #[tokio::test]
+    async fn test_slow_tap_misses_messages() {
+        let dam = Dam::with_queue(Gun::new(), 2);
+        let mut tap = dam.tap("rtc");
+        for i in 0..3 {
+            dam.hear(&format!(r##"{{"#":"r{}","rtc":{{"id":"x"}}}}"##, i), "a").await;
+        }
+        assert_eq!(tap.recv().await.unwrap().id, Some("r0".to_string()));
+        assert_eq!(tap.recv().await.unwrap().id, Some("r1".to_string()));
+        dam.hear(r##"{"#":"r3","rtc":{"id":"x"}}"##, "a").await;
+        assert_eq!(tap.recv().await.unwrap().id, Some("r3".to_string()));
+
+        drop(tap);
+        dam.hear(r##"{"#":"r4","rtc":{"id":"x"}}"##, "a").await;
+        assert!(dam.taps.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_hushed_messages_are_not_relayed() {
+        let dam = Dam::new(Gun::new());
+        let _a = peer(&dam, "a", "pa").await;
+        let mut b = peer(&dam, "b", "pb").await;
+        let mut tap = dam.tap("rtc");
+        dam.hush("rtc", |rtc| rtc.get("to").is_none());
+        dam.hear(r##"{"#":"r1","rtc":{"id":"x"}}"##, "a").await;
+        assert_eq!(tap.recv().await.unwrap().id, Some("r1".to_string()));
+        assert!(next(&mut b).is_none());
+        dam.hear(r##"{"#":"r2","rtc":{"id":"x","to":"y"}}"##, "a").await;
+        assert_eq!(next(&mut b).unwrap().id, Some("r2".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_gone_peer_is_dropped() {
+        let dam = Dam::new(Gun::new());
+        let _a = peer(&dam, "a", "pa").await;
+        drop(peer(&dam, "b", "pb").await);
+        dam.hear(r##"{"#":"g1","get":{"#":"mark"}}"##, "a").await;
+        assert!(!dam.peers.read().await.contains_key("b"));
+    }
+
+    #[tokio::test]
+    async fn test_bad_frame_gets_err() {
+        let dam = Dam::new(Gun::new());
+        let mut a = peer(&dam, "a", "pa").await;
+        dam.hear("{oops", "a").await;
+        assert!(next(&mut a).unwrap().err.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_user_space_needs_signatures() {
+        let dam = Dam::new(Gun::new());
+        let mut a = peer(&dam, "a", "pa").await;
+        let mut b = peer(&dam, "b", "pb").await;
+        let soul = "~9_ou4yalMYSOWZzaDXwr1qWqPSYg0kLd4SGpgaD14lY.h34EgZAOX33fDgY75pzqUpPhYSyyFo1VjdBdlUzvqFU";
+        dam.hear(&format!(r##"{{"#":"g1","get":{{"#":"{}"}}}}"##, soul), "b").await;
+        next(&mut a).unwrap();
+
+        dam.hear(&format!(r##"{{"#":"p1","put":{{"{0}":{{"_":{{"#":"{0}",">":{{"name":1}}}},"name":"Mallory"}}}}}}"##, soul), "a").await;
+        let err = next(&mut a).unwrap();
+        assert_eq!(err.ack, Some("p1".to_string()));
+        assert!(err.err.is_some());
+        assert!(next(&mut b).is_none());
+        assert!(dam.gun.node(soul).await.unwrap().is_none());
+
+        let signed = r#"{\":\":\"Mark\",\"~\":\"W6Zx8yYR3wdFLZ+5EZGxNQ/jtxGZAbjk+x2H505Zd+OdgJNylgY5dHiDbueOv767PsdQbQh+0hT3yrr2JnQdIg==\"}"#;
+        dam.hear(&format!(r##"{{"#":"p2","put":{{"{0}":{{"_":{{"#":"{0}",">":{{"name":1634000000000.5}}}},"name":"{1}"}}}}}}"##, soul, signed), "a").await;
+        assert!(next(&mut a).unwrap().ok.is_some());
+        assert_eq!(next(&mut b).unwrap().id, Some("p2".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_content_addressed_puts() {
+        let dam = Dam::new(Gun::new());
+        let mut a = peer(&dam, "a", "pa").await;
+        let hello = "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+        let one = "a4ayc/80/OGda4BO/1o/V0etpOqiLx1JwB5S3beHW0s=";
+        let put = |id: &str, key: &str, val: &str, state: u32| format!(
+            r##"{{"#":"{0}","put":{{"#":{{"_":{{"#":"#",">":{{"{1}":{3}}}}},"{1}":{2}}}}}}}"##, id, key, val, state
+        );
+
+        dam.hear(&put("p1", hello, r#""hello""#, 1), "a").await;
+        assert!(next(&mut a).unwrap().ok.is_some());
+        dam.hear(&put("p2", hello, r#""hullo""#, 2), "a").await;
+        assert!(next(&mut a).unwrap().err.is_some());
+
+        // The same content again changes nothing, not even its state.
+        dam.hear(&put("p3", hello, r#""hello""#, 3), "a").await;
+        assert!(next(&mut a).unwrap().ok.is_some());
+        assert_eq!(dam.gun.node("#").await.unwrap().unwrap().state(hello), Some(1.0));
+
+        // "1" and 1 hash the same, but what was written stays.
+        dam.hear(&put("p4", one, "1", 1), "a").await;
+        next(&mut a).unwrap();
+        dam.hear(&put("p5", one, r#""1""#, 2), "a").await;
+        assert!(next(&mut a).unwrap().err.is_some());
+        assert_eq!(dam.gun.node("#").await.unwrap().unwrap().get(one), Some(&Value::Number(1.0)));
     }
-}
\ No newline at end of file
+}
@@ -1,17 +1,100 @@
-use crate::obj::{Object, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-pub struct Dup;
+/// How long a message id is remembered, as in gun.js.
+pub const DUP_AGE: Duration = Duration::from_secs(9);
+
+/// How many message ids are remembered at most.
+pub const DUP_MAX: usize = 100_000;
+
+/// What a message of our own is tracked as having come via, so that its
+/// answers are known to be for us.
+pub const LOCAL: &str = "";
+
+/// Dup remembers the ids of the messages we've recently seen, so that
+/// a message echoed back to us through the mesh is not handled twice.
+///
+/// An id is forgotten once it's older than `age`, or once more than
+/// `max` newer ids have been tracked, whichever comes first.
+pub struct Dup {
+    age: Duration,
+    max: usize,
+    s: Mutex<Seen>,
+}
+
+#[derive(Default)]
+struct Seen {
+    ids: HashMap<String, Entry>,
+    // Ids in the order they were tracked. An id tracked again is queued
+    // again; the older record is skipped when it comes up.
+    queue: VecDeque<(String, Instant)>,
+}
+
+struct Entry {
+    at: Instant,
+    via: Option<String>,
+}
 
 impl Dup {
-    pub fn add<'a>(obj: &'a mut Object, key: String, value: Value) {
-        obj.insert(key, value)
+    pub fn new(age: Duration, max: usize) -> Self {
+        Dup { age, max, s: Mutex::new(Seen::default()) }
+    }
+
+    /// Whether the id has been tracked within the last `age`.
+    pub fn check(&self, id: &str) -> bool {
+        let mut seen = self.s.lock().unwrap();
+        self.drop_old(&mut seen, Instant::now());
+        seen.ids.contains_key(id)
+    }
+
+    /// Remember the id, or refresh it if it's already known.
+    pub fn track(&self, id: &str) {
+        self.track_via(id, None);
+    }
+
+    /// Remember the id along with the peer the message came from, so
+    /// that answers to it can be routed back the way it came.
+    pub fn track_via(&self, id: &str, via: Option<&str>) {
+        let now = Instant::now();
+        let mut seen = self.s.lock().unwrap();
+        let entry = Entry { at: now, via: via.map(String::from) };
+        seen.ids.insert(id.to_string(), entry);
+        seen.queue.push_back((id.to_string(), now));
+        self.drop_old(&mut seen, now);
+    }
+
+    /// The peer a tracked message came from.
+    pub fn via(&self, id: &str) -> Option<String> {
+        let mut seen = self.s.lock().unwrap();
+        self.drop_old(&mut seen, Instant::now());
+        seen.ids.get(id).and_then(|entry| entry.via.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.s.lock().unwrap().ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
-    pub fn check(_obj: & Object, _id: String) -> bool {
-        unimplemented!("dup::check")
+    fn drop_old(&self, seen: &mut Seen, now: Instant) {
+        while let Some((id, at)) = seen.queue.front() {
+            let expired = now.duration_since(*at) >= self.age;
+            if !expired && seen.ids.len() <= self.max {
+                break;
+            }
+            if seen.ids.get(id).map(|entry| entry.at) == Some(*at) {
+                seen.ids.remove(id);
+            }
+            seen.queue.pop_front();
+        }
     }
+}
 
-    pub fn track(_obj: &Object, _id: String) {
-        unimplemented!("dup::track")
+impl Default for Dup {
+    fn default() -> Self {
+        Self::new(DUP_AGE, DUP_MAX)
     }
-}
\ No newline at end of file
+}
@@ -18,6 +18,7 @@ pub trait Isomorphic: PartialEq + Eq {
     fn is(&self, other: impl Isomorphic) -> bool;
 }
 
+#[allow(clippy::derived_hash_with_manual_eq)]
 #[derive(Debug, Serialize, Clone, Hash)]
 pub struct Graph<T: Eq + Hash + Clone + Serialize + fmt::Debug> {
     vertices: VertexSet<T>,
@@ -110,7 +111,7 @@ where
 
         for v in self.vertices.into_iter() {
             for e in self.edges.into_iter() {
-                if e.is_incident(&v) {
+                if e.is_incident(v) {
                     vs.insert(v.clone());
                     es.insert(e.clone());
                 }
@@ -147,7 +148,7 @@ where
     }
 
     pub fn is_graph(vertices: VertexSet<T>, edges: EdgeSet<T>) -> bool {
-        if vertices.len() == 0 {
+        if vertices.is_empty() {
             return false;
         }
 
@@ -167,6 +168,7 @@ where
 
 // A vertex set V is a finite non-empty set
 // V = {A, B, C, D}
+#[allow(clippy::derived_hash_with_manual_eq)]
 #[derive(Hash, Clone, Debug, Serialize, Default)]
 pub struct Vertex<T: Eq + Hash + Clone + Serialize + fmt::Debug>(pub T);
 
@@ -231,7 +233,7 @@ where
 {
     fn eq(&self, other: &Set<T>) -> bool {
         let (Set(h1), Set(h2)) = (&self, other);
-        h1.difference(&h2).count() == 0
+        h1.difference(h2).count() == 0
     }
 }
 
@@ -265,6 +267,11 @@ where
         hs.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        let Set(hs) = self;
+        hs.is_empty()
+    }
+
     pub fn is_subset(&self, other: &Set<T>) -> bool {
         let (Set(h1), Set(h2)) = (self, other);
         h1.is_subset(h2)
@@ -289,7 +296,7 @@ where
     type IntoIter = std::collections::hash_set::Iter<'a, T>;
     fn into_iter(self) -> std::collections::hash_set::Iter<'a, T> {
         let Set(hs) = self;
-        hs.into_iter()
+        hs.iter()
     }
 }
 
@@ -479,9 +486,9 @@ pub mod tests {
 
         let g = GraphBuilder::<i32>::new()
             .add_vertexset(Set::<Vertex<i32>>::from_iter(
-                vec![v1__, v2__, v3__, v4, v5, v6].iter())
+                [v1__, v2__, v3__, v4, v5, v6].iter())
             )
-            .add_edgeset(EdgeSet::from_iter(vec![e1, e2, e3].iter()))
+            .add_edgeset(EdgeSet::from_iter([e1, e2, e3].iter()))
             .build()?;
 
         println!("{:#?}", g);
@@ -1,17 +1,273 @@
-use crate::obj::Object;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::adapters::StorageAdapter;
+use crate::dup::Dup;
+use crate::ham::{self, State};
+use crate::message::{self, Msg};
+use crate::node::Node;
+use crate::obj::Value;
+use crate::sea;
+use crate::store::Store;
 
-pub struct Gun<'a> {
-    pub dups: &'a Object
+/// How many writes from the future are held at most. Past that, more
+/// of them are refused, and the oldest of those failing to be retried are
+/// given up on.
+pub const MAX_DEFERRED: usize = 10_000;
+
+pub struct Gun {
+    pub dups: Dup,
+    store: Arc<Store>,
+    deferred: Later,
+}
+
+// Writes with a state ahead of our clock, held until it catches up. A
+// timer is set for the earliest of them, so they land even if nothing
+// else comes in meanwhile.
+#[derive(Default)]
+struct Deferred {
+    // Each node with the earliest state it holds.
+    nodes: Vec<(State, Node)>,
+    // When the soonest timer set goes off, if one is.
+    timer: Option<State>,
 }
 
-impl<'a> Gun<'_> {
-    // One can only borrow as mutable once, so not sure if this is legit or necessary.
-    pub fn get(_msg: Object, _peer: &mut Object) {
-        unimplemented!("Gun::get");
+type Later = Arc<Mutex<Deferred>>;
+
+impl Gun {
+    pub fn new() -> Self {
+        Gun {
+            dups: Dup::default(),
+            store: Arc::new(Store::default()),
+            deferred: Later::default(),
+        }
+    }
+
+    /// Keep the graph in the given storage instead of in memory.
+    pub fn with_storage(adapter: Arc<dyn StorageAdapter>) -> Self {
+        Gun { store: Arc::new(Store::new(adapter)), ..Gun::new() }
+    }
+
+    /// Answer a `get` from the store with a `put` pointing back at it,
+    /// narrowed to the key or lexical range under `.` if there is one.
+    /// There is no answer when we know nothing about what was asked.
+    pub async fn get(&self, msg: &Msg) -> Result<Option<Msg>, String> {
+        let get = match &msg.get {
+            Some(get) => get,
+            None => return Ok(None),
+        };
+        let node = match self.store.query(&get.soul, get.key.as_ref()).await? {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+
+        let mut put = BTreeMap::new();
+        put.insert(get.soul.to_string(), node);
+        let hash = serde_json::to_string(&put).ok().map(|raw| message::hash(&raw));
+        Ok(Some(Msg { put: Some(put), hash, ..msg.reply() }))
+    }
+
+    /// Get a copy of the node we currently hold for a soul.
+    pub async fn node(&self, soul: &str) -> Result<Option<Node>, String> {
+        self.store.get(soul).await
     }
 
     // TODO: "in" is a reserve word in Rust.
-    pub fn inbound(&self, _msg: &Object) {
-        unimplemented!("Gun.in");
+    /// Merge the `put` graph of an incoming message into ours through HAM.
+    /// Every node is checked before any is written, but then each is
+    /// written on its own: if storage fails partway, the nodes written
+    /// stay written, and the error names the souls that weren't.
+    pub async fn inbound(&self, msg: &Msg) -> Result<(), String> {
+        if let Some(put) = &msg.put {
+            for blacklist in sea::check(put)? {
+                if self.blacklisted(&blacklist).await? {
+                    return Err("Certificant blacklisted.".to_string());
+                }
+            }
+        }
+        let machine = ham::state();
+        self.flush_deferred(machine).await?;
+
+        let mut nodes = Vec::new();
+        for node in msg.put.iter().flat_map(|put| put.values()) {
+            if sea::is_hashed(node.soul()) {
+                nodes.push(Cow::Owned(self.unwritten(node).await?));
+            } else {
+                nodes.push(Cow::Borrowed(node));
+            }
+        }
+
+        let mut failed = Vec::new();
+        let mut error = None;
+        for node in nodes.iter() {
+            if let Err(e) = self.put(node, machine).await {
+                failed.push(node.soul());
+                error.get_or_insert(e);
+            }
+        }
+        match error {
+            None => Ok(()),
+            Some(e) if failed.len() == nodes.len() => Err(e),
+            Some(e) => Err(format!("{} Not written: {}.", e, failed.join(", "))),
+        }
+    }
+
+    /// Whether the writer is on a blacklist a certificate named.
+    async fn blacklisted(&self, blacklist: &sea::Blacklist) -> Result<bool, String> {
+        let field = |node: Option<Node>, key: &str| node.and_then(|node| node.get(key).map(sea::unpack));
+        let mut soul = blacklist.soul.clone();
+        if let Some(via) = &blacklist.via {
+            match field(self.store.get(&soul).await?, via) {
+                Some(Value::Link(list)) => soul = list.get_id(),
+                _ => return Ok(false),
+            }
+        }
+        Ok(match field(self.store.get(&soul).await?, &blacklist.writer) {
+            Some(Value::Bit(listed)) => listed,
+            Some(Value::Number(listed)) => listed == 1.0,
+            _ => false,
+        })
+    }
+
+    /// The fields of a content-addressed node we don't have yet. Those we
+    /// have are never written again, and can't be changed.
+    async fn unwritten(&self, node: &Node) -> Result<Node, String> {
+        let current = match self.store.get(node.soul()).await? {
+            Some(current) => current,
+            None => return Ok(node.clone()),
+        };
+        let mut unwritten = Node::new(node.soul());
+        for (key, (val, state)) in node.iter() {
+            match current.get(key) {
+                Some(had) if had == val => {}
+                Some(_) => return Err("Content cannot be changed.".to_string()),
+                None => unwritten.insert(key.to_string(), val.clone(), *state),
+            }
+        }
+        Ok(unwritten)
+    }
+
+    /// Retry the deferred writes whose state the machine has caught up with.
+    pub async fn flush_deferred(&self, machine: State) -> Result<(), String> {
+        flush_deferred(&self.store, &self.deferred, machine).await
+    }
+
+    async fn put(&self, node: &Node, machine: State) -> Result<(), String> {
+        put(&self.store, &self.deferred, node, machine).await
+    }
+
+    /// Write out anything still waiting to be stored.
+    pub async fn flush(&self) -> Result<(), String> {
+        self.store.flush().await
     }
-}
\ No newline at end of file
+}
+
+async fn put(store: &Arc<Store>, later: &Later, node: &Node, machine: State) -> Result<(), String> {
+    let mix = store.merge(node, machine).await?;
+    if mix.defer.is_empty() {
+        return Ok(());
+    }
+    let mut deferred = later.lock().unwrap();
+    if deferred.nodes.len() >= MAX_DEFERRED {
+        return Err("Too many writes from the future.".to_string());
+    }
+    let at = earliest(&mix.defer);
+    deferred.nodes.push((at, mix.defer));
+    schedule(store, later, &mut deferred, at);
+    Ok(())
+}
+
+async fn flush_deferred(store: &Arc<Store>, later: &Later, machine: State) -> Result<(), String> {
+    let due: Vec<Node> = {
+        let mut deferred = later.lock().unwrap();
+        if !deferred.nodes.iter().any(|(at, _)| *at <= machine) {
+            return Ok(());
+        }
+        let (due, ahead) = std::mem::take(&mut deferred.nodes).into_iter().partition(|(at, _)| *at <= machine);
+        deferred.nodes = ahead;
+        due.into_iter().map(|(_, node)| node).collect()
+    };
+    let mut due = due.into_iter();
+    while let Some(node) = due.next() {
+        if let Err(e) = put(store, later, &node, machine).await {
+            // Keep what's left to try again in a while.
+            let mut deferred = later.lock().unwrap();
+            let retry = ham::state() + 1000.0;
+            let dropped = requeue(&mut deferred, std::iter::once(node).chain(due).collect(), retry);
+            schedule(store, later, &mut deferred, retry);
+            if dropped > 0 {
+                return Err(format!("{} Gave up on {} deferred writes.", e, dropped));
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// Hold nodes again until `at`, as many as there is room for, giving up on
+// the oldest of them. Returns how many were given up on.
+fn requeue(deferred: &mut Deferred, nodes: Vec<Node>, at: State) -> usize {
+    let room = MAX_DEFERRED.saturating_sub(deferred.nodes.len());
+    let dropped = nodes.len().saturating_sub(room);
+    deferred.nodes.extend(nodes.into_iter().skip(dropped).map(|node| (at, node)));
+    dropped
+}
+
+// Set a timer to retry the deferred writes at `at`, unless one goes off sooner.
+fn schedule(store: &Arc<Store>, later: &Later, deferred: &mut Deferred, at: State) {
+    if deferred.timer.is_some_and(|timer| timer <= at) {
+        return;
+    }
+    deferred.timer = Some(at);
+    let (store, later) = (store.clone(), later.clone());
+    tokio::spawn(async move {
+        let wait = (at - ham::state()).max(0.0) + 1.0;
+        tokio::time::sleep(Duration::from_secs_f64(wait / 1000.0)).await;
+        {
+            let mut deferred = later.lock().unwrap();
+            if deferred.timer != Some(at) {
+                return;
+            }
+            deferred.timer = None;
+        }
+        if let Err(e) = flush_deferred(&store, &later, ham::state()).await {
+            eprintln!("failed to write deferred nodes: {}", e);
+        }
+        // Those still ahead need a timer of their own.
+        let mut deferred = later.lock().unwrap();
+        let next = deferred.nodes.iter().map(|(at, _)| *at).reduce(f64::min);
+        if let Some(next) = next {
+            schedule(&store, &later, &mut deferred, next);
+        }
+    });
+}
+
+/// The earliest state of any field of a node.
+fn earliest(node: &Node) -> State {
+    node.iter().map(|(_, (_, state))| *state).reduce(f64::min).unwrap_or_default()
+}
+
+impl Default for Gun {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_requeue_is_capped() {
+        let mut deferred = Deferred::default();
+        let nodes = |n: usize| (0..n).map(|i| Node::new(&i.to_string())).collect::<Vec<Node>>();
+        assert_eq!(requeue(&mut deferred, nodes(MAX_DEFERRED - 2), 1.0), 0);
+        assert_eq!(requeue(&mut deferred, nodes(5), 2.0), 3);
+        assert_eq!(deferred.nodes.len(), MAX_DEFERRED);
+        // The newest are the ones kept.
+        assert_eq!(deferred.nodes.last().unwrap().1.soul(), "4");
+        assert_eq!(deferred.nodes[MAX_DEFERRED - 2].1.soul(), "3");
+    }
+}
@@ -0,0 +1,177 @@
+//! Hypothetical Amnesia Machine
+//!
+//! Every field of a node is written at a state, a vector clock-free
+//! timestamp in milliseconds. When two writes meet, HAM decides which one
+//! survives so that every peer converges on the same graph no matter the
+//! order the writes arrive in:
+//!
+//! - a state ahead of the machine's own clock is deferred until its time comes,
+//! - a state older than the one we have is history and is dropped,
+//! - a newer state wins,
+//! - equal states are broken lexically on the JSON form of the values.
+
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::node::Node;
+use crate::obj::Value;
+
+/// State is a point in time, in milliseconds since the Unix epoch.
+pub type State = f64;
+
+/// Sub-millisecond steps taken when the clock hasn't moved, as gun.js does.
+const STATE_STEP: f64 = 999.0;
+
+static CLOCK: Mutex<(State, f64)> = Mutex::new((0.0, 0.0));
+
+/// Get the machine's current state. Successive calls never return the same state.
+pub fn state() -> State {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as State)
+        .unwrap_or(0.0);
+    let mut clock = CLOCK.lock().unwrap();
+    let (last, n) = *clock;
+    if last < now {
+        *clock = (now, 0.0);
+        return now;
+    }
+    let next = last + (n + 1.0) / STATE_STEP;
+    *clock = (next, n + 1.0);
+    next
+}
+
+/// Ham is the outcome of comparing an incoming field against the current one.
+#[derive(Debug, PartialEq)]
+pub enum Ham {
+    /// The incoming state is from the future; hold on to it until then.
+    Defer,
+    /// The incoming state is older than what we have.
+    Historical,
+    /// The incoming value wins.
+    Incoming,
+    /// The current value wins.
+    Current,
+    /// Both sides already agree.
+    Same,
+}
+
+pub fn ham(
+    machine: State,
+    incoming_state: State,
+    current_state: State,
+    incoming_value: &Value,
+    current_value: &Value,
+) -> Result<Ham, String> {
+    if machine < incoming_state {
+        return Ok(Ham::Defer);
+    }
+    if incoming_state < current_state {
+        return Ok(Ham::Historical);
+    }
+    if current_state < incoming_state {
+        return Ok(Ham::Incoming);
+    }
+    if incoming_state == current_state {
+        let incoming = lexical(incoming_value);
+        let current = lexical(current_value);
+        if incoming == current {
+            return Ok(Ham::Same);
+        }
+        if incoming < current {
+            return Ok(Ham::Current);
+        }
+        return Ok(Ham::Incoming);
+    }
+    Err(format!(
+        "Invalid CRDT Data: {} to {} at {} to {}!",
+        lexical(incoming_value), lexical(current_value), incoming_state, current_state
+    ))
+}
+
+/// Mix is the result of running an incoming node through HAM.
+#[derive(Debug, PartialEq)]
+pub struct Mix {
+    /// Fields that win and should be written.
+    pub diff: Node,
+    /// Fields from the future, to be retried once the machine catches up.
+    pub defer: Node,
+}
+
+/// Run every field of `incoming` against the `current` node, if there is one.
+pub fn union(current: Option<&Node>, incoming: &Node, machine: State) -> Result<Mix, String> {
+    let mut diff = Node::new(incoming.soul());
+    let mut defer = Node::new(incoming.soul());
+
+    for (key, (val, state)) in incoming.iter() {
+        let (current_value, current_state) = match current.and_then(|node| node.get(key).zip(node.state(key))) {
+            Some((val, state)) => (val, state),
+            None => (&Value::Null, State::NEG_INFINITY),
+        };
+        match ham(machine, *state, current_state, val, current_value)? {
+            Ham::Defer => defer.insert(key.to_string(), val.clone(), *state),
+            Ham::Incoming => diff.insert(key.to_string(), val.clone(), *state),
+            Ham::Historical | Ham::Current | Ham::Same => {}
+        }
+    }
+
+    Ok(Mix { diff, defer })
+}
+
+/// The lexical form of a value, its JSON text, used to break ties.
+pub fn lexical(val: &Value) -> String {
+    match val {
+        Value::Null => "null".to_string(),
+        Value::Bit(b) => b.to_string(),
+        Value::Number(n) if n.is_finite() && n.fract() == 0.0 && n.abs() < 1e21 => {
+            format!("{}", *n as i64)
+        }
+        Value::Number(n) if n.is_finite() => n.to_string(),
+        Value::Number(_) => "null".to_string(),
+        Value::Text(s) => serde_json::to_string(s).unwrap_or_default(),
+        Value::Link(obj) => format!("{{\"#\":{}}}", serde_json::to_string(&obj.get_id()).unwrap_or_default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_ham() {
+        let a = Value::Text("a".to_string());
+        let b = Value::Text("b".to_string());
+        assert_eq!(ham(10.0, 11.0, 5.0, &a, &b), Ok(Ham::Defer));
+        assert_eq!(ham(10.0, 4.0, 5.0, &a, &b), Ok(Ham::Historical));
+        assert_eq!(ham(10.0, 6.0, 5.0, &a, &b), Ok(Ham::Incoming));
+        assert_eq!(ham(10.0, 5.0, 5.0, &a, &a), Ok(Ham::Same));
+        assert_eq!(ham(10.0, 5.0, 5.0, &a, &b), Ok(Ham::Current));
+        assert_eq!(ham(10.0, 5.0, 5.0, &b, &a), Ok(Ham::Incoming));
+        assert!(ham(10.0, f64::NAN, 5.0, &a, &b).is_err());
+    }
+
+    #[test]
+    fn test_union() -> Result<(), String> {
+        let mut current = Node::new("mark");
+        current.insert("name".to_string(), Value::Text("Mark".to_string()), 5.0);
+        current.insert("age".to_string(), Value::Number(30.0), 5.0);
+
+        let mut incoming = Node::new("mark");
+        incoming.insert("name".to_string(), Value::Text("Marc".to_string()), 4.0);
+        incoming.insert("age".to_string(), Value::Number(31.0), 6.0);
+        incoming.insert("city".to_string(), Value::Text("Bangkok".to_string()), 20.0);
+
+        let mix = union(Some(&current), &incoming, 10.0)?;
+        assert_eq!(mix.diff.get("name"), None);
+        assert_eq!(mix.diff.get("age"), Some(&Value::Number(31.0)));
+        assert_eq!(mix.defer.get("city"), Some(&Value::Text("Bangkok".to_string())));
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_is_monotonic() {
+        let a = state();
+        let b = state();
+        assert!(a < b);
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::metrics::METRICS;
+
+/// Rate is how many messages and bytes a client may send each second.
+/// It may send up to a second's worth at once. Zero means no limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub messages: u32,
+    pub bytes: u64,
+}
+
+impl Rate {
+    pub const UNLIMITED: Rate = Rate { messages: 0, bytes: 0 };
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LimitOptions {
+    /// What each connection may send.
+    pub connection: Rate,
+    /// What all the connections from one remote address may send together.
+    pub ip: Rate,
+    /// After how many frames over the limit a connection is disconnected,
+    /// or 0 to never disconnect it.
+    pub strikes: u32,
+}
+
+/// Strikes are forgotten once a connection stays within its limits for this long.
+pub const STRIKES_FORGIVEN: Duration = Duration::from_secs(10);
+
+impl LimitOptions {
+    /// Limits that never refuse anything.
+    pub fn unlimited() -> Self {
+        LimitOptions { connection: Rate::UNLIMITED, ip: Rate::UNLIMITED, strikes: 0 }
+    }
+}
+
+impl Default for LimitOptions {
+    fn default() -> Self {
+        LimitOptions {
+            connection: Rate { messages: 100, bytes: 1024 * 1024 },
+            ip: Rate { messages: 500, bytes: 5 * 1024 * 1024 },
+            strikes: 10,
+        }
+    }
+}
+
+// Bucket holds up to a second's worth of tokens and refills continuously.
+// Something bigger than the whole bucket is let through once it is full,
+// leaving it in debt for as long as that takes to pay back.
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+}
+
+impl Bucket {
+    fn new(rate: f64) -> Self {
+        Bucket { rate, tokens: rate }
+    }
+
+    fn refill(&mut self, secs: f64) {
+        self.tokens = (self.tokens + secs * self.rate).min(self.rate);
+    }
+
+    fn has(&self, n: f64) -> bool {
+        self.rate == 0.0 || self.tokens >= n.min(self.rate)
+    }
+
+    fn take(&mut self, n: f64) {
+        if self.rate != 0.0 {
+            self.tokens -= n;
+        }
+    }
+}
+
+struct Buckets {
+    messages: Bucket,
+    bytes: Bucket,
+    last: Instant,
+}
+
+impl Buckets {
+    fn new(rate: Rate, now: Instant) -> Self {
+        Buckets { messages: Bucket::new(rate.messages as f64), bytes: Bucket::new(rate.bytes as f64), last: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let secs = now.saturating_duration_since(self.last).as_secs_f64();
+        self.messages.refill(secs);
+        self.bytes.refill(secs);
+        self.last = now;
+    }
+
+    fn has(&self, messages: usize, bytes: usize) -> bool {
+        self.messages.has(messages as f64) && self.bytes.has(bytes as f64)
+    }
+
+    fn take(&mut self, messages: usize, bytes: usize) {
+        self.messages.take(messages as f64);
+        self.bytes.take(bytes as f64);
+    }
+}
+
+/// Verdict is what to do with a frame a client sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Drop the frame and tell the client to slow down.
+    Refuse,
+    /// The client kept going; hang up on it.
+    Disconnect,
+}
+
+/// Limiter keeps clients from flooding the relay, with a token bucket for
+/// each connection and one shared by every connection from the same address.
+pub struct Limiter {
+    opt: LimitOptions,
+    // The shared buckets, with how many connections are using them.
+    ips: Mutex<HashMap<IpAddr, (Buckets, usize)>>,
+    /// How many frames were refused for going over the limits.
+    pub refused: AtomicU64,
+    /// How many connections were dropped for going over the limits.
+    pub disconnected: AtomicU64,
+}
+
+impl Limiter {
+    pub fn new(opt: LimitOptions) -> Self {
+        Limiter { opt, ips: Default::default(), refused: AtomicU64::new(0), disconnected: AtomicU64::new(0) }
+    }
+
+    pub fn options(&self) -> &LimitOptions {
+        &self.opt
+    }
+
+    /// Start limiting a new connection from `ip`, if it is known.
+    pub fn open(self: &Arc<Self>, ip: Option<IpAddr>) -> Connection {
+        let now = Instant::now();
+        if let Some(ip) = ip {
+            self.ips.lock().unwrap().entry(ip)
+                .or_insert_with(|| (Buckets::new(self.opt.ip, now), 0))
+                .1 += 1;
+        }
+        Connection { limiter: self.clone(), ip, buckets: Buckets::new(self.opt.connection, now), strikes: 0, struck: now }
+    }
+}
+
+/// Connection is the limit on one client, for as long as it is connected.
+pub struct Connection {
+    limiter: Arc<Limiter>,
+    ip: Option<IpAddr>,
+    buckets: Buckets,
+    strikes: u32,
+    // When it last went over the limits.
+    struck: Instant,
+}
+
+impl Connection {
+    /// Count a frame of `bytes` holding `messages` against the limits.
+    pub fn check(&mut self, messages: usize, bytes: usize) -> Verdict {
+        self.check_at(messages, bytes, Instant::now())
+    }
+
+    /// Count a frame of `bytes` against the limits, before parsing it.
+    pub fn check_bytes(&mut self, bytes: usize) -> Verdict {
+        self.check(0, bytes)
+    }
+
+    /// Count the messages a frame turned out to hold.
+    pub fn check_messages(&mut self, messages: usize) -> Verdict {
+        self.check(messages, 0)
+    }
+
+    fn check_at(&mut self, messages: usize, bytes: usize, now: Instant) -> Verdict {
+        let limiter = self.limiter.clone();
+        let mut ips = limiter.ips.lock().unwrap();
+        let mut shared = self.ip.and_then(|ip| ips.get_mut(&ip)).map(|(buckets, _)| buckets);
+        self.buckets.refill(now);
+        if let Some(shared) = shared.as_mut() {
+            shared.refill(now);
+        }
+
+        let allowed = self.buckets.has(messages, bytes)
+            && shared.as_ref().is_none_or(|shared| shared.has(messages, bytes));
+        if allowed {
+            self.buckets.take(messages, bytes);
+            if let Some(shared) = shared {
+                shared.take(messages, bytes);
+            }
+            return Verdict::Allow;
+        }
+
+        limiter.refused.fetch_add(1, Ordering::Relaxed);
+        METRICS.limit_refused.inc();
+        if now.saturating_duration_since(self.struck) >= STRIKES_FORGIVEN {
+            self.strikes = 0;
+        }
+        self.strikes += 1;
+        self.struck = now;
+        if limiter.opt.strikes != 0 && self.strikes >= limiter.opt.strikes {
+            limiter.disconnected.fetch_add(1, Ordering::Relaxed);
+            METRICS.limit_disconnected.inc();
+            return Verdict::Disconnect;
+        }
+        Verdict::Refuse
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let ip = match self.ip {
+            Some(ip) => ip,
+            None => return,
+        };
+        let mut ips = self.limiter.ips.lock().unwrap();
+        if let Some((_, conns)) = ips.get_mut(&ip) {
+            *conns -= 1;
+            if *conns == 0 {
+                ips.remove(&ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn limiter(connection: Rate, ip: Rate, strikes: u32) -> Arc<Limiter> {
+        Arc::new(Limiter::new(LimitOptions { connection, ip, strikes }))
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let limiter = limiter(Rate { messages: 2, bytes: 100 }, Rate::UNLIMITED, 3);
+        let mut conn = limiter.open(None);
+        let now = Instant::now();
+        assert_eq!(conn.check_at(1, 10, now), Verdict::Allow);
+        assert_eq!(conn.check_at(1, 10, now), Verdict::Allow);
+        assert_eq!(conn.check_at(1, 10, now), Verdict::Refuse);
+
+        // Half a second later there is room for one more.
+        let later = now + Duration::from_millis(500);
+        assert_eq!(conn.check_at(1, 10, later), Verdict::Allow);
+        assert_eq!(conn.check_at(1, 10, later), Verdict::Refuse);
+        assert_eq!(conn.check_at(1, 10, later), Verdict::Disconnect);
+        assert_eq!(limiter.refused.load(Ordering::Relaxed), 3);
+        assert_eq!(limiter.disconnected.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_strikes_are_forgiven() {
+        let strict = limiter(Rate { messages: 1, bytes: 0 }, Rate::UNLIMITED, 2);
+        let mut conn = strict.open(None);
+        let mut now = Instant::now();
+        conn.check_at(1, 1, now);
+        for _ in 0..5 {
+            assert_eq!(conn.check_at(1, 1, now), Verdict::Refuse);
+            now += STRIKES_FORGIVEN;
+            assert_eq!(conn.check_at(1, 1, now), Verdict::Allow);
+        }
+        assert_eq!(conn.check_at(1, 1, now), Verdict::Refuse);
+        assert_eq!(conn.check_at(1, 1, now), Verdict::Disconnect);
+
+        // No strikes at all means never hanging up.
+        let never = limiter(Rate { messages: 1, bytes: 0 }, Rate::UNLIMITED, 0);
+        let mut conn = never.open(None);
+        for _ in 0..100 {
+            conn.check_at(1, 1, now);
+        }
+        assert_eq!(conn.check_at(1, 1, now), Verdict::Refuse);
+    }
+
+    #[test]
+    fn test_bytes_limit() {
+        let limiter = limiter(Rate { messages: 0, bytes: 100 }, Rate::UNLIMITED, 10);
+        let mut conn = limiter.open(None);
+        let now = Instant::now();
+        assert_eq!(conn.check_at(1, 60, now), Verdict::Allow);
+        assert_eq!(conn.check_at(1, 60, now), Verdict::Refuse);
+
+        // A frame bigger than the bucket gets through once it is full, and
+        // nothing else does until that is paid back.
+        let later = now + Duration::from_secs(1);
+        assert_eq!(conn.check_at(1, 250, later), Verdict::Allow);
+        assert_eq!(conn.check_at(1, 1, later + Duration::from_secs(1)), Verdict::Refuse);
+        assert_eq!(conn.check_at(1, 1, later + Duration::from_secs(2)), Verdict::Allow);
+    }
+
+    #[test]
+    fn test_ip_limit_is_shared() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let limiter = limiter(Rate { messages: 2, bytes: 0 }, Rate { messages: 3, bytes: 0 }, 10);
+        let mut a = limiter.open(Some(ip));
+        let mut b = limiter.open(Some(ip));
+        let mut c = limiter.open(Some("10.0.0.2".parse().unwrap()));
+        let now = Instant::now();
+        assert_eq!(a.check_at(1, 1, now), Verdict::Allow);
+        assert_eq!(a.check_at(1, 1, now), Verdict::Allow);
+        assert_eq!(b.check_at(1, 1, now), Verdict::Allow);
+        assert_eq!(b.check_at(1, 1, now), Verdict::Refuse);
+        assert_eq!(c.check_at(2, 1, now), Verdict::Allow);
+
+        drop((a, b, c));
+        assert!(limiter.ips.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = Arc::new(Limiter::new(LimitOptions::unlimited()));
+        let mut conn = limiter.open(Some("10.0.0.1".parse().unwrap()));
+        for _ in 0..1000 {
+            assert_eq!(conn.check(100, 1024 * 1024), Verdict::Allow);
+        }
+    }
+}
@@ -1,5 +1,10 @@
+use std::collections::BTreeMap;
+use std::fmt;
 use std::str::FromStr;
-use crate::obj::Value;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error;
+use crate::node::Node;
+use crate::obj::{gen_random, Value};
 
 
 /// Key represents constraint in the Message's keys.
@@ -10,17 +15,22 @@ pub enum Key {
     AckId,
     Peers,
     Via,
+    Error,
+    Dam,
 }
 
-impl ToString for Key {
-    fn to_string(&self) -> String {
-        match self {
-            Self::MessageId   => String::from("#"),
-            Self::ContentHash => String::from("##"),
-            Self::AckId       => String::from("@"),
-            Self::Peers       => String::from("><"),
-            Self::Via         => String::from("via"),
-        }
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::MessageId   => "#",
+            Self::ContentHash => "##",
+            Self::AckId       => "@",
+            Self::Peers       => "><",
+            Self::Via         => "via",
+            Self::Error       => "err",
+            Self::Dam         => "dam",
+        };
+        f.write_str(s)
     }
 }
 
@@ -33,12 +43,334 @@ impl FromStr for Key {
             "@"   => Ok(Key::AckId),
             "><"  => Ok(Key::Peers),
             "via" => Ok(Key::Via),
+            "err" => Ok(Key::Error),
+            "dam" => Ok(Key::Dam),
             _     => Err(format!("{} is not a valid key", s)),
         }
     }
 }
 
-pub(crate) trait Message {
+pub trait Message {
     fn insert(&mut self, key: Key, val: Value) -> Result<(), &str>;
     fn get(&self, key: Key) -> Option<Value>;
-}
\ No newline at end of file
+}
+
+/// Put is the graph carried by a `put` message, keyed by soul.
+pub type Put = BTreeMap<String, Node>;
+
+/// Msg is a single GUN wire message.
+///
+/// Every message has an id under `#`. Replies point back at the message
+/// they answer with `@`, and carry either the data asked for in `put`,
+/// an `ok` or an `err`. Keys we don't know about are kept in `other` so
+/// that relaying a message never loses anything.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct Msg {
+    #[serde(rename = "#", default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "##", default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(rename = "@", default, skip_serializing_if = "Option::is_none")]
+    pub ack: Option<String>,
+    #[serde(rename = "><", default, skip_serializing_if = "Option::is_none")]
+    pub peers: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub get: Option<Get>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub put: Option<Put>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ok: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub err: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dam: Option<String>,
+    /// The peer the message came from. Never sent on the wire.
+    #[serde(skip)]
+    pub via: Option<String>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Msg {
+    /// Create an empty message with a fresh id.
+    pub fn new() -> Self {
+        Msg { id: Some(gen_random(9)), ..Default::default() }
+    }
+
+    /// Create an empty message answering this one.
+    pub fn reply(&self) -> Self {
+        Msg { ack: self.id.clone(), ..Msg::new() }
+    }
+
+    /// Parse a raw frame, which holds either one message or an array of them.
+    /// Each message in a batch is parsed on its own, so that a bad one
+    /// doesn't take the others down with it.
+    pub fn parse(raw: &str) -> Vec<Result<Msg, MessageError>> {
+        match serde_json::from_str(raw) {
+            Ok(serde_json::Value::Array(items)) => items.into_iter().map(Msg::from_json).collect(),
+            Ok(item) => vec![Msg::from_json(item)],
+            Err(e) => vec![Err(MessageError::Json(e.to_string()))],
+        }
+    }
+
+    fn from_json(json: serde_json::Value) -> Result<Msg, MessageError> {
+        let id = json.get("#").and_then(|id| id.as_str()).map(String::from);
+        let msg: Msg = serde_json::from_value(json)
+            .map_err(|e| MessageError::Invalid { id: id.clone(), reason: e.to_string() })?;
+        msg.validate()?;
+        Ok(msg)
+    }
+
+    fn validate(&self) -> Result<(), MessageError> {
+        let invalid = |reason: String| MessageError::Invalid { id: self.id.clone(), reason };
+        if self.id.is_none() {
+            return Err(invalid("message has no id".to_string()));
+        }
+        if let Some(put) = &self.put {
+            for (soul, node) in put.iter() {
+                if soul != node.soul() {
+                    return Err(invalid(format!("node '{}' is put under '{}'", node.soul(), soul)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+impl Message for Msg {
+    fn insert(&mut self, key: Key, val: Value) -> Result<(), &str> {
+        let text = match val {
+            Value::Text(text) => text,
+            _ => return Err("message keys only hold text"),
+        };
+        let field = match key {
+            Key::MessageId   => &mut self.id,
+            Key::ContentHash => &mut self.hash,
+            Key::AckId       => &mut self.ack,
+            Key::Peers       => &mut self.peers,
+            Key::Via         => &mut self.via,
+            Key::Error       => &mut self.err,
+            Key::Dam         => &mut self.dam,
+        };
+        *field = Some(text);
+        Ok(())
+    }
+
+    fn get(&self, key: Key) -> Option<Value> {
+        let field = match key {
+            Key::MessageId   => &self.id,
+            Key::ContentHash => &self.hash,
+            Key::AckId       => &self.ack,
+            Key::Peers       => &self.peers,
+            Key::Via         => &self.via,
+            Key::Error       => &self.err,
+            Key::Dam         => &self.dam,
+        };
+        field.clone().map(Value::Text)
+    }
+}
+
+/// Hash a string the way gun.js does, to tell identical answers apart
+/// under `##`.
+pub fn hash(s: &str) -> String {
+    let mut c: i32 = 0;
+    for ch in s.encode_utf16() {
+        c = c.wrapping_shl(5).wrapping_sub(c).wrapping_add(ch as i32);
+    }
+    c.to_string()
+}
+
+/// Get asks for a node by soul under `#`, optionally narrowed under `.`
+/// to a single key or a lexical query.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Get {
+    #[serde(rename = "#")]
+    pub soul: String,
+    #[serde(rename = ".", default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<Dot>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Dot {
+    Key(String),
+    Lex(Lex),
+}
+
+/// Lex is GUN's lexical query over the keys of a node.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct Lex {
+    /// Exactly this key.
+    #[serde(rename = "=", default, skip_serializing_if = "Option::is_none")]
+    pub exact: Option<String>,
+    /// Keys starting with this prefix.
+    #[serde(rename = "*", default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    /// Keys from this one on.
+    #[serde(rename = ">", default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<String>,
+    /// Keys up to this one.
+    #[serde(rename = "<", default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<String>,
+    /// Walk the keys in reverse order.
+    #[serde(rename = "-", default, skip_serializing_if = "is_false",
+            serialize_with = "serialize_flag", deserialize_with = "deserialize_flag")]
+    pub reverse: bool,
+    /// At most this many keys.
+    #[serde(rename = "%", default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+impl Dot {
+    /// Whether a key is one the query asks for. Limits aside, as they
+    /// depend on the other keys around.
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            Dot::Key(k) => k == key,
+            Dot::Lex(lex) => lex.matches(key),
+        }
+    }
+
+    /// The first key the query may match, if it has one.
+    pub fn lower(&self) -> Option<&str> {
+        match self {
+            Dot::Key(key) => Some(key),
+            Dot::Lex(lex) => [&lex.exact, &lex.prefix, &lex.start].iter()
+                .filter_map(|bound| bound.as_deref())
+                .max(),
+        }
+    }
+}
+
+impl Lex {
+    /// Whether a key falls within the query, leaving the limit aside.
+    pub fn matches(&self, key: &str) -> bool {
+        if let Some(exact) = &self.exact {
+            if key != exact {
+                return false;
+            }
+        }
+        if let Some(prefix) = &self.prefix {
+            if !key.starts_with(prefix.as_str()) {
+                return false;
+            }
+        }
+        if let Some(start) = &self.start {
+            if key < start.as_str() {
+                return false;
+            }
+        }
+        if let Some(end) = &self.end {
+            if key > end.as_str() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+/// gun.js writes flags as `1`.
+fn serialize_flag<S: Serializer>(b: &bool, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u8(*b as u8)
+}
+
+fn deserialize_flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Bool(b) => Ok(b),
+        serde_json::Value::Number(n) => Ok(n.as_f64() != Some(0.0)),
+        serde_json::Value::Null => Ok(false),
+        _ => Err(D::Error::custom("expected a flag")),
+    }
+}
+
+/// MessageError is why a frame couldn't be read as GUN messages.
+#[derive(Debug, PartialEq, Clone)]
+pub enum MessageError {
+    /// The frame is binary; GUN only speaks text.
+    Binary,
+    /// The frame is not JSON.
+    Json(String),
+    /// The frame is JSON but not a valid message.
+    Invalid { id: Option<String>, reason: String },
+    /// The peer is sending more than it is allowed to.
+    Limited,
+}
+
+impl MessageError {
+    /// The `err` message to send back to whoever sent the bad frame.
+    pub fn reply(&self) -> Msg {
+        let ack = match self {
+            Self::Invalid { id, .. } => id.clone(),
+            _ => None,
+        };
+        Msg { ack, err: Some(self.to_string()), ..Msg::new() }
+    }
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Binary => write!(f, "binary frames are not supported"),
+            Self::Json(e) => write!(f, "invalid JSON: {}", e),
+            Self::Invalid { reason, .. } => write!(f, "invalid message: {}", reason),
+            Self::Limited => write!(f, "too many messages, slow down"),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_round_trip() {
+        let raw = r##"[{"#":"a1","get":{"#":"mark",".":{"*":"na","%":2,"-":1}}},{"#":"a2","put":{"mark":{"_":{"#":"mark",">":{"boss":3,"name":2}},"boss":{"#":"amber"},"name":"Mark"}},"extra":true}]"##;
+        let msgs: Vec<Msg> = Msg::parse(raw).into_iter().map(|m| m.unwrap()).collect();
+
+        let get = msgs[0].get.as_ref().unwrap();
+        assert_eq!(get.soul, "mark");
+        assert_eq!(get.key, Some(Dot::Lex(Lex {
+            prefix: Some("na".to_string()),
+            reverse: true,
+            limit: Some(2),
+            ..Default::default()
+        })));
+
+        let node = &msgs[1].put.as_ref().unwrap()["mark"];
+        assert_eq!(node.get("name"), Some(&Value::Text("Mark".to_string())));
+        assert_eq!(node.state("boss"), Some(3.0));
+        assert_eq!(msgs[1].other.get("extra"), Some(&serde_json::Value::Bool(true)));
+
+        for msg in msgs {
+            let again = Msg::parse(&msg.to_string()).pop().unwrap().unwrap();
+            assert_eq!(again, msg);
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(Msg::parse("{not json").pop(), Some(Err(MessageError::Json(_)))));
+        assert!(matches!(Msg::parse(r##"{"get":{"#":"a"}}"##).pop(), Some(Err(MessageError::Invalid { id: None, .. }))));
+
+        let bad_node = r##"{"#":"b1","put":{"a":{"_":{"#":"a",">":{}},"x":1}}}"##;
+        let err = Msg::parse(bad_node).pop().unwrap().unwrap_err();
+        assert_eq!(err.reply().ack, Some("b1".to_string()));
+
+        let bad_value = r##"{"#":"b2","put":{"a":{"_":{"#":"a",">":{"x":1}},"x":[1]}}}"##;
+        assert!(Msg::parse(bad_value).pop().unwrap().is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use std::sync::LazyLock;
+use std::time::Instant;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use crate::dam::Dam;
+use crate::limit::Limiter;
+use crate::message::Msg;
+
+/// The relay's metrics, kept for the whole process.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Metrics counts what goes through the relay, for `/metrics` to serve
+/// in the Prometheus text format. Counters are bumped along the message
+/// path and by the Limiter; gauges are read from the Dam and Limiter when
+/// scraped.
+pub struct Metrics {
+    registry: Registry,
+    /// Messages heard from peers, by type.
+    pub messages_in: IntCounterVec,
+    /// Messages sent to peers, by type, once for each peer sent to.
+    pub messages_out: IntCounterVec,
+    pub bytes_in: IntCounter,
+    pub bytes_out: IntCounter,
+    /// How many peers each put was relayed to.
+    pub fanout: Histogram,
+    /// Messages dropped for having been heard before.
+    pub dedup_hits: IntCounter,
+    /// Frames that couldn't be queued for a peer or a tap, by why.
+    pub dropped_sends: IntCounterVec,
+    /// How long the storage adapter takes, by operation.
+    pub storage: HistogramVec,
+    /// Frames refused for going over the rate limits.
+    pub limit_refused: IntCounter,
+    /// Connections dropped for going over the rate limits.
+    pub limit_disconnected: IntCounter,
+    peers: IntGaugeVec,
+    queued: IntGauge,
+    queued_max: IntGauge,
+    dup_ids: IntGauge,
+    subscribed: IntGauge,
+    limit: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("rod".to_string()), None).unwrap();
+        let counters = |name: &str, help: &str, labels: &[&str]| {
+            let vec = IntCounterVec::new(Opts::new(name, help), labels).unwrap();
+            registry.register(Box::new(vec.clone())).unwrap();
+            vec
+        };
+        let counter = |name: &str, help: &str| {
+            let counter = IntCounter::new(name, help).unwrap();
+            registry.register(Box::new(counter.clone())).unwrap();
+            counter
+        };
+        let gauges = |name: &str, help: &str, labels: &[&str]| {
+            let vec = IntGaugeVec::new(Opts::new(name, help), labels).unwrap();
+            registry.register(Box::new(vec.clone())).unwrap();
+            vec
+        };
+        let gauge = |name: &str, help: &str| {
+            let gauge = IntGauge::new(name, help).unwrap();
+            registry.register(Box::new(gauge.clone())).unwrap();
+            gauge
+        };
+
+        let fanout = Histogram::with_opts(
+            HistogramOpts::new("put_fanout", "How many peers each put is relayed to.")
+                .buckets(vec![0.0, 1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 1000.0]),
+        ).unwrap();
+        registry.register(Box::new(fanout.clone())).unwrap();
+        let storage = HistogramVec::new(
+            HistogramOpts::new("storage_seconds", "How long the storage adapter takes, by operation."),
+            &["op"],
+        ).unwrap();
+        registry.register(Box::new(storage.clone())).unwrap();
+
+        Metrics {
+            messages_in: counters("messages_in_total", "Messages heard from peers, by type.", &["type"]),
+            messages_out: counters("messages_out_total", "Messages sent to peers, by type.", &["type"]),
+            bytes_in: counter("bytes_in_total", "Bytes heard from peers."),
+            bytes_out: counter("bytes_out_total", "Bytes sent to peers."),
+            fanout,
+            dedup_hits: counter("dedup_hits_total", "Messages dropped for having been heard before."),
+            dropped_sends: counters("dropped_sends_total", "Frames that couldn't be queued for a peer or a tap.", &["reason"]),
+            storage,
+            peers: gauges("peers", "Connected peers, by whether they are users or relays.", &["kind"]),
+            queued: gauge("queued_frames", "Frames waiting to be written to peers."),
+            queued_max: gauge("queued_frames_max", "Frames waiting for the peer with the longest queue."),
+            dup_ids: gauge("dup_ids", "Message ids remembered to drop echoes."),
+            subscribed: gauge("subscribed_souls", "Souls some peer is subscribed to."),
+            limit: gauges("rate_limit", "The configured rate limits per second, 0 for none.", &["scope", "unit"]),
+            limit_refused: counter("rate_limit_refused_total", "Frames refused for going over the rate limits."),
+            limit_disconnected: counter("rate_limit_disconnected_total", "Connections dropped for going over the rate limits."),
+            registry,
+        }
+    }
+
+    /// Count a message heard from a peer.
+    pub fn heard(&self, msg: &Msg) {
+        self.messages_in.with_label_values(&[kind(msg)]).inc();
+    }
+
+    /// Count a message of `bytes` sent to `peers` peers.
+    pub fn sent(&self, msg: &Msg, bytes: usize, peers: usize) {
+        self.messages_out.with_label_values(&[kind(msg)]).inc_by(peers as u64);
+        self.bytes_out.inc_by((bytes * peers) as u64);
+    }
+
+    /// Start timing a storage operation, which is observed when the
+    /// returned timer is dropped.
+    pub fn time_storage(&self, op: &str) -> StorageTimer {
+        StorageTimer { histogram: self.storage.with_label_values(&[op]), start: Instant::now() }
+    }
+
+    /// Everything in the Prometheus text format, with the gauges read
+    /// from `dam` and `limiter` now.
+    pub async fn render(&self, dam: &Dam, limiter: &Limiter) -> String {
+        {
+            let peers = dam.peers.read().await;
+            let relays = peers.values().filter(|p| p.relay).count();
+            self.peers.with_label_values(&["relay"]).set(relays as i64);
+            self.peers.with_label_values(&["user"]).set((peers.len() - relays) as i64);
+            self.queued.set(peers.values().map(|p| p.queued()).sum::<usize>() as i64);
+            self.queued_max.set(peers.values().map(|p| p.queued()).max().unwrap_or(0) as i64);
+        }
+        self.dup_ids.set(dam.gun.dups.len() as i64);
+        self.subscribed.set(dam.subs.len() as i64);
+
+        let opt = limiter.options();
+        for (scope, rate) in [("connection", opt.connection), ("ip", opt.ip)] {
+            self.limit.with_label_values(&[scope, "messages"]).set(rate.messages as i64);
+            self.limit.with_label_values(&[scope, "bytes"]).set(rate.bytes as i64);
+        }
+        self.limit.with_label_values(&["connection", "strikes"]).set(opt.strikes as i64);
+
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+/// StorageTimer observes how long it lived once dropped.
+pub struct StorageTimer {
+    histogram: Histogram,
+    start: Instant,
+}
+
+impl Drop for StorageTimer {
+    fn drop(&mut self) {
+        self.histogram.observe(self.start.elapsed().as_secs_f64());
+    }
+}
+
+/// The type of a message, as metrics count it.
+fn kind(msg: &Msg) -> &'static str {
+    if msg.dam.is_some() {
+        "dam"
+    } else if msg.ack.is_some() {
+        "ack"
+    } else if msg.get.is_some() {
+        "get"
+    } else if msg.put.is_some() {
+        "put"
+    } else {
+        "other"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::gun::gun::Gun;
+    use crate::limit::LimitOptions;
+
+    #[tokio::test]
+    async fn test_render() {
+        let dam = Dam::new(Gun::new());
+        let limiter = Limiter::new(LimitOptions::default());
+        let heard = METRICS.messages_in.with_label_values(&["put"]).get();
+        dam.hear(r##"{"#":"p1","put":{"mark":{"_":{"#":"mark",">":{"name":1}},"name":"Mark"}}}"##, "a").await;
+        assert!(METRICS.messages_in.with_label_values(&["put"]).get() > heard);
+
+        let text = METRICS.render(&dam, &limiter).await;
+        assert!(text.contains("rod_messages_in_total{type=\"put\"}"));
+        assert!(text.contains("rod_storage_seconds_count{op=\"merge\"}"));
+        assert!(text.contains("rod_rate_limit{scope=\"connection\",unit=\"messages\"} 100"));
+        assert!(text.contains("rod_peers{kind=\"user\"} 0"));
+        assert!(text.contains("# TYPE rod_rate_limit_refused_total counter"));
+    }
+}
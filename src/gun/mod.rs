@@ -1,7 +1,17 @@
+pub mod config;
 pub mod dam;
+#[allow(clippy::module_inception)]
 pub mod gun;
 pub mod dup;
+pub mod limit;
+pub mod metrics;
 pub mod message;
 pub mod obj;
 pub mod graph;
+pub mod ham;
+pub mod sea;
+pub mod user;
+pub mod node;
+pub mod store;
+pub mod subscriptions;
 pub mod adapters;
@@ -0,0 +1,183 @@
+use std::collections::btree_map;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error;
+use serde::ser::SerializeMap;
+use serde_json::json;
+use crate::obj::Value;
+use crate::ham::State;
+use crate::message::Dot;
+
+/// Node is a vertex of the GUN graph: a soul and its fields, each field
+/// carrying the state (the `_` `>` metadata) it was written at.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Node {
+    soul: String,
+    fields: BTreeMap<String, (Value, State)>,
+}
+
+impl Node {
+    /// Create an empty node with the given soul.
+    pub fn new(soul: &str) -> Self {
+        Node { soul: soul.to_string(), fields: BTreeMap::new() }
+    }
+
+    /// Get the node's soul.
+    pub fn soul(&self) -> &str {
+        &self.soul
+    }
+
+    /// Get a field's value.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.fields.get(key).map(|(val, _)| val)
+    }
+
+    /// Get the state a field was written at.
+    pub fn state(&self, key: &str) -> Option<State> {
+        self.fields.get(key).map(|(_, state)| *state)
+    }
+
+    /// Insert a field with its state, replacing any previous one.
+    pub fn insert(&mut self, key: String, val: Value, state: State) {
+        self.fields.insert(key, (val, state));
+    }
+
+    /// Iterate over the fields in key order.
+    pub fn iter(&self) -> btree_map::Iter<'_, String, (Value, State)> {
+        self.fields.iter()
+    }
+
+    /// Get the part of the node a `.` query asks for: a single key, or the
+    /// keys within a lexical range, counted from the end when reversed.
+    pub fn select(&self, dot: &Dot) -> Node {
+        let (limit, reverse) = match dot {
+            Dot::Key(_) => (None, false),
+            Dot::Lex(lex) => (lex.limit, lex.reverse),
+        };
+        let lower = match dot.lower() {
+            Some(key) => Bound::Included(key),
+            None => Bound::Unbounded,
+        };
+        let keys = self.fields.range::<str, _>((lower, Bound::Unbounded))
+            .filter(|(key, _)| dot.matches(key));
+        let keys: Box<dyn Iterator<Item = (&String, &(Value, State))>> = if reverse {
+            Box::new(keys.rev())
+        } else {
+            Box::new(keys)
+        };
+
+        let mut node = Node::new(&self.soul);
+        for (key, (val, state)) in keys.take(limit.unwrap_or(usize::MAX)) {
+            node.insert(key.to_string(), val.clone(), *state);
+        }
+        node
+    }
+
+    /// Copy every field of `other` into this node.
+    pub fn merge(&mut self, other: Node) {
+        self.fields.extend(other.fields);
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+/// Nodes are written on the wire with their metadata under `_`:
+/// `{"_": {"#": soul, ">": {key: state}}, key: value}`.
+impl Serialize for Node {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let states: BTreeMap<&String, State> = self.fields.iter()
+            .map(|(key, (_, state))| (key, *state))
+            .collect();
+        let mut meta = serde_json::Map::new();
+        meta.insert("#".to_string(), json!(self.soul));
+        meta.insert(">".to_string(), json!(states));
+
+        let mut map = serializer.serialize_map(Some(self.fields.len() + 1))?;
+        map.serialize_entry("_", &meta)?;
+        for (key, (val, _)) in self.fields.iter() {
+            map.serialize_entry(key, val)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Node {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut map = serde_json::Map::deserialize(deserializer)?;
+        let meta = match map.remove("_") {
+            Some(serde_json::Value::Object(meta)) => meta,
+            _ => return Err(D::Error::custom("node has no metadata")),
+        };
+        let soul = match meta.get("#") {
+            Some(serde_json::Value::String(soul)) => soul.to_string(),
+            _ => return Err(D::Error::custom("node has no soul")),
+        };
+        let no_states = serde_json::Map::new();
+        let states = match meta.get(">") {
+            Some(serde_json::Value::Object(states)) => states,
+            None => &no_states,
+            _ => return Err(D::Error::custom(format!("node '{}' has invalid states", soul))),
+        };
+
+        let mut node = Node::new(&soul);
+        for (key, val) in map.into_iter() {
+            let state = match states.get(&key).and_then(|state| state.as_f64()) {
+                Some(state) => state,
+                None => return Err(D::Error::custom(format!("No state on '{}' in node '{}'", key, soul))),
+            };
+            let val = Value::deserialize(val)
+                .map_err(|e| D::Error::custom(format!("Invalid value at '{}' in node '{}': {}", key, soul, e)))?;
+            node.insert(key, val, state);
+        }
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::message::Lex;
+
+    fn timeline() -> Node {
+        let mut node = Node::new("timeline");
+        for day in ["2021-08-01", "2021-08-02", "2021-08-03", "2021-09-01", "note"].iter() {
+            node.insert(day.to_string(), Value::Text(day.to_string()), 1.0);
+        }
+        node
+    }
+
+    fn keys(node: &Node) -> Vec<&str> {
+        node.iter().map(|(key, _)| key.as_str()).collect()
+    }
+
+    #[test]
+    fn test_select() {
+        let node = timeline();
+        assert_eq!(keys(&node.select(&Dot::Key("note".to_string()))), vec!["note"]);
+        assert!(node.select(&Dot::Key("nope".to_string())).is_empty());
+
+        let prefix = Lex { prefix: Some("2021-08".to_string()), ..Default::default() };
+        assert_eq!(keys(&node.select(&Dot::Lex(prefix))), vec!["2021-08-01", "2021-08-02", "2021-08-03"]);
+
+        let range = Lex {
+            start: Some("2021-08-02".to_string()),
+            end: Some("2021-09-01".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(keys(&node.select(&Dot::Lex(range.clone()))), vec!["2021-08-02", "2021-08-03", "2021-09-01"]);
+
+        let first = Lex { limit: Some(2), ..range.clone() };
+        assert_eq!(keys(&node.select(&Dot::Lex(first))), vec!["2021-08-02", "2021-08-03"]);
+
+        let last = Lex { limit: Some(2), reverse: true, ..range };
+        assert_eq!(keys(&node.select(&Dot::Lex(last))), vec!["2021-08-03", "2021-09-01"]);
+    }
+}
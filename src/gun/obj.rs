@@ -1,6 +1,9 @@
 use std::collections::HashMap;
 use std::convert::TryInto;
 use rand::Rng;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error;
+use serde::ser::SerializeMap;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Object(String, HashMap<String, Value>);
@@ -25,6 +28,17 @@ impl Object {
     pub fn insert(&mut self, key: String, val: Value) {
         self.1.insert(key, val);
     }
+
+    /// Iterate over the object's keys and values.
+    pub fn iter(&self) -> std::collections::hash_map::Iter<'_, String, Value> {
+        self.1.iter()
+    }
+}
+
+impl Default for Object {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 /// Build an Object according to the Builder pattern
@@ -62,7 +76,13 @@ impl ObjectBuilder {
     }
 }
 
-fn gen_random(len: i32) -> String {
+impl Default for ObjectBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub(crate) fn gen_random(len: i32) -> String {
     const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
                             abcdefghijklmnopqrstuvwxyz\
                             0123456789)(*&^%$#@!~";
@@ -79,7 +99,7 @@ fn gen_random(len: i32) -> String {
 pub enum Value {
     Null,
     Bit(bool),
-    Number(f32),
+    Number(f64),
     Text(String),
     Link(Object),
 }
@@ -93,4 +113,42 @@ impl TryInto<String> for &Value {
             Err("Failed to convert to &str")
         }
     }
-}
\ No newline at end of file
+}
+
+/// Values are written on the wire as JSON scalars, with a link
+/// to another node written as `{"#": soul}`.
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Null => serializer.serialize_unit(),
+            Value::Bit(b) => serializer.serialize_bool(*b),
+            Value::Number(n) => serializer.serialize_f64(*n),
+            Value::Text(s) => serializer.serialize_str(s),
+            Value::Link(obj) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("#", &obj.get_id())?;
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::Null => Ok(Value::Null),
+            serde_json::Value::Bool(b) => Ok(Value::Bit(b)),
+            serde_json::Value::Number(n) => n.as_f64()
+                .map(Value::Number)
+                .ok_or_else(|| D::Error::custom(format!("{} is not a number", n))),
+            serde_json::Value::String(s) => Ok(Value::Text(s)),
+            serde_json::Value::Object(map) => match (map.len(), map.get("#")) {
+                (1, Some(serde_json::Value::String(soul))) => {
+                    Ok(Value::Link(ObjectBuilder::new().with_id(soul).create()))
+                }
+                _ => Err(D::Error::custom("objects must be linked by soul")),
+            },
+            serde_json::Value::Array(_) => Err(D::Error::custom("arrays are not supported")),
+        }
+    }
+}
@@ -0,0 +1,678 @@
+//! SEA, GUN's Security, Encryption and Authorization.
+//!
+//! Everything here reads and writes what sea.js does, so that data signed
+//! or encrypted in a browser can be checked or opened here and the other
+//! way around. Keys are on P-256 and written as base64url: a public key
+//! is its x and y joined by a `.`, a private key its scalar.
+//!
+//! Signatures are ECDSA over the SHA-256 of the signed text, in base64.
+//! Nodes under `~<pub>` souls belong to the user with that public key, and
+//! every field written to them has to be signed by that user. A signed
+//! field's value is the JSON text `{":": value, "~": signature}`, signed
+//! over the field's soul, key, value and state. The older
+//! `SEA{"m": value, "s": signature}` only signs the value, so it could be
+//! replayed under any key at any state, and is still read but no longer
+//! taken in a put.
+
+use aes_gcm::aead::consts::U15;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::aes::Aes256;
+use aes_gcm::{AesGcm, Nonce};
+use std::convert::TryFrom;
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use p256::{EncodedPoint, PublicKey, SecretKey};
+use p256::elliptic_curve::sec1::{FromEncodedPoint, ToEncodedPoint};
+use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use p256::ecdsa::signature::{Signer, Verifier};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use serde_json::value::RawValue;
+use sha2::{Digest, Sha256};
+use crate::ham::{self, State};
+use crate::message::Put;
+use crate::obj::Value;
+
+/// How many rounds of PBKDF2 `work` does.
+const ITERATIONS: u32 = 100_000;
+
+/// AES-GCM with the 15-byte IVs sea.js uses.
+type Aes = AesGcm<Aes256, U15>;
+
+/// A user's keys: one pair to sign with and one to agree on secrets with.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Pair {
+    #[serde(rename = "pub")]
+    pub pub_key: String,
+    #[serde(rename = "priv")]
+    pub priv_key: String,
+    pub epub: String,
+    pub epriv: String,
+}
+
+/// Signed data as sea.js writes it, keeping what was signed as written.
+#[derive(Deserialize)]
+struct Signed<'a> {
+    #[serde(borrow)]
+    m: &'a RawValue,
+    s: String,
+}
+
+/// A field signed as sea.js writes it, by the owner of its space or by
+/// someone they gave a certificate to.
+#[derive(Deserialize)]
+struct SignedField<'a> {
+    #[serde(rename = ":", borrow)]
+    val: &'a RawValue,
+    #[serde(rename = "~")]
+    sig: String,
+    #[serde(rename = "+", borrow, default)]
+    cert: Option<&'a RawValue>,
+    #[serde(rename = "*", default)]
+    by: Option<String>,
+}
+
+/// A blacklist named by a certificate, which the user it was given to must
+/// not be on: the key `writer` set to `true` or 1 in the node at `soul`,
+/// or in the node `soul` links to under `via`.
+#[derive(Debug, PartialEq)]
+pub struct Blacklist {
+    pub soul: String,
+    pub via: Option<String>,
+    pub writer: String,
+}
+
+/// Make a new user's keys.
+pub fn pair() -> Pair {
+    let signing = SecretKey::random(&mut OsRng);
+    let ecdh = SecretKey::random(&mut OsRng);
+    Pair {
+        pub_key: public(&signing.public_key()),
+        priv_key: URL_SAFE_NO_PAD.encode(signing.to_bytes()),
+        epub: public(&ecdh.public_key()),
+        epriv: URL_SAFE_NO_PAD.encode(ecdh.to_bytes()),
+    }
+}
+
+/// Sign `data` as `SEA{"m": data, "s": signature}`.
+pub fn sign(data: &serde_json::Value, pair: &Pair) -> Result<String, String> {
+    let m = match data {
+        serde_json::Value::String(raw) => parse(raw),
+        data => data.clone(),
+    };
+    let s = signature(&text(&m), pair)?;
+    Ok(format!("SEA{}", json!({ "m": m, "s": s })))
+}
+
+/// What signed `data` says, if `pub_key` signed it.
+pub fn verify(data: &str, pub_key: &str) -> Result<serde_json::Value, String> {
+    let json = data.strip_prefix("SEA").unwrap_or(data);
+    let signed: Signed = serde_json::from_str(json).map_err(|_| "Unverified data.")?;
+    let m = signed_text(signed.m);
+    check_signature(&m, &signed.s, pub_key)?;
+    Ok(parse(&m))
+}
+
+/// Give a certificate to write what `policy` allows in our space, until
+/// `expiry` if there is one, to `grantees`, or to anyone if one of them is
+/// `*`. Those the certificate was given to can be blacklisted later, in
+/// the node linked to from `blacklist` in our space.
+pub fn certify(
+    grantees: &[&str],
+    policy: serde_json::Value,
+    pair: &Pair,
+    expiry: Option<State>,
+    blacklist: Option<&str>,
+) -> Result<String, String> {
+    let grantees = match grantees {
+        grantees if grantees.contains(&"*") => json!("*"),
+        [grantee] => json!(grantee),
+        grantees => json!(grantees),
+    };
+    let mut cert = json!({ "c": grantees, "w": policy });
+    if let Some(expiry) = expiry {
+        cert["e"] = json!(expiry);
+    }
+    if let Some(blacklist) = blacklist {
+        cert["wb"] = json!(blacklist);
+    }
+    sign(&cert, pair)
+}
+
+/// Check that a certificate `owner` gave lets `writer` write `key` in
+/// `soul` as of `now`, and what blacklist the writer must not be on.
+/// Expiry is up to our clock, not the state the writer picked.
+pub fn check_certificate(
+    cert: &str,
+    owner: &str,
+    writer: &str,
+    soul: &str,
+    key: &str,
+    now: State,
+) -> Result<Option<Blacklist>, String> {
+    let fail = || "Certificate verification fail.".to_string();
+    let cert = verify(cert, owner).map_err(|_| fail())?;
+    let expiry = match cert.get("e") {
+        Some(serde_json::Value::Number(e)) => e.as_f64(),
+        Some(serde_json::Value::String(e)) => e.parse().ok(),
+        _ => None,
+    };
+    if expiry.is_some_and(|expiry| now > expiry) {
+        return Err("Certificate expired.".to_string());
+    }
+    let granted = match cert.get("c") {
+        Some(serde_json::Value::String(c)) => c == writer || c.contains('*'),
+        Some(serde_json::Value::Array(c)) => c.iter().any(|c| c == writer || c == "*"),
+        _ => false,
+    };
+    if !granted {
+        return Err(fail());
+    }
+
+    let path = soul.split_once('/').map(|(_, path)| path).unwrap_or("");
+    let policies = match cert.get("w") {
+        Some(serde_json::Value::Array(policies)) => policies.iter().collect(),
+        Some(policy) => vec![policy],
+        None => return Err(fail()),
+    };
+    let policy = policies.into_iter().find(|policy| allows(policy, path, key)).ok_or_else(fail)?;
+    // Anyone may write, but only in paths or keys named after them.
+    let anyone = match policy.get("+") {
+        Some(serde_json::Value::String(plus)) => plus.contains('*'),
+        Some(serde_json::Value::Array(plus)) => plus.iter().any(|plus| plus == "*"),
+        _ => false,
+    };
+    if anyone && !path.is_empty() && !path.contains(writer) && !key.contains(writer) {
+        return Err(format!("Path \"{}\" or key \"{}\" must contain string \"{}\".", path, key, writer));
+    }
+
+    let blacklist = |soul: &str, via: Option<&str>| Blacklist {
+        soul: soul.to_string(),
+        via: via.map(String::from),
+        writer: writer.to_string(),
+    };
+    Ok(match cert.get("wb") {
+        Some(serde_json::Value::String(wb)) if wb.starts_with('~') => Some(blacklist(wb, None)),
+        Some(serde_json::Value::String(wb)) => Some(blacklist(&format!("~{}", owner), Some(wb))),
+        Some(wb) => wb.get("#").and_then(|soul| soul.as_str()).map(|soul| blacklist(soul, None)),
+        None => None,
+    })
+}
+
+/// The signature of `text` by `pair`, in base64.
+pub fn signature(text: &str, pair: &Pair) -> Result<String, String> {
+    let key = URL_SAFE_NO_PAD.decode(&pair.priv_key).ok()
+        .and_then(|key| SigningKey::from_slice(&key).ok())
+        .ok_or("Invalid private key.")?;
+    // WebCrypto hashes the hash sea.js gives it once more.
+    let sig: Signature = key.sign(&Sha256::digest(text.as_bytes()));
+    Ok(STANDARD.encode(sig.to_bytes()))
+}
+
+/// Check a signature of `text` by `pub_key`.
+pub fn check_signature(text: &str, sig: &str, pub_key: &str) -> Result<(), String> {
+    let key = VerifyingKey::from(&public_key(pub_key)?);
+    let sig = STANDARD.decode(sig).ok()
+        .and_then(|sig| Signature::from_slice(&sig).ok())
+        .ok_or("Signature did not match.")?;
+    let hash = Sha256::digest(text.as_bytes());
+    key.verify(&hash, &sig).map_err(|_| "Signature did not match.".to_string())
+}
+
+/// Encrypt `data` as `SEA{"ct": ..., "iv": ..., "s": ...}`, with a key
+/// made from `key` and a random salt. `key` is a user's `epriv`, a
+/// `secret` or any other text.
+pub fn encrypt(data: &serde_json::Value, key: &str) -> Result<String, String> {
+    let mut salt = [0u8; 9];
+    let mut iv = [0u8; 15];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut iv);
+    let ct = aes(key, &salt)
+        .encrypt(&Nonce::from(iv), text(data).as_bytes())
+        .map_err(|_| "Could not encrypt.")?;
+    Ok(format!("SEA{}", json!({
+        "ct": STANDARD.encode(ct),
+        "iv": STANDARD.encode(iv),
+        "s": STANDARD.encode(salt),
+    })))
+}
+
+/// Open what `encrypt` made with the same `key`.
+pub fn decrypt(data: &str, key: &str) -> Result<serde_json::Value, String> {
+    let sealed = parse(data);
+    let field = |name| sealed.get(name).and_then(|v| v.as_str()).ok_or("Could not decrypt.");
+    let (ct, iv, salt) = (field("ct")?, field("iv")?, field("s")?);
+
+    let base64 = |raw: &str| STANDARD.decode(raw).ok();
+    let opened = match (base64(ct), base64(iv), base64(salt)) {
+        (Some(ct), Some(iv), Some(salt)) => open(key, &ct, &iv, &salt),
+        _ => None,
+    };
+    // Accounts made by older versions kept each byte as a character.
+    let opened = opened.or_else(|| {
+        let bytes = |raw: &str| raw.encode_utf16().map(|c| c as u8).collect::<Vec<u8>>();
+        open(key, &bytes(ct), &bytes(iv), &bytes(salt))
+    });
+    let opened = opened.ok_or("Could not decrypt.")?;
+    let opened = String::from_utf8(opened).map_err(|_| "Could not decrypt.")?;
+    Ok(parse(&opened))
+}
+
+/// Stretch a password with PBKDF2 and a salt, into 64 bytes in base64.
+pub fn work(data: &str, salt: &str) -> String {
+    let mut out = [0u8; 64];
+    pbkdf2::pbkdf2_hmac::<Sha256>(data.as_bytes(), salt.as_bytes(), ITERATIONS, &mut out);
+    STANDARD.encode(out)
+}
+
+/// The SHA-256 of `data` in base64, as `work` gives it with `{name: "SHA-256"}`.
+pub fn hash(data: &str) -> String {
+    STANDARD.encode(Sha256::digest(data.as_bytes()))
+}
+
+/// The secret we share with the owner of `epub`, for `encrypt` and `decrypt`.
+pub fn secret(epub: &str, pair: &Pair) -> Result<String, String> {
+    let theirs = public_key(epub)?;
+    let ours = URL_SAFE_NO_PAD.decode(&pair.epriv).ok()
+        .and_then(|key| SecretKey::from_slice(&key).ok())
+        .ok_or("Invalid private key.")?;
+    let shared = p256::ecdh::diffie_hellman(ours.to_nonzero_scalar(), theirs.as_affine());
+    Ok(URL_SAFE_NO_PAD.encode(shared.raw_secret_bytes()))
+}
+
+/// A value as sea.js reads it back: `SEA{...}` and other JSON parsed, and
+/// anything else as text.
+pub fn parse(raw: &str) -> serde_json::Value {
+    let json = raw.strip_prefix("SEA").filter(|json| json.starts_with('{')).unwrap_or(raw);
+    serde_json::from_str(json).unwrap_or_else(|_| serde_json::Value::String(raw.to_string()))
+}
+
+/// Check that every node written to a user's space is signed by its user,
+/// or by someone they gave a certificate to. Gives the blacklists those
+/// certificates name, for the caller to look up.
+pub fn check(put: &Put) -> Result<Vec<Blacklist>, String> {
+    let mut blacklists = Vec::new();
+    for (soul, node) in put.iter() {
+        // Aliases are claimed unsigned, but only link to the users named
+        // by their keys.
+        if soul.starts_with("~@") {
+            for (key, (val, _)) in node.iter() {
+                match val {
+                    Value::Link(link) if link.get_id() == *key => {}
+                    _ => return Err("Alias not same!".to_string()),
+                }
+            }
+            continue;
+        }
+        if let Some(pub_key) = soul_pub(soul) {
+            for (key, (val, state)) in node.iter() {
+                blacklists.extend(check_field(soul, key, val, *state, &pub_key)?);
+            }
+        } else if is_hashed(soul) {
+            for (key, (val, _)) in node.iter() {
+                check_hash(key, val)?;
+            }
+        }
+    }
+    Ok(blacklists)
+}
+
+/// Whether a soul is content-addressed, with every key the hash of its value.
+pub fn is_hashed(soul: &str) -> bool {
+    soul.contains('#') && soul_pub(soul).is_none()
+}
+
+/// Check that a key ends with the hash of its value, after its last `#`.
+pub fn check_hash(key: &str, val: &Value) -> Result<(), String> {
+    let text = match val {
+        Value::Text(text) => text.to_string(),
+        val => ham::lexical(val),
+    };
+    match key.rsplit('#').next() {
+        Some(hashed) if hashed == hash(&text) => Ok(()),
+        _ => Err("Data hash not same as hash!".to_string()),
+    }
+}
+
+/// The public key of the user a soul belongs to: what follows its `~`, up
+/// to the second character that can't be in base64url, with a `.` between.
+pub fn soul_pub(soul: &str) -> Option<String> {
+    let rest = soul.split('~').nth(1)?;
+    if rest.starts_with('@') {
+        return None;
+    }
+    let mut parts = rest.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'));
+    let (x, y) = (parts.next()?, parts.next()?);
+    Some(format!("{}.{}", x, y))
+}
+
+/// Check one field written to the space of `pub_key`, and what blacklist
+/// its writer must not be on if they wrote it under a certificate.
+pub fn check_field(soul: &str, key: &str, val: &Value, state: State, pub_key: &str) -> Result<Option<Blacklist>, String> {
+    check_field_at(soul, key, val, state, pub_key, ham::state())
+}
+
+fn check_field_at(
+    soul: &str,
+    key: &str,
+    val: &Value,
+    state: State,
+    pub_key: &str,
+    now: State,
+) -> Result<Option<Blacklist>, String> {
+    // A user's account names its own key, unsigned.
+    if key == "pub" && soul == format!("~{}", pub_key) {
+        return match val {
+            Value::Text(text) if text == pub_key => Ok(None),
+            _ => Err("Account not same!".to_string()),
+        };
+    }
+    let raw = match val {
+        Value::Text(raw) if raw.starts_with("SEA{") => return Err("Unverified data.".to_string()),
+        Value::Text(raw) => raw,
+        _ => return Err("Unverified data.".to_string()),
+    };
+    let signed: SignedField = serde_json::from_str(raw).map_err(|_| "Unverified data.")?;
+    let text = field_text(soul, key, signed.val.get(), state);
+    match &signed.by {
+        Some(writer) if writer != pub_key => {
+            check_signature(&text, &signed.sig, writer)?;
+            let cert = signed.cert.ok_or("Certificate verification fail.")?;
+            check_certificate(cert.get(), pub_key, writer, soul, key, now)
+        }
+        _ => check_signature(&text, &signed.sig, pub_key).map(|_| None),
+    }
+}
+
+/// Sign a field written by `pair`, as the value to write: to their own
+/// space, or to someone else's under a certificate they gave.
+pub fn sign_field(
+    soul: &str,
+    key: &str,
+    val: &Value,
+    state: State,
+    pair: &Pair,
+    cert: Option<&str>,
+) -> Result<Value, String> {
+    let val = ham::lexical(val);
+    let s = signature(&field_text(soul, key, &val, state), pair)?;
+    Ok(Value::Text(match cert {
+        Some(cert) => format!(
+            "{{\":\":{},\"~\":{},\"+\":{},\"*\":{}}}",
+            val, json!(s), cert.strip_prefix("SEA").unwrap_or(cert), json!(pair.pub_key)
+        ),
+        None => format!("{{\":\":{},\"~\":{}}}", val, json!(s)),
+    }))
+}
+
+/// What is signed for a field: its soul, key, value as JSON and state, in
+/// the order sea.js writes them.
+pub fn field_text(soul: &str, key: &str, val: &str, state: State) -> String {
+    format!(
+        "{{\"#\":{},\".\":{},\":\":{},\">\":{}}}",
+        json!(soul), json!(key), val, ham::lexical(&Value::Number(state))
+    )
+}
+
+/// What a field says without its signature.
+pub fn unpack(val: &Value) -> Value {
+    let raw = match val {
+        Value::Text(raw) => raw,
+        val => return val.clone(),
+    };
+    let inner = match parse(raw) {
+        serde_json::Value::Object(signed) if signed.contains_key("~") => signed.get(":").cloned(),
+        serde_json::Value::Object(signed) if signed.contains_key("s") => match signed.get("m") {
+            Some(serde_json::Value::String(m)) => Some(parse(m)),
+            m => m.cloned(),
+        },
+        _ => None,
+    };
+    inner.and_then(|inner| serde_json::from_value(inner).ok()).unwrap_or_else(|| val.clone())
+}
+
+/// A public key from its SEA form.
+fn public_key(pub_key: &str) -> Result<PublicKey, String> {
+    let invalid = || format!("Invalid public key '{}'.", pub_key);
+    let mut coords = pub_key.split('.').map(|c| URL_SAFE_NO_PAD.decode(c).ok());
+    let (x, y) = match (coords.next().flatten(), coords.next().flatten()) {
+        (Some(x), Some(y)) if x.len() == 32 && y.len() == 32 => (x, y),
+        _ => return Err(invalid()),
+    };
+    let point = EncodedPoint::from_affine_coordinates(x.as_slice().into(), y.as_slice().into(), false);
+    Option::from(PublicKey::from_encoded_point(&point)).ok_or_else(invalid)
+}
+
+/// A public key in its SEA form.
+fn public(key: &PublicKey) -> String {
+    let point = key.to_encoded_point(false);
+    match (point.x(), point.y()) {
+        (Some(x), Some(y)) => format!("{}.{}", URL_SAFE_NO_PAD.encode(x), URL_SAFE_NO_PAD.encode(y)),
+        _ => unreachable!("an uncompressed point has both coordinates"),
+    }
+}
+
+/// The AES key sea.js makes from a key and a salt. The salt's bytes are
+/// taken as characters, which are then written out as UTF-8.
+fn aes(key: &str, salt: &[u8]) -> Aes {
+    let salt: String = salt.iter().map(|&b| b as char).collect();
+    let key = Sha256::digest(format!("{}{}", key, salt).as_bytes());
+    Aes::new(&key)
+}
+
+fn open(key: &str, ct: &[u8], iv: &[u8], salt: &[u8]) -> Option<Vec<u8>> {
+    let iv = <[u8; 15]>::try_from(iv).ok()?;
+    aes(key, salt).decrypt(&Nonce::from(iv), ct).ok()
+}
+
+/// Whether a path or key matches a pattern in a certificate's policy, as
+/// gun.js matches them: a string exactly, and an object by its `=`, by
+/// its `*` prefix, or by its `>` and `<` bounds.
+fn text_match(t: &str, pattern: Option<&serde_json::Value>) -> bool {
+    let get = |name| match pattern {
+        Some(serde_json::Value::String(exact)) if name == "=" => Some(exact.as_str()),
+        Some(serde_json::Value::Object(pattern)) => pattern.get(name).and_then(|v| v.as_str()),
+        _ => None,
+    };
+    let (exact, prefix, start, end) = (get("="), get("*"), get(">"), get("<"));
+    if exact.or(prefix).or(start).or(end) == Some(t) {
+        return true;
+    }
+    if exact.is_some() {
+        return false;
+    }
+    if prefix.or(start).is_some_and(|prefix| t.starts_with(prefix)) {
+        return true;
+    }
+    if prefix.is_some() {
+        return false;
+    }
+    match (start, end) {
+        (Some(start), Some(end)) => t >= start && t <= end,
+        (Some(start), None) => t >= start,
+        (None, Some(end)) => t <= end,
+        (None, None) => false,
+    }
+}
+
+/// Whether a certificate's policy allows writing `key` under `path`, the
+/// soul it is written to past the user's own.
+fn allows(policy: &serde_json::Value, path: &str, key: &str) -> bool {
+    let (soul, dot) = (policy.get("#"), policy.get("."));
+    let full = if path.is_empty() { key.to_string() } else { format!("{}/{}", path, key) };
+    (text_match(path, soul) && text_match(key, dot))
+        || (dot.is_none() && text_match(path, soul))
+        || (soul.is_none() && text_match(key, dot))
+        || text_match(&full, Some(soul.unwrap_or(policy)))
+}
+
+/// What sea.js hashed for signed data: strings as they are, anything else
+/// as the JSON it was written as.
+fn signed_text(m: &RawValue) -> String {
+    serde_json::from_str::<String>(m.get()).unwrap_or_else(|_| m.get().to_string())
+}
+
+/// What sea.js hashes or encrypts for a value: strings as they are,
+/// anything else as JSON.
+fn text(m: &serde_json::Value) -> String {
+    match m {
+        serde_json::Value::String(s) => s.to_string(),
+        m => m.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Made with sea.js.
+    const PUB: &str = "9_ou4yalMYSOWZzaDXwr1qWqPSYg0kLd4SGpgaD14lY.h34EgZAOX33fDgY75pzqUpPhYSyyFo1VjdBdlUzvqFU";
+    const SIG: &str = "W6Zx8yYR3wdFLZ+5EZGxNQ/jtxGZAbjk+x2H505Zd+OdgJNylgY5dHiDbueOv767PsdQbQh+0hT3yrr2JnQdIg==";
+    const OLD: &str = r#"SEA{"m":"hello","s":"lQTuxi+z1F5x6lHtAsW9dks0IIUj1qjese6s5UmV6fGpg6pTpFzNWLWcfyZ+F2fArXpXN20/MQfZoeYyDu9VRQ=="}"#;
+
+    fn mark() -> Pair {
+        Pair {
+            pub_key: PUB.to_string(),
+            priv_key: "UlPHn0tVQFhJyLttP18tRVHyOiWjZ6jz-DV2rL8ZzTE".to_string(),
+            epub: "tBV8boircbjWgn4Mc2YoepY5u0u61jK6ysbm0nOJKd8.2twY99A1XFIMg-vA2VryWmpVdqOWV1kZ9q02o4DrQnM".to_string(),
+            epriv: "IglzE3Zbpi_KA57FDdQtENAf-3RM1wToOI-HQa1FVno".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_soul_pub() {
+        assert_eq!(soul_pub(&format!("~{}", PUB)), Some(PUB.to_string()));
+        assert_eq!(soul_pub(&format!("~{}/profile", PUB)), Some(PUB.to_string()));
+        assert_eq!(soul_pub("~@mark"), None);
+        assert_eq!(soul_pub("~mark"), None);
+        assert_eq!(soul_pub("mark"), None);
+    }
+
+    #[test]
+    fn test_check_field() {
+        let soul = format!("~{}", PUB);
+        let signed = Value::Text(format!(r#"{{":":"Mark","~":"{}"}}"#, SIG));
+        check_field(&soul, "name", &signed, 1634000000000.5, PUB).unwrap();
+        assert!(check_field(&soul, "name", &signed, 1634000000001.0, PUB).is_err());
+        assert!(check_field(&soul, "nick", &signed, 1634000000000.5, PUB).is_err());
+
+        let forged = Value::Text(format!(r#"{{":":"Marc","~":"{}"}}"#, SIG));
+        assert!(check_field(&soul, "name", &forged, 1634000000000.5, PUB).is_err());
+        assert!(check_field(&soul, "name", &Value::Text("Mark".to_string()), 1.0, PUB).is_err());
+
+        check_field(&soul, "pub", &Value::Text(PUB.to_string()), 1.0, PUB).unwrap();
+        assert!(check_field(&soul, "pub", &Value::Text("someone.else".to_string()), 1.0, PUB).is_err());
+    }
+
+    #[test]
+    fn test_signed_value_replayed_under_other_key() {
+        let soul = format!("~{}", PUB);
+        // Signed by the user, but nothing says for which field.
+        verify(OLD, PUB).unwrap();
+        assert!(check_field(&soul, "greeting", &Value::Text(OLD.to_string()), 1.0, PUB).is_err());
+        assert!(check_field(&soul, "name", &Value::Text(OLD.to_string()), 1.0, PUB).is_err());
+
+        let pair = mark();
+        let signed = sign_field(&soul, "greeting", &Value::Text("hello".to_string()), 2.0, &pair, None).unwrap();
+        check_field(&soul, "greeting", &signed, 2.0, PUB).unwrap();
+        assert!(check_field(&soul, "name", &signed, 2.0, PUB).is_err());
+        assert!(check_field(&format!("{}/profile", soul), "greeting", &signed, 2.0, PUB).is_err());
+    }
+
+    #[test]
+    fn test_check_hash() {
+        let hello = Value::Text("hello".to_string());
+        check_hash("LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=", &hello).unwrap();
+        check_hash("post#LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=", &hello).unwrap();
+        assert!(check_hash("LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=", &Value::Text("hullo".to_string())).is_err());
+        check_hash(&hash("30"), &Value::Number(30.0)).unwrap();
+        assert!(is_hashed("#") && is_hashed("posts#"));
+        assert!(!is_hashed(&format!("~{}#", PUB)) && !is_hashed("posts"));
+    }
+
+    #[test]
+    fn test_check_certificate() {
+        // Given by mark with sea.js, to write in his inbox until 2033.
+        let other = "5A7vhH_rSOKWXobh2NOTY7c0nxSLbefoSpKos3Uksaw.P_zhGpIY6nPDuEHO-rp52d3n0wL7_hiQ_AbJQPjuncA";
+        let cert = r##"{"m":{"c":"5A7vhH_rSOKWXobh2NOTY7c0nxSLbefoSpKos3Uksaw.P_zhGpIY6nPDuEHO-rp52d3n0wL7_hiQ_AbJQPjuncA","e":2000000000000,"w":{"#":{"*":"inbox"},"+":"*"},"wb":"blacklist"},"s":"2/TBDQuWWn5370qr9wRVLC9AaEAvkuBUYaN9d98qh+akWXQYQXybI0tL8CCWefDfOvQ9hkIgSbh2Ncnh3cTuuw=="}"##;
+        let val = Value::Text(format!(
+            r#"{{":":"hi","~":"dRX6Rpas7+5Zl+oPCEOnWomRpjx/IYTR9AN7Zm0vqr3zRy8WXBKhXG+0eoF+LtB+Hmfy6yYKrN68nM4wZJbZOg==","+":{},"*":"{}"}}"#,
+            cert, other
+        ));
+        let soul = format!("~{}/inbox", PUB);
+        let key = format!("{}/1", other);
+        let blacklist = Blacklist { soul: format!("~{}", PUB), via: Some("blacklist".to_string()), writer: other.to_string() };
+        assert_eq!(check_field(&soul, &key, &val, 1634000000000.0, PUB), Ok(Some(blacklist)));
+
+        // Once expired, a write dated before the expiry is refused all the same.
+        let expired = Err("Certificate expired.".to_string());
+        assert_eq!(check_field_at(&soul, &key, &val, 1634000000000.0, PUB, 2000000000001.0), expired);
+
+        let check = |soul: &str, key: &str, now| check_certificate(cert, PUB, other, soul, key, now);
+        assert!(check(&soul, &key, 1634000000000.0).is_ok());
+        assert_eq!(check(&soul, &key, 2000000000001.0), expired);
+        assert!(check(&soul, "1", 1.0).is_err());
+        assert!(check(&format!("~{}/outbox", PUB), &key, 1.0).is_err());
+        assert!(check_certificate(cert, PUB, PUB, &soul, &format!("{}/1", PUB), 1.0).is_err());
+        assert!(check_certificate(cert, other, other, &soul, &key, 1.0).is_err());
+    }
+
+    #[test]
+    fn test_policies() {
+        let allowed = |policy, path, key| allows(&policy, path, key);
+        assert!(allowed(json!("profile/name"), "profile", "name"));
+        assert!(!allowed(json!("profile/name"), "profile", "age"));
+        assert!(allowed(json!({ "#": "inbox", ".": { "*": "2021" } }), "inbox", "2021-08-01"));
+        assert!(!allowed(json!({ "#": "inbox", ".": { "*": "2021" } }), "inbox", "2020-12-31"));
+        assert!(allowed(json!({ ".": { ">": "b", "<": "d" } }), "", "c"));
+        assert!(!allowed(json!({ ".": { ">": "b", "<": "d" } }), "", "e"));
+        assert!(allowed(json!({ "#": { "*": "inbox" } }), "inbox/2021", "anything"));
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signed = r#"SEA{"m":{"a":1},"s":"qjgE/8IZbjUbbTxwPY6PKWB41LmVQoOxz4WOajiKuiapjatHwQ3sWi8Pe4B5UmXhiXEC1AWpDud22+2JydU0PA=="}"#;
+        assert_eq!(verify(signed, PUB), Ok(json!({ "a": 1 })));
+        assert_eq!(verify(OLD, PUB), Ok(json!("hello")));
+        assert!(verify(OLD, &pair().pub_key).is_err());
+
+        let pair = pair();
+        let signed = sign(&json!("hello"), &pair).unwrap();
+        assert!(signed.starts_with(r#"SEA{"m":"hello","s":"#));
+        assert_eq!(verify(&signed, &pair.pub_key), Ok(json!("hello")));
+        assert!(verify(&signed.replace("hello", "hullo"), &pair.pub_key).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_and_decrypt() {
+        let enc = r#"SEA{"ct":"VpzEVL1EgTQp8n09PnTFytviyxRx","iv":"DWqVxwFvvwB8LAy9lFtj","s":"tapADqqZ3KQB"}"#;
+        assert_eq!(decrypt(enc, &mark().epriv), Ok(json!("hello")));
+        let enc = r#"SEA{"ct":"yBMycJquCJVn4qsOUn/sfgpqIeIJHUZ8Nq3j9lknfSAf","iv":"y0OFEZDYO19UTdLdORBh","s":"W+927ktZ4N+H"}"#;
+        assert_eq!(decrypt(enc, "a shared key"), Ok(json!({ "a": 1, "b": "two" })));
+        assert!(decrypt(enc, "another key").is_err());
+
+        let enc = encrypt(&json!({ "n": [1, 2] }), "key").unwrap();
+        assert_eq!(decrypt(&enc, "key"), Ok(json!({ "n": [1, 2] })));
+    }
+
+    #[test]
+    fn test_work_and_secret() {
+        assert_eq!(work("password", "salt"), "A5Si7eMyyaE+uC6bJGMWBMMd+Xi04vD70sVJlE+deaU2zuqbksYXDLvwFT7zOk/1cyHhe3pfrcM/cCPd0yXaRw==");
+        assert_eq!(hash("hello"), "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=");
+
+        let other = Pair {
+            pub_key: "5A7vhH_rSOKWXobh2NOTY7c0nxSLbefoSpKos3Uksaw.P_zhGpIY6nPDuEHO-rp52d3n0wL7_hiQ_AbJQPjuncA".to_string(),
+            priv_key: "5whN-CZbLh0kW4N3lAsD7p3RArbwxjnANqG-gi-5mMQ".to_string(),
+            epub: "MJ2r_6zsxD7cENC3SIc8H2Rs67RbVqJL9rr3cmVruHY.r1z8LRkqHGxqHjOQbbFMxFcSHBaw1JTyUivQLZKI1Nw".to_string(),
+            epriv: "DkeLZrhjdyceGKP4JXBOmFXGpzA_GcPjn8QvEHCQ1u0".to_string(),
+        };
+        let shared = "MZAzPBaemgpqsGnY3MflDfB99XDrKcDgClierFAeFHs";
+        assert_eq!(secret(&other.epub, &mark()).as_deref(), Ok(shared));
+        assert_eq!(secret(&mark().epub, &other).as_deref(), Ok(shared));
+        let enc = r#"SEA{"ct":"DS1vB56q1ts1opMjRhXE+WXnRJTZfka8Wd8=","iv":"/PXDF6wDJTqGMFJc8IV9","s":"FWIFXyIvKi0A"}"#;
+        assert_eq!(decrypt(enc, shared), Ok(json!("between us")));
+    }
+}
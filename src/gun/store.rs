@@ -0,0 +1,144 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use crate::adapters::StorageAdapter;
+use crate::adapters::memory::MemoryStore;
+use crate::ham::{Mix, State};
+use crate::message::Dot;
+use crate::metrics::METRICS;
+use crate::node::Node;
+
+/// How many locks merges into different nodes are spread over.
+const STRIPES: usize = 64;
+
+/// Store is the graph: every node we know, keyed by soul, with the state
+/// of each of its fields. Where the nodes are kept is up to the storage
+/// adapter underneath.
+pub struct Store {
+    adapter: Arc<dyn StorageAdapter>,
+    // Serializes merges into the same node for adapters that can't do
+    // them atomically, by the stripe its soul hashes to.
+    merging: Vec<Mutex<()>>,
+}
+
+impl Store {
+    pub fn new(adapter: Arc<dyn StorageAdapter>) -> Self {
+        Store { adapter, merging: (0..STRIPES).map(|_| Mutex::new(())).collect() }
+    }
+
+    fn stripe(&self, soul: &str) -> &Mutex<()> {
+        let mut hasher = DefaultHasher::new();
+        soul.hash(&mut hasher);
+        &self.merging[hasher.finish() as usize % STRIPES]
+    }
+
+    /// Get a copy of the node with this soul.
+    pub async fn get(&self, soul: &str) -> Result<Option<Node>, String> {
+        let _timer = METRICS.time_storage("get");
+        self.adapter.get(soul).await
+    }
+
+    /// Get the part of a node a `get` asks for, if there is any.
+    pub async fn query(&self, soul: &str, dot: Option<&Dot>) -> Result<Option<Node>, String> {
+        let _timer = METRICS.time_storage("query");
+        match dot {
+            Some(dot) => self.adapter.get_range(soul, dot).await,
+            None => self.adapter.get(soul).await,
+        }
+    }
+
+    /// Run a node through HAM against what we have and write the fields
+    /// that win. Concurrent writes to the same node can't overtake each
+    /// other: either the adapter merges atomically or we take turns, while
+    /// writes to other nodes go on.
+    pub async fn merge(&self, node: &Node, machine: State) -> Result<Mix, String> {
+        if self.adapter.atomic_merge() {
+            let _timer = METRICS.time_storage("merge");
+            return self.adapter.merge(node, machine).await;
+        }
+        let _turn = self.stripe(node.soul()).lock().await;
+        let _timer = METRICS.time_storage("merge");
+        self.adapter.merge(node, machine).await
+    }
+
+    /// Make sure everything written so far is stored for good.
+    pub async fn flush(&self) -> Result<(), String> {
+        let _timer = METRICS.time_storage("flush");
+        self.adapter.flush().await
+    }
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryStore::new()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::time::{Duration, Instant};
+    use async_trait::async_trait;
+    use crate::obj::Value;
+
+    // Memory that takes its time reading one node.
+    struct Slow(MemoryStore);
+
+    #[async_trait]
+    impl StorageAdapter for Slow {
+        async fn get(&self, soul: &str) -> Result<Option<Node>, String> {
+            if soul == "slow" {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+            }
+            self.0.get(soul).await
+        }
+
+        async fn put(&self, node: &Node) -> Result<(), String> {
+            self.0.put(node).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_merge() -> Result<(), String> {
+        let memory = Arc::new(MemoryStore::new());
+        let store = Store::new(memory.clone());
+        let mut node = Node::new("mark");
+        node.insert("name".to_string(), Value::Text("Mark".to_string()), 1.0);
+        node.insert("age".to_string(), Value::Number(30.0), 1.0);
+        store.merge(&node, 10.0).await?;
+
+        let mut older = Node::new("mark");
+        older.insert("name".to_string(), Value::Text("Marc".to_string()), 0.5);
+        let mix = store.merge(&older, 10.0).await?;
+        assert!(mix.diff.is_empty());
+
+        let stored = store.get("mark").await?.unwrap();
+        assert_eq!(stored.get("name"), Some(&Value::Text("Mark".to_string())));
+        assert_eq!(stored.state("age"), Some(1.0));
+        assert_eq!(memory.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merges_into_other_nodes_go_on() -> Result<(), String> {
+        let store = Arc::new(Store::new(Arc::new(Slow(MemoryStore::new()))));
+        assert!(!std::ptr::eq(store.stripe("slow"), store.stripe("fast")));
+        let node = |soul: &str| {
+            let mut node = Node::new(soul);
+            node.insert("x".to_string(), Value::Number(1.0), 1.0);
+            node
+        };
+        let slow = {
+            let store = store.clone();
+            tokio::spawn(async move { store.merge(&node("slow"), 10.0).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let start = Instant::now();
+        store.merge(&node("fast"), 10.0).await?;
+        assert!(start.elapsed() < Duration::from_millis(200));
+        slow.await.unwrap()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use crate::message::{Dot, Get, Lex, Put};
+
+/// How many souls, keys and lexical queries a peer may be subscribed to
+/// in all. Past that its gets are refused.
+pub const MAX_PER_PEER: usize = 10_000;
+
+/// How many lexical queries a peer may have on one soul, as every put to
+/// it is matched against each of them.
+pub const MAX_LEX: usize = 16;
+
+/// Subscriptions indexes which peers asked for which souls, and for
+/// which keys under them, so that a put is only sent to the peers that
+/// asked for what it carries. Relays are sent every put.
+///
+/// Finding who wants a put costs a lookup per soul and key it carries,
+/// plus a match against each lexical query on those souls. Both are
+/// bounded by how much each peer may subscribe to.
+#[derive(Default)]
+pub struct Subscriptions {
+    s: Mutex<Index>,
+}
+
+#[derive(Default)]
+struct Index {
+    souls: HashMap<String, Soul>,
+    // What each peer is subscribed to, to forget it once it leaves.
+    peers: HashMap<String, Subscribed>,
+    // Peers that get every put.
+    relays: HashSet<String>,
+}
+
+#[derive(Default)]
+struct Subscribed {
+    souls: HashSet<String>,
+    // How many subscriptions, counting each key and query on its own.
+    count: usize,
+}
+
+#[derive(Default)]
+struct Soul {
+    // Peers that asked for the whole node.
+    whole: HashSet<String>,
+    // Peers that asked for single keys, by key.
+    keys: HashMap<String, HashSet<String>>,
+    // Peers that asked for keys by a lexical query.
+    lex: HashMap<String, Vec<Lex>>,
+}
+
+impl Soul {
+    fn is_empty(&self) -> bool {
+        self.whole.is_empty() && self.keys.is_empty() && self.lex.is_empty()
+    }
+
+    // How many keys and queries a peer asked for, short of the whole node.
+    fn count(&self, peer: &str) -> usize {
+        self.keys.values().filter(|peers| peers.contains(peer)).count()
+            + self.lex.get(peer).map_or(0, |queries| queries.len())
+    }
+
+    fn forget(&mut self, peer: &str) {
+        self.whole.remove(peer);
+        self.keys.retain(|_, peers| {
+            peers.remove(peer);
+            !peers.is_empty()
+        });
+        self.lex.remove(peer);
+    }
+}
+
+impl Subscriptions {
+    pub fn new() -> Self {
+        Subscriptions::default()
+    }
+
+    /// Subscribe a peer to the node a get asks for, or to the keys it
+    /// asks for. Asking for the whole node covers any keys asked before.
+    /// A peer subscribed to as much as it may be is refused.
+    pub fn subscribe(&self, peer: &str, get: &Get) -> Result<(), String> {
+        let mut index = self.s.lock().unwrap();
+        let Index { souls, peers, .. } = &mut *index;
+        let (new, freed, lex) = match souls.get(&get.soul) {
+            Some(soul) if soul.whole.contains(peer) => return Ok(()),
+            Some(soul) => match &get.key {
+                None => (true, soul.count(peer), 0),
+                Some(Dot::Key(key)) => (!soul.keys.get(key).is_some_and(|peers| peers.contains(peer)), 0, 0),
+                Some(Dot::Lex(lex)) => {
+                    let queries = soul.lex.get(peer).map_or(&[][..], |queries| &queries[..]);
+                    (!queries.contains(lex), 0, queries.len())
+                }
+            },
+            None => (true, 0, 0),
+        };
+        if !new {
+            return Ok(());
+        }
+        let count = peers.get(peer).map_or(0, |subscribed| subscribed.count) - freed;
+        if count >= MAX_PER_PEER {
+            return Err("Too many subscriptions.".to_string());
+        }
+        if lex >= MAX_LEX {
+            return Err("Too many lexical queries on one soul.".to_string());
+        }
+
+        let subscribed = peers.entry(peer.to_string()).or_default();
+        subscribed.souls.insert(get.soul.clone());
+        subscribed.count = count + 1;
+        let soul = souls.entry(get.soul.clone()).or_default();
+        match &get.key {
+            None => {
+                soul.forget(peer);
+                soul.whole.insert(peer.to_string());
+            }
+            Some(Dot::Key(key)) => {
+                soul.keys.entry(key.clone()).or_default().insert(peer.to_string());
+            }
+            Some(Dot::Lex(lex)) => {
+                soul.lex.entry(peer.to_string()).or_default().push(lex.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the peer every put, as it keeps the graph or serves peers of
+    /// its own.
+    pub fn relay(&self, peer: &str) {
+        self.s.lock().unwrap().relays.insert(peer.to_string());
+    }
+
+    /// Forget everything a peer asked for.
+    pub fn forget(&self, peer: &str) {
+        let mut index = self.s.lock().unwrap();
+        index.relays.remove(peer);
+        for soul in index.peers.remove(peer).map(|subscribed| subscribed.souls).unwrap_or_default() {
+            if let Some(subs) = index.souls.get_mut(&soul) {
+                subs.forget(peer);
+                if subs.is_empty() {
+                    index.souls.remove(&soul);
+                }
+            }
+        }
+    }
+
+    /// The peers that asked for any of what a put carries, and relays.
+    pub fn interested(&self, put: &Put) -> HashSet<String> {
+        let index = self.s.lock().unwrap();
+        let mut peers = index.relays.clone();
+        for (soul, node) in put {
+            let subs = match index.souls.get(soul) {
+                Some(subs) => subs,
+                None => continue,
+            };
+            peers.extend(subs.whole.iter().cloned());
+            for (key, _) in node.iter() {
+                if let Some(keyed) = subs.keys.get(key) {
+                    peers.extend(keyed.iter().cloned());
+                }
+            }
+            for (peer, queries) in &subs.lex {
+                if !peers.contains(peer) && node.iter().any(|(key, _)| queries.iter().any(|lex| lex.matches(key))) {
+                    peers.insert(peer.clone());
+                }
+            }
+        }
+        peers
+    }
+
+    /// How many souls someone is subscribed to.
+    pub fn len(&self) -> usize {
+        self.s.lock().unwrap().souls.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::node::Node;
+    use crate::obj::Value;
+
+    fn get(soul: &str, key: Option<Dot>) -> Get {
+        Get { soul: soul.to_string(), key }
+    }
+
+    fn put(soul: &str, keys: &[&str]) -> Put {
+        let mut node = Node::new(soul);
+        for key in keys {
+            node.insert(key.to_string(), Value::Number(1.0), 1.0);
+        }
+        let mut put = Put::new();
+        put.insert(soul.to_string(), node);
+        put
+    }
+
+    fn sorted(peers: HashSet<String>) -> Vec<String> {
+        let mut peers: Vec<String> = peers.into_iter().collect();
+        peers.sort();
+        peers
+    }
+
+    #[test]
+    fn test_souls_match_exactly() {
+        let subs = Subscriptions::new();
+        subs.subscribe("a", &get("a", None)).unwrap();
+        subs.subscribe("b", &get("ba", None)).unwrap();
+        assert_eq!(sorted(subs.interested(&put("a", &["x"]))), vec!["a"]);
+        assert_eq!(sorted(subs.interested(&put("ba", &["x"]))), vec!["b"]);
+        assert!(subs.interested(&put("b", &["x"])).is_empty());
+    }
+
+    #[test]
+    fn test_keys() {
+        let subs = Subscriptions::new();
+        subs.subscribe("a", &get("mark", Some(Dot::Key("name".to_string())))).unwrap();
+        let lex = Lex { prefix: Some("2021-08".to_string()), ..Lex::default() };
+        subs.subscribe("b", &get("mark", Some(Dot::Lex(lex)))).unwrap();
+        subs.subscribe("c", &get("mark", None)).unwrap();
+        subs.relay("r");
+
+        assert_eq!(sorted(subs.interested(&put("mark", &["name"]))), vec!["a", "c", "r"]);
+        assert_eq!(sorted(subs.interested(&put("mark", &["2021-08-01"]))), vec!["b", "c", "r"]);
+        assert_eq!(sorted(subs.interested(&put("mark", &["age"]))), vec!["c", "r"]);
+        assert_eq!(sorted(subs.interested(&put("amber", &["name"]))), vec!["r"]);
+
+        // The whole node covers the keys asked for before.
+        subs.subscribe("a", &get("mark", None)).unwrap();
+        assert_eq!(sorted(subs.interested(&put("mark", &["age"]))), vec!["a", "c", "r"]);
+    }
+
+    #[test]
+    fn test_forget() {
+        let subs = Subscriptions::new();
+        subs.subscribe("a", &get("mark", Some(Dot::Key("name".to_string())))).unwrap();
+        subs.subscribe("a", &get("amber", None)).unwrap();
+        subs.subscribe("b", &get("amber", None)).unwrap();
+        subs.relay("a");
+        subs.forget("a");
+        assert_eq!(subs.len(), 1);
+        assert!(subs.interested(&put("mark", &["name"])).is_empty());
+        assert_eq!(sorted(subs.interested(&put("amber", &["name"]))), vec!["b"]);
+        subs.forget("b");
+        assert!(subs.is_empty());
+    }
+
+    #[test]
+    fn test_subscriptions_are_capped() {
+        let subs = Subscriptions::new();
+        for i in 0..MAX_PER_PEER - 1 {
+            subs.subscribe("a", &get("mark", Some(Dot::Key(i.to_string())))).unwrap();
+        }
+        subs.subscribe("a", &get("amber", None)).unwrap();
+        assert!(subs.subscribe("a", &get("other", None)).is_err());
+        // Asking again for what it has is fine, and so is the whole of a
+        // node in place of its keys.
+        subs.subscribe("a", &get("amber", None)).unwrap();
+        subs.subscribe("a", &get("mark", None)).unwrap();
+        subs.subscribe("a", &get("other", None)).unwrap();
+        subs.subscribe("b", &get("other", None)).unwrap();
+
+        for i in 0..MAX_LEX {
+            let lex = Lex { prefix: Some(i.to_string()), ..Lex::default() };
+            subs.subscribe("c", &get("mark", Some(Dot::Lex(lex)))).unwrap();
+        }
+        let lex = Lex { prefix: Some("x".to_string()), ..Lex::default() };
+        assert!(subs.subscribe("c", &get("mark", Some(Dot::Lex(lex)))).is_err());
+
+        subs.forget("a");
+        assert_eq!(sorted(subs.interested(&put("other", &["x"]))), vec!["b"]);
+    }
+}
@@ -0,0 +1,277 @@
+//! GUN user accounts, as sea.js keeps them.
+//!
+//! A user's account lives in the node `~<pub>`: their public keys, their
+//! alias, and under `auth` their private keys, encrypted with a proof of
+//! work of their password. The node `~@<alias>` links to every account
+//! that goes by that alias, which is how an alias and a password find
+//! their keys again.
+
+use std::sync::Arc;
+use std::time::Duration;
+use serde_json::json;
+use crate::dam::Dam;
+use crate::ham;
+use crate::message::Put;
+use crate::node::Node;
+use crate::obj::{gen_random, ObjectBuilder, Value};
+use crate::sea::{self, Pair};
+
+/// How long to wait for peers to tell us about an account.
+pub const LOOKUP_WAIT: Duration = Duration::from_secs(2);
+
+/// A user we're logged in as.
+pub struct User {
+    dam: Arc<Dam>,
+    alias: String,
+    pair: Pair,
+}
+
+/// A node in a user's space, whose writes we sign. Its soul is the
+/// user's, followed by the path to it. The space is ours, or someone
+/// else's who gave us a certificate to write in it.
+pub struct Scope<'a> {
+    user: &'a User,
+    root: String,
+    path: Vec<String>,
+    cert: Option<String>,
+}
+
+impl User {
+    /// Create an account for `alias`, and log in as them. Fails if the
+    /// alias is already taken.
+    pub async fn create(dam: Arc<Dam>, alias: &str, pass: &str) -> Result<User, String> {
+        let aliases = format!("~@{}", alias);
+        if dam.fetch(&aliases, LOOKUP_WAIT).await?.is_some_and(|node| !node.is_empty()) {
+            return Err("User already created!".to_string());
+        }
+
+        let pair = sea::pair();
+        let salt = gen_random(64);
+        let proof = sea::work(pass, &salt);
+        let keys = json!({ "priv": pair.priv_key, "epriv": pair.epriv });
+        let ek = sea::parse(&sea::encrypt(&keys, &proof)?);
+        let auth = json!({ "ek": ek, "s": salt }).to_string();
+
+        let user = User { dam, alias: alias.to_string(), pair };
+        let soul = user.soul();
+        let state = ham::state();
+        let mut account = Node::new(&soul);
+        account.insert("pub".to_string(), Value::Text(user.pair.pub_key.clone()), state);
+        for (key, val) in [("alias", alias), ("epub", &user.pair.epub), ("auth", &auth)].iter() {
+            let val = sea::sign_field(&soul, key, &Value::Text(val.to_string()), state, &user.pair, None)?;
+            account.insert(key.to_string(), val, state);
+        }
+        let mut claim = Node::new(&aliases);
+        claim.insert(soul.clone(), link(&soul), state);
+
+        let mut put = Put::new();
+        put.insert(soul, account);
+        put.insert(aliases, claim);
+        user.dam.put(put).await?;
+        Ok(user)
+    }
+
+    /// Log in as `alias`, trying every account that goes by it.
+    pub async fn auth(dam: Arc<Dam>, alias: &str, pass: &str) -> Result<User, String> {
+        let wrong = || "Wrong user or password.".to_string();
+        let aliases = dam.fetch(&format!("~@{}", alias), LOOKUP_WAIT).await?.ok_or_else(wrong)?;
+        for (soul, _) in aliases.iter() {
+            let account = match dam.fetch(soul, LOOKUP_WAIT).await? {
+                Some(account) => account,
+                None => continue,
+            };
+            if let Some(pair) = open(&account, pass) {
+                return Ok(User { dam, alias: alias.to_string(), pair });
+            }
+        }
+        Err(wrong())
+    }
+
+    pub fn alias(&self) -> &str {
+        &self.alias
+    }
+
+    /// Our keys.
+    pub fn pair(&self) -> &Pair {
+        &self.pair
+    }
+
+    /// The soul of our account node, at the root of our space.
+    pub fn soul(&self) -> String {
+        format!("~{}", self.pair.pub_key)
+    }
+
+    /// The root of our space, our account node.
+    pub fn root(&self) -> Scope<'_> {
+        Scope { user: self, root: self.soul(), path: Vec::new(), cert: None }
+    }
+
+    /// The account node of the user with the public key `owner`, to write
+    /// in under the certificate they gave us.
+    pub fn space(&self, owner: &str, cert: &str) -> Scope<'_> {
+        Scope { user: self, root: format!("~{}", owner), path: Vec::new(), cert: Some(cert.to_string()) }
+    }
+
+    /// The node under `key` in our account node.
+    pub fn get(&self, key: &str) -> Scope<'_> {
+        self.root().get(key)
+    }
+}
+
+impl<'a> Scope<'a> {
+    /// The node under `key` in this one.
+    pub fn get(&self, key: &str) -> Scope<'a> {
+        let mut path = self.path.clone();
+        path.push(key.to_string());
+        Scope { user: self.user, root: self.root.clone(), path, cert: self.cert.clone() }
+    }
+
+    pub fn soul(&self) -> String {
+        soul(&self.root, &self.path)
+    }
+
+    /// Write a field of this node, signed. In our own space, the links to
+    /// it from the nodes above are written along with it; in someone
+    /// else's, those are theirs to write.
+    pub async fn put(&self, key: &str, val: Value) -> Result<(), String> {
+        let state = ham::state();
+        let mut put = Put::new();
+        self.sign(&mut put, &self.soul(), key, &val, state)?;
+        if self.cert.is_none() {
+            for (depth, key) in self.path.iter().enumerate() {
+                let below = soul(&self.root, &self.path[..=depth]);
+                self.sign(&mut put, &soul(&self.root, &self.path[..depth]), key, &link(&below), state)?;
+            }
+        }
+        self.user.dam.put(put).await
+    }
+
+    /// Read a field of this node, without its signature.
+    pub async fn value(&self, key: &str) -> Result<Option<Value>, String> {
+        let node = self.user.dam.fetch(&self.soul(), LOOKUP_WAIT).await?;
+        Ok(node.and_then(|node| node.get(key).map(sea::unpack)))
+    }
+
+    fn sign(&self, put: &mut Put, soul: &str, key: &str, val: &Value, state: ham::State) -> Result<(), String> {
+        let val = sea::sign_field(soul, key, val, state, &self.user.pair, self.cert.as_deref())?;
+        put.entry(soul.to_string())
+            .or_insert_with(|| Node::new(soul))
+            .insert(key.to_string(), val, state);
+        Ok(())
+    }
+}
+
+/// Our keys from an account, if `pass` is its password.
+fn open(account: &Node, pass: &str) -> Option<Pair> {
+    let text = |key| match account.get(key).map(sea::unpack) {
+        Some(Value::Text(text)) => Some(text),
+        _ => None,
+    };
+    let auth = sea::parse(&text("auth")?);
+    let (ek, salt) = (auth.get("ek")?, auth.get("s")?.as_str()?);
+    let ek = match ek {
+        serde_json::Value::String(ek) => ek.to_string(),
+        ek => ek.to_string(),
+    };
+    let keys = sea::decrypt(&ek, &sea::work(pass, salt)).ok()?;
+    Some(Pair {
+        pub_key: text("pub")?,
+        priv_key: keys.get("priv")?.as_str()?.to_string(),
+        epub: text("epub")?,
+        epriv: keys.get("epriv")?.as_str()?.to_string(),
+    })
+}
+
+fn soul(root: &str, path: &[String]) -> String {
+    std::iter::once(root).chain(path.iter().map(String::as_str)).collect::<Vec<&str>>().join("/")
+}
+
+fn link(soul: &str) -> Value {
+    Value::Link(ObjectBuilder::new().with_id(soul).create())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use tokio::sync::mpsc;
+    use crate::dam::Peer;
+    use crate::gun::gun::Gun;
+
+    // An account made as sea.js makes them, with the password "correct horse".
+    const MARK: &str = r##"{"_":{"#":"~9_ou4yalMYSOWZzaDXwr1qWqPSYg0kLd4SGpgaD14lY.h34EgZAOX33fDgY75pzqUpPhYSyyFo1VjdBdlUzvqFU",">":{"pub":1634000000000,"alias":1634000000000,"epub":1634000000000,"auth":1634000000000}},"pub":"9_ou4yalMYSOWZzaDXwr1qWqPSYg0kLd4SGpgaD14lY.h34EgZAOX33fDgY75pzqUpPhYSyyFo1VjdBdlUzvqFU","alias":"{\":\":\"mark\",\"~\":\"MkIIUx71sBDuYVxPZqyU76TGk52IfnBE4ITeuMY/JIgsPe5xv007bSre/V8stvMmpK1Q3PmZ9aVHhzpzpGx+Cw==\"}","epub":"{\":\":\"tBV8boircbjWgn4Mc2YoepY5u0u61jK6ysbm0nOJKd8.2twY99A1XFIMg-vA2VryWmpVdqOWV1kZ9q02o4DrQnM\",\"~\":\"xlB8+penD7HOLMrh4q8fE2PNWQBhwerSbIuA8lCkYx3UJy6+bpuquXyl29vb8D65prTIPAeQBFWi8q57RPMUmA==\"}","auth":"{\":\":\"{\\\"ek\\\":{\\\"ct\\\":\\\"P7K8lZvcbENd0t00H+VqCpBXGUtJq1LyCmMZJfc9NEDjc7Oka1i3SJ6QdkAuMsk1J5h39uJNwi3jPjyejYPpQ+Oz6x/8a0rOh90wMkd8ADVjYFUATm+2NSsZlxPntbPpatCO0FbwhzKSW3zIbdx9mhAhFEjPeoQi+3FZSg==\\\",\\\"iv\\\":\\\"hCNth+/NsUb3uFeTRymL\\\",\\\"s\\\":\\\"GXJl3InoOOKS\\\"},\\\"s\\\":\\\"pDgR1Yt7Qb3kZ9wXcVnM2aLs8eHuJf6TgYr0oPiK4xWzE5qNbCdMhUjA1sRtGvLk\\\"}\",\"~\":\"ZmHdc51DTAtC3zGTneU1M0c6FvStIzoRKN4ERHZyvfvRrRe8O1PFPKIPYrD7ZxA5hHtB4Edv3CPt9EltLOp3+Q==\"}"}"##;
+
+    /// Connect two relays both ways over channels.
+    async fn link_dams(a: &Arc<Dam>, b: &Arc<Dam>) {
+        for (from, to, id) in [(a, b, "b"), (b, a, "a")].iter() {
+            let (tx, mut rx) = mpsc::channel::<String>(from.queue());
+            let (to, via) = (Arc::clone(to), if *id == "a" { "b" } else { "a" });
+            tokio::spawn(async move {
+                while let Some(raw) = rx.recv().await {
+                    to.hear(&raw, via).await;
+                }
+            });
+            from.connect(id, Peer::relay(tx)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_account_from_sea_js() -> Result<(), String> {
+        let dam = Arc::new(Dam::new(Gun::new()));
+        let account: Node = serde_json::from_str(MARK).unwrap();
+        let soul = account.soul().to_string();
+        let mut claim = Node::new("~@mark");
+        claim.insert(soul.clone(), link(&soul), 1.0);
+        let mut put = Put::new();
+        put.insert(soul, account);
+        put.insert("~@mark".to_string(), claim);
+        dam.put(put).await?;
+
+        let user = User::auth(dam.clone(), "mark", "correct horse").await?;
+        assert_eq!(user.pair().priv_key, "UlPHn0tVQFhJyLttP18tRVHyOiWjZ6jz-DV2rL8ZzTE");
+        assert_eq!(user.pair().epriv, "IglzE3Zbpi_KA57FDdQtENAf-3RM1wToOI-HQa1FVno");
+        assert!(User::auth(dam.clone(), "mark", "battery staple").await.is_err());
+        assert!(User::auth(dam, "amber", "correct horse").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_and_auth_across_relays() -> Result<(), String> {
+        let (a, b) = (Arc::new(Dam::new(Gun::new())), Arc::new(Dam::new(Gun::new())));
+        link_dams(&a, &b).await;
+
+        let user = User::create(a.clone(), "bot", "hunter2").await?;
+        user.get("profile").put("name", Value::Text("Bot".to_string())).await?;
+        assert!(User::create(a.clone(), "bot", "other").await.is_err());
+
+        // The other relay checked every signature and took the writes.
+        let again = User::auth(b.clone(), "bot", "hunter2").await?;
+        assert_eq!(again.pair(), user.pair());
+        assert_eq!(again.get("profile").value("name").await?, Some(Value::Text("Bot".to_string())));
+        let root = b.gun.node(&user.soul()).await?.unwrap();
+        assert_eq!(sea::unpack(root.get("profile").unwrap()), link(&format!("{}/profile", user.soul())));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_in_another_space_with_certificate() -> Result<(), String> {
+        let dam = Arc::new(Dam::new(Gun::new()));
+        let owner = User::create(dam.clone(), "owner", "pass").await?;
+        let writer = User::create(dam.clone(), "writer", "pass").await?;
+        let writer_pub = writer.pair().pub_key.clone();
+        let policy = json!({ "#": { "*": "inbox" }, "+": "*" });
+        let cert = sea::certify(&[&writer_pub], policy, owner.pair(), None, Some("blacklist"))?;
+
+        let inbox = writer.space(&owner.pair().pub_key, &cert).get("inbox");
+        inbox.put(&format!("{}/1", writer_pub), Value::Text("hi".to_string())).await?;
+        assert!(inbox.put("1", Value::Text("hi".to_string())).await.is_err());
+        assert!(writer.space(&owner.pair().pub_key, &cert).put("name", Value::Null).await.is_err());
+        let read = owner.get("inbox").value(&format!("{}/1", writer_pub)).await?;
+        assert_eq!(read, Some(Value::Text("hi".to_string())));
+
+        owner.get("blacklist").put(&writer_pub, Value::Bit(true)).await?;
+        let blocked = inbox.put(&format!("{}/2", writer_pub), Value::Text("hi again".to_string())).await;
+        assert_eq!(blocked, Err("Certificant blacklisted.".to_string()));
+        Ok(())
+    }
+}
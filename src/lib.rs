@@ -7,19 +7,131 @@ pub use crate::gun::*;
 mod tests {
 
     use super::*;
-    use obj::{Object, Value};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use obj::Value;
     use dup::Dup;
-    use message::Key;
+    use crate::gun::gun::{Gun, MAX_DEFERRED};
+    use adapters::filesystem::FileStore;
+    use message::{Key, Msg};
 
     #[test]
     fn test_dup() {
-        let mut obj = Object::new();
-        let key: String = Key::AckId.to_string();
-        let _ = Dup::add(&mut obj, key.clone(), Value::Null);
-        if let Some(val) = obj.get(key) {
-            assert_eq!(*val, Value::Null);
-        } else {
-            assert!(false);
+        let dup = Dup::default();
+        let id: String = Key::AckId.to_string();
+        assert!(!dup.check(&id));
+        dup.track(&id);
+        assert!(dup.check(&id));
+        assert!(!dup.check("other"));
+    }
+
+    #[test]
+    fn test_dup_expires() {
+        let dup = Dup::new(Duration::from_millis(20), 2);
+        dup.track("a");
+        dup.track("b");
+        dup.track("c");
+        assert!(!dup.check("a"));
+        assert!(dup.check("b") && dup.check("c"));
+        assert_eq!(dup.len(), 2);
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!dup.check("c"));
+        assert!(dup.is_empty());
+    }
+
+    fn put_message(soul: &str, key: &str, val: &str, state: f64) -> Msg {
+        let raw = format!(
+            r##"{{"#":"{}","put":{{"{}":{{"_":{{"#":"{}",">":{{"{}":{}}}}},"{}":"{}"}}}}}}"##,
+            state, soul, soul, key, state, key, val
+        );
+        Msg::parse(&raw).pop().unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_gun_inbound() -> Result<(), String> {
+        let gun = Gun::new();
+        let now = ham::state();
+
+        gun.inbound(&put_message("mark", "name", "Mark", now - 10.0)).await?;
+        gun.inbound(&put_message("mark", "name", "Marc", now - 20.0)).await?;
+        gun.inbound(&put_message("mark", "name", "Amber", now - 10.0)).await?;
+        let node = gun.node("mark").await?.unwrap();
+        assert_eq!(node.get("name"), Some(&Value::Text("Mark".to_string())));
+
+        gun.inbound(&put_message("mark", "name", "Later", now + 50.0)).await?;
+        assert_eq!(gun.node("mark").await?.unwrap().get("name"), Some(&Value::Text("Mark".to_string())));
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        gun.flush_deferred(ham::state()).await?;
+        assert_eq!(gun.node("mark").await?.unwrap().get("name"), Some(&Value::Text("Later".to_string())));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deferred_writes_land_on_their_own() -> Result<(), String> {
+        let gun = Gun::new();
+        let now = ham::state();
+        gun.inbound(&put_message("mark", "name", "Later", now + 50.0)).await?;
+        assert!(gun.node("mark").await?.is_none());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(gun.node("mark").await?.unwrap().get("name"), Some(&Value::Text("Later".to_string())));
+
+        // Far too many from the future are refused rather than held.
+        let far = now + 3_600_000.0;
+        for i in 0..MAX_DEFERRED {
+            gun.inbound(&put_message(&format!("s{}", i), "name", "Later", far)).await?;
         }
+        assert!(gun.inbound(&put_message("one", "more", "Later", far)).await.is_err());
+        Ok(())
+    }
+
+    // Memory that fails to write one node.
+    struct Failing(adapters::memory::MemoryStore);
+
+    #[async_trait::async_trait]
+    impl adapters::StorageAdapter for Failing {
+        async fn get(&self, soul: &str) -> Result<Option<node::Node>, String> {
+            self.0.get(soul).await
+        }
+
+        async fn put(&self, node: &node::Node) -> Result<(), String> {
+            if node.soul() == "bad" {
+                return Err("Disk full.".to_string());
+            }
+            self.0.put(node).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_partly_failed_put_names_souls() -> Result<(), String> {
+        let gun = Gun::with_storage(Arc::new(Failing(adapters::memory::MemoryStore::new())));
+        let raw = r##"{"#":"p1","put":{
+            "a":{"_":{"#":"a",">":{"x":1}},"x":1},
+            "bad":{"_":{"#":"bad",">":{"x":1}},"x":1},
+            "c":{"_":{"#":"c",">":{"x":1}},"x":1}}}"##;
+        let e = gun.inbound(&Msg::parse(raw).pop().unwrap().unwrap()).await.unwrap_err();
+        assert_eq!(e, "Disk full. Not written: bad.");
+        assert!(gun.node("a").await?.is_some() && gun.node("c").await?.is_some());
+        assert!(gun.node("bad").await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gun_remembers_across_restarts() -> Result<(), String> {
+        let dir = std::env::temp_dir().join(format!("rod-gun-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let now = ham::state();
+
+        let gun = Gun::with_storage(Arc::new(FileStore::open(&dir).map_err(|e| e.to_string())?));
+        gun.inbound(&put_message("mark", "name", "Mark", now - 10.0)).await?;
+        gun.flush().await?;
+
+        let gun = Gun::with_storage(Arc::new(FileStore::open(&dir).map_err(|e| e.to_string())?));
+        gun.inbound(&put_message("mark", "name", "Marc", now - 20.0)).await?;
+        let get = Msg::parse(r##"{"#":"g1","get":{"#":"mark"}}"##).pop().unwrap().unwrap();
+        let reply = gun.get(&get).await?.unwrap();
+        assert_eq!(reply.put.unwrap()["mark"].get("name"), Some(&Value::Text("Mark".to_string())));
+
+        std::fs::remove_dir_all(&dir).map_err(|e| e.to_string())
     }
 }
@@ -1,34 +1,149 @@
 extern crate clap;
-use clap::{Arg, App, SubCommand};
+use clap::{Arg, App, ArgMatches, SubCommand};
+use rod::gun::adapters::{open_storage, BACKENDS};
 use rod::gun::adapters::websocket_server::serve;
+use rod::gun::config::Config;
 
 fn main() {
-    let matches = App::new("My Super Program")
-                          .version("1.0")
-                          .author("Kevin K. <kbknapp@gmail.com>")
-                          .about("Does awesome things")
+    let matches = App::new("rod")
+                          .version(env!("CARGO_PKG_VERSION"))
+                          .about("A GUN relay server")
                           .arg(Arg::with_name("config")
                                .short("c")
                                .long("config")
                                .value_name("FILE")
-                               .help("Sets a custom config file")
-                               .takes_value(true))
+                               .env("ROD_CONFIG")
+                               .help("the TOML config file to read; ROD_<SECTION>_<KEY> variables and flags override it")
+                               .takes_value(true)
+                               .global(true))
                           .subcommand(SubCommand::with_name("serve")
                                       .about("runs the rod server")
-                                      .version("1.3")
-                                      .author("Someone E. <someone_else@other.com>")
                                       .arg(Arg::with_name("debug")
                                           .short("d")
-                                          .help("print debug information verbosely")))
+                                          .help("print debug information verbosely"))
+                                      .arg(Arg::with_name("storage")
+                                          .long("storage")
+                                          .value_name("BACKEND")
+                                          .help("where to keep the graph")
+                                          .possible_values(BACKENDS))
+                                      .arg(Arg::with_name("radata")
+                                          .long("radata")
+                                          .value_name("DIR")
+                                          .help("directory the filesystem storage writes to"))
+                                      .arg(Arg::with_name("redis")
+                                          .long("redis")
+                                          .value_name("URL")
+                                          .help("the Redis server the redis storage talks to"))
+                                      .arg(Arg::with_name("redis-prefix")
+                                          .long("redis-prefix")
+                                          .value_name("PREFIX")
+                                          .help("what the keys written to Redis start with"))
+                                      .arg(Arg::with_name("s3-endpoint")
+                                          .long("s3-endpoint")
+                                          .value_name("URL")
+                                          .help("the S3-compatible service the s3 storage talks to, if not AWS"))
+                                      .arg(Arg::with_name("s3-region")
+                                          .long("s3-region")
+                                          .value_name("REGION")
+                                          .help("the region of the bucket the s3 storage writes to"))
+                                      .arg(Arg::with_name("s3-bucket")
+                                          .long("s3-bucket")
+                                          .value_name("BUCKET")
+                                          .help("the bucket the s3 storage writes to"))
+                                      .arg(Arg::with_name("s3-prefix")
+                                          .long("s3-prefix")
+                                          .value_name("PREFIX")
+                                          .help("what the objects written to S3 are named with first"))
+                                      .arg(Arg::with_name("peer")
+                                          .long("peer")
+                                          .value_name("URL")
+                                          .help("another relay to mesh with, like ws://host:port/gun")
+                                          .multiple(true)
+                                          .number_of_values(1))
+                                      .arg(Arg::with_name("multicast")
+                                          .long("multicast")
+                                          .help("find and sync with peers on the local network"))
+                                      .arg(Arg::with_name("webrtc")
+                                          .long("webrtc")
+                                          .help("connect to the peers of the mesh over WebRTC data channels"))
+                                      .arg(Arg::with_name("queue")
+                                          .long("queue")
+                                          .value_name("FRAMES")
+                                          .help("how many messages may wait to be sent to a peer before it is dropped as too slow"))
+                                      .arg(Arg::with_name("limit-messages")
+                                          .long("limit-messages")
+                                          .value_name("N")
+                                          .help("how many messages each connection may send per second, or 0 for no limit"))
+                                      .arg(Arg::with_name("limit-bytes")
+                                          .long("limit-bytes")
+                                          .value_name("N")
+                                          .help("how many bytes each connection may send per second, or 0 for no limit"))
+                                      .arg(Arg::with_name("ip-limit-messages")
+                                          .long("ip-limit-messages")
+                                          .value_name("N")
+                                          .help("how many messages all connections from one address may send per second, or 0 for no limit"))
+                                      .arg(Arg::with_name("ip-limit-bytes")
+                                          .long("ip-limit-bytes")
+                                          .value_name("N")
+                                          .help("how many bytes all connections from one address may send per second, or 0 for no limit"))
+                                      .arg(Arg::with_name("limit-strikes")
+                                          .long("limit-strikes")
+                                          .value_name("N")
+                                          .help("after how many messages over the limits a connection is dropped, or 0 for never")))
                           .get_matches();
 
-    let config = matches.value_of("config").unwrap_or("default.conf");
-    println!("Value for config: {}", config);
-
     if let Some(matches) = matches.subcommand_matches("serve") {
-        if matches.is_present("debug") {
+        let config = Config::load(matches.value_of("config"))
+            .and_then(|mut config| {
+                apply_flags(&mut config, matches)?;
+                config.validate()?;
+                Ok(config)
+            })
+            .unwrap_or_else(|e| exit(&e));
+        if config.server.debug {
             println!("Printing debug info...");
         }
-        serve();
+        match open_storage(&config.storage.backend, &config.storage.options()) {
+            Ok(storage) => serve(config, storage),
+            Err(e) => exit(&e),
+        }
+    }
+}
+
+/// Override the config with the flags given to `serve`.
+fn apply_flags(config: &mut Config, matches: &ArgMatches) -> Result<(), String> {
+    let flag = |name: &str| matches.value_of(name).map(String::from);
+    if let Some(backend) = flag("storage") { config.storage.backend = backend; }
+    if let Some(path) = flag("radata") { config.storage.path = path; }
+    if let Some(url) = flag("redis") { config.storage.redis_url = url; }
+    if let Some(prefix) = flag("redis-prefix") { config.storage.redis_prefix = prefix; }
+    if let Some(endpoint) = flag("s3-endpoint") { config.storage.s3_endpoint = Some(endpoint); }
+    if let Some(region) = flag("s3-region") { config.storage.s3_region = region; }
+    if let Some(bucket) = flag("s3-bucket") { config.storage.s3_bucket = bucket; }
+    if let Some(prefix) = flag("s3-prefix") { config.storage.s3_prefix = prefix; }
+    if let Some(urls) = matches.values_of("peer") {
+        config.mesh.peers = urls.map(String::from).collect();
     }
+    config.server.debug |= matches.is_present("debug");
+    config.mesh.multicast |= matches.is_present("multicast");
+    config.mesh.webrtc |= matches.is_present("webrtc");
+    number(&mut config.server.queue, matches, "queue")?;
+    number(&mut config.limits.messages, matches, "limit-messages")?;
+    number(&mut config.limits.bytes, matches, "limit-bytes")?;
+    number(&mut config.limits.ip_messages, matches, "ip-limit-messages")?;
+    number(&mut config.limits.ip_bytes, matches, "ip-limit-bytes")?;
+    number(&mut config.limits.strikes, matches, "limit-strikes")
+}
+
+/// Read a numeric flag, if it was given.
+fn number<T: std::str::FromStr>(field: &mut T, matches: &ArgMatches, name: &str) -> Result<(), String> {
+    if let Some(val) = matches.value_of(name) {
+        *field = val.parse().map_err(|_| format!("--{} must be a number", name))?;
+    }
+    Ok(())
+}
+
+fn exit(e: &str) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(1);
 }